@@ -0,0 +1,380 @@
+//! End-to-end harness: a node, a validator and a wallet run as real
+//! processes on loopback ephemeral ports
+//!
+//! The node is the seed of a fresh network with two genesis keys: Alice,
+//! the only staker, runs the validator, and Carol holds the coins the
+//! wallet spends. Carol never produces blocks, so her wallet only ever sees
+//! mature outputs. Bob is a plain recipient. The validator and any node
+//! joining later start without a chain and download it from their peers.
+
+use anyhow::{Context, Result, bail};
+use poslib::crypto::{PrivateKey, PublicKey};
+use poslib::network::Message;
+use poslib::sha256::Hash;
+use poslib::util::Saveable;
+use std::future::Future;
+use std::net::TcpListener as StdTcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time::{Instant, sleep, timeout};
+
+/// One coin, in satoshis
+pub const COIN: u64 = 10u64.pow(8);
+/// Spendable coins Carol's wallet starts with
+pub const CAROL_ALLOCATION: u64 = 10_000 * COIN;
+/// Stake that makes Alice the only slot validator
+pub const ALICE_STAKE: u64 = 5_000 * COIN;
+/// How long anything is waited for before the test fails
+pub const TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Directory the workspace binaries are built into, next to the test
+/// executable (`target/<profile>/deps/<test>`)
+fn binary_dir() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    exe.parent()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .context("test executable is not under target/<profile>/deps")
+}
+
+/// Path of the `name` binary. The node, validator and wallet are built
+/// on first use since `cargo test` only builds the binaries of the
+/// package under test.
+pub fn binary(name: &str) -> Result<PathBuf> {
+    static BUILT: OnceLock<Result<(), String>> = OnceLock::new();
+    let dir = binary_dir()?;
+    BUILT
+        .get_or_init(|| {
+            let mut cargo = std::process::Command::new(env!("CARGO"));
+            cargo.args(["build", "-p", "node", "-p", "validator", "-p", "wallet"]);
+            if dir.ends_with("release") {
+                cargo.arg("--release");
+            }
+            match cargo.status() {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format!("cargo build failed: {status}")),
+                Err(e) => Err(format!("could not run cargo: {e}")),
+            }
+        })
+        .clone()
+        .map_err(anyhow::Error::msg)?;
+    Ok(dir.join(name))
+}
+
+/// A port nothing listens on right now
+pub fn free_port() -> Result<u16> {
+    Ok(StdTcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Poll `check` until it holds, failing after `TIMEOUT`
+pub async fn eventually<F, Fut>(what: &str, mut check: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match check().await {
+            Ok(true) => return Ok(()),
+            Ok(false) | Err(_) if Instant::now() < deadline => {}
+            Ok(false) => bail!("timed out waiting for {what}"),
+            Err(e) => return Err(e.context(format!("timed out waiting for {what}"))),
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Balance of a public key as reported by `Message::Balance`
+#[derive(Debug, Clone, Copy)]
+pub struct Balance {
+    pub spendable: u64,
+    pub active_stake: u64,
+    pub unlocked_stake: u64,
+}
+
+/// Ask the node at `addr` one question, on a connection of its own
+pub async fn request(addr: &str, message: Message) -> Result<Message> {
+    let mut stream = TcpStream::connect(addr).await?;
+    Message::handshake_async(&mut stream, Hash::zero()).await?;
+    message.send_async(&mut stream).await?;
+    Ok(Message::receive_async(&mut stream).await?)
+}
+
+/// Chain height of the node at `addr`
+pub async fn height(addr: &str) -> Result<u64> {
+    match request(addr, Message::FetchBlockHeight).await? {
+        Message::BlockHeight(height) => Ok(height),
+        other => bail!("expected BlockHeight, got {other:?}"),
+    }
+}
+
+/// A running node and validator, and the nodes that joined them, all
+/// killed when dropped
+pub struct Network {
+    dir: TempDir,
+    node_addr: String,
+    validator_addr: String,
+    pub alice: PrivateKey,
+    pub bob: PrivateKey,
+    pub carol: PrivateKey,
+    processes: Vec<Child>,
+}
+
+impl Network {
+    /// Start the seed node on a fresh network, then the validator syncing
+    /// from it once the node is listening
+    pub async fn start() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let keys = dir.path().join("node").join("validator");
+        std::fs::create_dir_all(&keys)?;
+        let (alice, bob, carol) = (
+            PrivateKey::new_key(),
+            PrivateKey::new_key(),
+            PrivateKey::new_key(),
+        );
+        for (name, key) in [("alice", &alice), ("bob", &bob), ("carol", &carol)] {
+            key.save_to_file(keys.join(format!("{name}.priv.cbor")))?;
+            key.public_key()
+                .save_to_file(keys.join(format!("{name}.pub.pem")))?;
+        }
+        let genesis = dir.path().join("genesis.toml");
+        std::fs::write(
+            &genesis,
+            format!(
+                r#"signer_private_key = "validator/alice.priv.cbor"
+signer_public_key = "validator/alice.pub.pem"
+
+[[validators]]
+public_key = "validator/alice.pub.pem"
+allocation = {COIN}
+stake = {ALICE_STAKE}
+locked_until = 1000
+
+[[validators]]
+public_key = "validator/carol.pub.pem"
+allocation = {CAROL_ALLOCATION}
+stake = 0
+locked_until = 0
+"#
+            ),
+        )?;
+
+        let node_port = free_port()?;
+        let node_addr = format!("127.0.0.1:{node_port}");
+        let node_dir = dir.path().join("node");
+        let node = spawn(
+            Command::new(binary("node")?)
+                .arg("--port")
+                .arg(node_port.to_string())
+                .args(["--bind", "127.0.0.1", "--data-dir"])
+                .arg(&node_dir)
+                .arg("--genesis-config")
+                .arg(&genesis),
+            &dir.path().join("node.log"),
+        )?;
+        eventually("the node to listen", || async {
+            Ok(height(&node_addr).await? > 0)
+        })
+        .await?;
+
+        let validator_port = free_port()?;
+        let validator_addr = format!("127.0.0.1:{validator_port}");
+        let validator_dir = dir.path().join("validator");
+        std::fs::create_dir_all(&validator_dir)?;
+        let validator = spawn(
+            Command::new(binary("validator")?)
+                .current_dir(&validator_dir)
+                .arg("--private-key-file")
+                .arg(keys.join("alice.priv.cbor"))
+                .arg("--port")
+                .arg(validator_port.to_string())
+                .args(["--bind", "127.0.0.1", "--block-interval", "5", "--nodes"])
+                .arg(&node_addr),
+            &dir.path().join("validator.log"),
+        )?;
+
+        Ok(Network {
+            dir,
+            node_addr,
+            validator_addr,
+            alice,
+            bob,
+            carol,
+            processes: vec![node, validator],
+        })
+    }
+
+    pub fn node_addr(&self) -> &str {
+        &self.node_addr
+    }
+
+    pub fn validator_addr(&self) -> &str {
+        &self.validator_addr
+    }
+
+    /// Start a node without a chain, given `peers` to sync from, and
+    /// return the address it listens on
+    pub fn join(&mut self, name: &str, peers: &[&str]) -> Result<String> {
+        let port = free_port()?;
+        let node = spawn(
+            Command::new(binary("node")?)
+                .arg("--port")
+                .arg(port.to_string())
+                .args(["--bind", "127.0.0.1", "--data-dir"])
+                .arg(self.dir.path().join(name))
+                .arg("--nodes")
+                .arg(peers.join(",")),
+            &self.dir.path().join(format!("{name}.log")),
+        )?;
+        self.processes.push(node);
+        Ok(format!("127.0.0.1:{port}"))
+    }
+
+    /// Chain height of the seed node
+    pub async fn height(&self) -> Result<u64> {
+        height(&self.node_addr).await
+    }
+
+    pub async fn balance(&self, key: &PublicKey) -> Result<Balance> {
+        match request(&self.node_addr, Message::FetchBalance(key.clone())).await? {
+            Message::Balance {
+                spendable,
+                active_stake,
+                unlocked_stake,
+            } => Ok(Balance {
+                spendable,
+                active_stake,
+                unlocked_stake,
+            }),
+            other => bail!("expected Balance, got {other:?}"),
+        }
+    }
+
+    /// Start Carol's wallet against the node, with Bob as a contact
+    pub async fn wallet(&self) -> Result<Wallet> {
+        let wallet_dir = self.dir.path().join("wallet");
+        let keys = self.dir.path().join("node").join("validator");
+        std::fs::create_dir_all(&wallet_dir)?;
+        let config = wallet_dir.join("wallet_config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"default_node = "{}"
+
+[fee_config]
+fee_type = "Percent"
+value = 0.1
+
+[[contacts]]
+name = "Bob"
+key = "{}"
+
+[[my_keys]]
+public = "{}"
+private = "{}"
+"#,
+                self.node_addr,
+                keys.join("bob.pub.pem").display(),
+                keys.join("carol.pub.pem").display(),
+                keys.join("carol.priv.cbor").display(),
+            ),
+        )?;
+        let mut child = Command::new(binary("wallet")?)
+            .current_dir(&wallet_dir)
+            .arg("--config")
+            .arg(&config)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().context("wallet stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("wallet stdout")?).lines();
+        Ok(Wallet {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// The last lines every process logged, to explain a failure
+    pub fn logs(&self) -> String {
+        let Ok(entries) = std::fs::read_dir(self.dir.path()) else {
+            return String::new();
+        };
+        let mut logs: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .collect();
+        logs.sort();
+        logs.iter()
+            .map(|path| {
+                let log = std::fs::read_to_string(path).unwrap_or_default();
+                let lines: Vec<&str> = log.lines().collect();
+                let tail = lines[lines.len().saturating_sub(20)..].join("\n");
+                format!("--- {}\n{tail}", path.display())
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Spawn `command` with its output appended to `log`, killed on drop
+fn spawn(command: &mut Command, log: &Path) -> Result<Child> {
+    let log = std::fs::File::create(log)?;
+    Ok(command
+        .env("RUST_LOG", "info")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .kill_on_drop(true)
+        .spawn()?)
+}
+
+/// A wallet REPL driven through its stdin
+pub struct Wallet {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Wallet {
+    /// Type `command` and wait for a line containing `expected`, returning
+    /// everything printed up to it
+    pub async fn run(&mut self, command: &str, expected: &str) -> Result<Vec<String>> {
+        self.stdin
+            .write_all(format!("{command}\n").as_bytes())
+            .await?;
+        self.stdin.flush().await?;
+        let mut printed = vec![];
+        let wait = async {
+            while let Some(line) = self.stdout.next_line().await? {
+                let done = line.contains(expected);
+                printed.push(line);
+                if done {
+                    return Ok(());
+                }
+            }
+            bail!("wallet exited")
+        };
+        match timeout(TIMEOUT, wait).await {
+            Ok(Ok(())) => Ok(printed),
+            Ok(Err(e)) => Err(e.context(format!("`{command}` printed {printed:?}"))),
+            Err(_) => bail!("`{command}` never printed {expected:?}, got {printed:?}"),
+        }
+    }
+
+    /// Leave the REPL
+    pub async fn exit(mut self) -> Result<()> {
+        self.stdin.write_all(b"exit\n").await?;
+        timeout(TIMEOUT, self.child.wait()).await??;
+        Ok(())
+    }
+}
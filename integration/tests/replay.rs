@@ -0,0 +1,66 @@
+//! Replays the manual wallet walkthrough against real processes: Carol
+//! pays Bob, then stakes, while Alice's validator keeps the chain growing
+
+use anyhow::{Context, Result};
+use integration::{COIN, Network, eventually};
+
+/// Paid to Bob
+const PAYMENT: u64 = 100 * COIN;
+/// Staked by Carol, the consensus minimum
+const STAKE: u64 = poslib::consensus::STAKE_MINIMUM_AMOUNT;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wallet_send_and_stake() -> Result<()> {
+    let network = Network::start().await?;
+    let result = replay(&network).await;
+    result.with_context(|| network.logs())
+}
+
+async fn replay(network: &Network) -> Result<()> {
+    let bob = network.bob.public_key();
+    let carol = network.carol.public_key();
+    let start = network.height().await?;
+    eventually("the validator to produce a block", || async {
+        Ok(network.height().await? > start)
+    })
+    .await?;
+
+    let mut wallet = network.wallet().await?;
+    wallet
+        .run(
+            &format!("send Bob {PAYMENT}"),
+            "Transaction to Bob sent successfully",
+        )
+        .await?;
+    eventually("Bob to be paid", || async {
+        Ok(network.balance(&bob).await?.spendable == PAYMENT)
+    })
+    .await?;
+    let paid_at = network.height().await?;
+
+    let before = network.balance(&carol).await?;
+    assert_eq!(before.active_stake, 0);
+    assert!(before.spendable < integration::CAROL_ALLOCATION - PAYMENT);
+    wallet
+        .run(
+            &format!("stake {STAKE}"),
+            "Stake transaction sent successfully",
+        )
+        .await?;
+    eventually("Carol's stake to confirm", || async {
+        Ok(network.balance(&carol).await?.active_stake == STAKE)
+    })
+    .await?;
+    let after = network.balance(&carol).await?;
+    assert!(after.spendable <= before.spendable - STAKE);
+    assert!(network.height().await? > paid_at);
+
+    let printed = wallet.run("balance", "Unstakable balance").await?;
+    assert!(
+        printed
+            .iter()
+            .any(|line| line.contains(&format!("Staked (locked) balance: {STAKE} satoshis"))),
+        "wallet printed {printed:?}"
+    );
+    wallet.exit().await
+}
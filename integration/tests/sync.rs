@@ -0,0 +1,40 @@
+//! A node joining a running network downloads the chain from several peers
+//! and keeps following the validator
+
+use anyhow::{Context, Result};
+use integration::{Network, eventually, height};
+
+/// Blocks the network produces before the new node joins
+const HEIGHT: u64 = 3;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn joining_node_syncs_from_its_peers() -> Result<()> {
+    let mut network = Network::start().await?;
+    let result = join(&mut network).await;
+    result.with_context(|| network.logs())
+}
+
+async fn join(network: &mut Network) -> Result<()> {
+    eventually("the validator to produce blocks", || async {
+        Ok(network.height().await? >= HEIGHT)
+    })
+    .await?;
+    let validator_height = height(network.validator_addr()).await?;
+    assert!(validator_height >= HEIGHT);
+
+    let peers = [
+        network.node_addr().to_string(),
+        network.validator_addr().to_string(),
+    ];
+    let joined = network.join("joined", &peers.each_ref().map(String::as_str))?;
+    eventually("the joining node to catch up", || async {
+        Ok(height(&joined).await? >= network.height().await?.max(HEIGHT))
+    })
+    .await?;
+
+    let synced = height(&joined).await?;
+    eventually("the joining node to follow new blocks", || async {
+        Ok(height(&joined).await? > synced)
+    })
+    .await
+}
@@ -1,38 +1,116 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use poslib::crypto::PrivateKey;
 use poslib::sha256::Hash;
 use poslib::types::{Block, BlockHeader, Transaction, TransactionOutput};
 use poslib::util::{MerkleRoot, Saveable};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::env;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use uuid::Uuid;
 
+fn usage() -> ! {
+    eprintln!(
+        "Usage: block_gen <output_block_file_path> [--locked-until <height>] [--count <n>] [--seed <hex>] [--json]"
+    );
+    exit(1);
+}
+
+/// `block.cbor` becomes `block_0.cbor`, `block_1.cbor`, ... when generating several
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: block_gen <output_block_file_path>");
-        exit(1);
+    let mut args = env::args().skip(1);
+    let Some(path) = args.next() else {
+        usage();
     };
-    let private_key = PrivateKey::new_key();
-    let transactions = vec![Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: poslib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
-            is_stake: true, // Genesis block output is staked so we have a validator
-            locked_until: 0,
-        }],
-    )];
-    let merkel_root = MerkleRoot::calculate(&transactions);
-    let header = BlockHeader::new(
-        Utc::now(),
-        Hash::zero(),
-        merkel_root,
-        private_key.public_key(),
-    );
-    let signature = poslib::crypto::Signature::sign_output(&header.hash(), &private_key);
-    let block = Block::new(header, transactions, signature);
-    block.save_to_file(path).expect("Failed to save block");
+    let mut locked_until = 0;
+    let mut count = 1;
+    let mut seed = None;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--locked-until" => {
+                locked_until = match args.next().map(|value| value.parse()) {
+                    Some(Ok(height)) => height,
+                    _ => usage(),
+                };
+            }
+            "--count" => {
+                count = match args.next().map(|value| value.parse()) {
+                    Some(Ok(count)) if count > 0 => count,
+                    _ => usage(),
+                };
+            }
+            "--seed" => {
+                seed = match args.next().map(|value| Hash::from_hex(&value)) {
+                    Some(Ok(seed)) => Some(seed),
+                    _ => usage(),
+                };
+            }
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    // the same seed gives the same key, ids, timestamps and signatures
+    let (mut rng, start) = match seed {
+        Some(seed) => (StdRng::from_seed(seed.as_bytes()), DateTime::UNIX_EPOCH),
+        None => (StdRng::from_entropy(), Utc::now()),
+    };
+    let private_key = PrivateKey::from_rng(&mut rng);
+    let interval = Duration::seconds(poslib::consensus::MIN_BLOCK_INTERVAL as i64);
+
+    // the first block is a genesis block, every later one builds on the
+    // one before it and is proposed by the genesis staker
+    let mut prev_hash = Hash::zero();
+    let mut prev_randomness = Hash::zero();
+    for index in 0..count {
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                unique_id: Uuid::from_bytes(rng.r#gen()),
+                value: poslib::economics::INITIAL_REWARD * 10u64.pow(8),
+                pubkey: private_key.public_key(),
+                // Genesis block output is staked so we have a validator
+                is_stake: index == 0,
+                locked_until: if index == 0 { locked_until } else { 0 },
+                delegate_to: None,
+            }],
+        )];
+        let merkel_root = MerkleRoot::calculate(&transactions);
+        let mut header = BlockHeader::new(
+            start + interval * index as i32,
+            prev_hash,
+            merkel_root,
+            private_key.public_key(),
+        );
+        if index > 0 {
+            header.set_randomness(&prev_randomness, &private_key);
+        }
+        let signature = poslib::crypto::Signature::sign_output(&header.hash(), &private_key);
+        let block = Block::new(header, transactions, signature);
+        let path = if count == 1 {
+            PathBuf::from(&path)
+        } else {
+            numbered(Path::new(&path), index)
+        };
+        if json {
+            block
+                .save_json_to_file(&path)
+                .expect("Failed to save block");
+        } else {
+            block.save_to_file(&path).expect("Failed to save block");
+        }
+        println!("Block id: {}", block.id());
+        prev_hash = block.id();
+        prev_randomness = block.header.randomness.unwrap_or(prev_hash);
+    }
 }
@@ -11,8 +11,15 @@ fn main() {
         eprintln!("Usage: block_print <block_file_path>");
         exit(1);
     };
+    // files ending in .json are read as JSON, anything else as CBOR
+    let json = path.ends_with(".json");
     if let Ok(file) = File::open(path) {
-        let block = Block::load(file).expect("Failed to load block");
+        let block = if json {
+            Block::load_json(file)
+        } else {
+            Block::load(file)
+        }
+        .expect("Failed to load block");
         println!("{:#?}", block);
     } else {
         eprintln!("Failed to open block file");
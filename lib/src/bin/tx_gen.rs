@@ -1,28 +1,101 @@
 use poslib::crypto::PrivateKey;
-use poslib::types::{Transaction, TransactionOutput};
+use poslib::sha256::Hash;
+use poslib::types::{Transaction, TransactionInput, TransactionOutput};
 use poslib::util::Saveable;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::env;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use uuid::Uuid;
+
+fn usage() -> ! {
+    eprintln!("Usage: tx_gen <output_transaction_file_path> [--count <n>] [--seed <hex>] [--json]");
+    exit(1);
+}
+
+/// `tx.cbor` becomes `tx_0.cbor`, `tx_1.cbor`, ... when generating several
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: tx_gen <output_transaction_file_path>");
-        exit(1);
+    let mut args = env::args().skip(1);
+    let Some(path) = args.next() else {
+        usage();
+    };
+    let mut count = 1;
+    let mut seed = None;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => {
+                count = match args.next().map(|value| value.parse()) {
+                    Some(Ok(count)) if count > 0 => count,
+                    _ => usage(),
+                };
+            }
+            "--seed" => {
+                seed = match args.next().map(|value| Hash::from_hex(&value)) {
+                    Some(Ok(seed)) => Some(seed),
+                    _ => usage(),
+                };
+            }
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    // the same seed gives the same key, ids and signatures
+    let mut rng = match seed {
+        Some(seed) => StdRng::from_seed(seed.as_bytes()),
+        None => StdRng::from_entropy(),
     };
-    let private_key = PrivateKey::new_key();
-    let transaction = Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: poslib::INITIAL_REWARD * 10u64.pow(8),
+    let private_key = PrivateKey::from_rng(&mut rng);
+    let value = poslib::economics::INITIAL_REWARD * 10u64.pow(8);
+
+    // the first transaction creates the coins, every later one spends the
+    // single output of the one before it
+    let mut previous: Option<TransactionOutput> = None;
+    for index in 0..count {
+        let inputs = previous
+            .iter()
+            .map(|output| {
+                TransactionInput::new(
+                    output.hash(),
+                    TransactionInput::SEQUENCE_FINAL,
+                    &private_key,
+                )
+            })
+            .collect();
+        let output = TransactionOutput {
+            unique_id: Uuid::from_bytes(rng.r#gen()),
+            value,
             pubkey: private_key.public_key(),
             is_stake: false,
             locked_until: 0,
-        }],
-    );
-    transaction
-        .save_to_file(path)
-        .expect("Failed to save transaction");
+            delegate_to: None,
+        };
+        let transaction = Transaction::new(inputs, vec![output.clone()]);
+        let path = if count == 1 {
+            PathBuf::from(&path)
+        } else {
+            numbered(Path::new(&path), index)
+        };
+        if json {
+            transaction
+                .save_json_to_file(&path)
+                .expect("Failed to save transaction");
+        } else {
+            transaction
+                .save_to_file(&path)
+                .expect("Failed to save transaction");
+        }
+        println!("{}: {}", path.display(), transaction.hash());
+        previous = Some(output);
+    }
 }
@@ -10,8 +10,15 @@ fn main() {
         eprintln!("Usage: tx_print <transaction_file_path>");
         exit(1);
     };
+    // files ending in .json are read as JSON, anything else as CBOR
+    let json = path.ends_with(".json");
     if let Ok(file) = File::open(path) {
-        let tx = Transaction::load(file).expect("Failed to load transaction");
+        let tx = if json {
+            Transaction::load_json(file)
+        } else {
+            Transaction::load(file)
+        }
+        .expect("Failed to load transaction");
         println!("{:#?}", tx);
     } else {
         eprintln!("Failed to open transaction file");
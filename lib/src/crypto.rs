@@ -1,11 +1,17 @@
+use crate::error::{EthError, Result};
 use crate::sha256::Hash;
 use crate::util::Saveable;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
 use ecdsa::signature::Verifier;
 use ecdsa::{Signature as ECDSASignature, SigningKey, VerifyingKey, signature::Signer};
 use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
 use spki::EncodePublicKey;
+use std::fs::File;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
 
 impl Saveable for PrivateKey {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
@@ -52,6 +58,117 @@ impl std::hash::Hash for PublicKey {
     }
 }
 
+impl PublicKey {
+    /// Lowercase hex of the compressed SEC1 point (33 bytes)
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.to_encoded_point(true).as_bytes())
+    }
+
+    /// Parse a compressed or uncompressed SEC1 point from hex
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.strip_prefix("0x").unwrap_or(s))
+            .map_err(|_| EthError::InvalidPublicKey)?;
+        VerifyingKey::from_sec1_bytes(&bytes)
+            .map(PublicKey)
+            .map_err(|_| EthError::InvalidPublicKey)
+    }
+
+    /// Base58check of the compressed key: a version byte, the 33 key
+    /// bytes and the first 4 bytes of their double SHA-256. The whole key
+    /// is kept since outputs pay keys, not key hashes.
+    pub fn to_address(&self) -> String {
+        let mut payload = vec![ADDRESS_VERSION];
+        payload.extend_from_slice(self.0.to_encoded_point(true).as_bytes());
+        let checksum = address_checksum(&payload);
+        payload.extend_from_slice(&checksum);
+        base58_encode(&payload)
+    }
+
+    /// Parse an address made by `to_address`, rejecting a bad checksum
+    pub fn from_address(address: &str) -> Result<Self> {
+        let bytes = base58_decode(address).ok_or(EthError::InvalidAddress)?;
+        if bytes.len() < 5 || bytes[0] != ADDRESS_VERSION {
+            return Err(EthError::InvalidAddress);
+        }
+        let (payload, checksum) = bytes.split_at(bytes.len() - 4);
+        if address_checksum(payload) != checksum {
+            return Err(EthError::InvalidAddress);
+        }
+        VerifyingKey::from_sec1_bytes(&payload[1..])
+            .map(PublicKey)
+            .map_err(|_| EthError::InvalidAddress)
+    }
+}
+
+/// First byte of every address payload
+const ADDRESS_VERSION: u8 = 0x00;
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn address_checksum(payload: &[u8]) -> [u8; 4] {
+    let once = hex::decode(sha256::digest(payload)).expect("sha256 digest is hex");
+    let twice = hex::decode(sha256::digest(once.as_slice())).expect("sha256 digest is hex");
+    [twice[0], twice[1], twice[2], twice[3]]
+}
+
+fn base58_encode(bytes: &[u8]) -> String {
+    // base 58 digits, least significant first
+    let mut digits: Vec<u8> = vec![];
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // every leading zero byte is written as a '1'
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&d| BASE58_ALPHABET[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+    // base 256 bytes, least significant first
+    let mut bytes: Vec<u8> = vec![];
+    for c in s.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|&a| a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    Some(
+        std::iter::repeat_n(0, zeros)
+            .chain(bytes.into_iter().rev())
+            .collect(),
+    )
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = EthError;
+    fn from_str(s: &str) -> Result<Self> {
+        PublicKey::from_hex(s)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] SigningKey<Secp256k1>);
 
@@ -77,12 +194,87 @@ mod signkey_serde {
     }
 }
 
+/// A private key encrypted with a passphrase: the key is derived with
+/// argon2 from the passphrase and `salt`, and seals the CBOR encoded
+/// private key with ChaCha20-Poly1305
+#[derive(Serialize, Deserialize)]
+struct EncryptedPrivateKey {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedPrivateKey {
+    fn cipher(passphrase: &str, salt: &[u8]) -> IoResult<ChaCha20Poly1305> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidInput, "Failed to derive key"))?;
+        Ok(ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
 impl PrivateKey {
+    /// Save the key encrypted with `passphrase`
+    pub fn save_encrypted<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> IoResult<()> {
+        let mut plaintext = Vec::new();
+        self.save(&mut plaintext)?;
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = EncryptedPrivateKey::cipher(passphrase, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to encrypt PrivateKey"))?;
+        let encrypted = EncryptedPrivateKey {
+            salt: salt.to_vec(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+        ciborium::ser::into_writer(&encrypted, File::create(path)?)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize PrivateKey"))
+    }
+    /// Load a key saved by `save_encrypted`. Plaintext key files are still
+    /// accepted, the passphrase is then ignored.
+    pub fn load_encrypted<P: AsRef<Path>>(path: P, passphrase: &str) -> IoResult<Self> {
+        let bytes = std::fs::read(path)?;
+        let Ok(encrypted) = ciborium::de::from_reader::<EncryptedPrivateKey, _>(bytes.as_slice())
+        else {
+            return Self::load(bytes.as_slice());
+        };
+        if encrypted.nonce.len() != 12 {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize PrivateKey",
+            ));
+        }
+        let plaintext = EncryptedPrivateKey::cipher(passphrase, &encrypted.salt)?
+            .decrypt(
+                Nonce::from_slice(&encrypted.nonce),
+                encrypted.ciphertext.as_slice(),
+            )
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Wrong passphrase"))?;
+        Self::load(plaintext.as_slice())
+    }
+    /// Whether the key file at `path` was written by `save_encrypted`
+    pub fn is_encrypted_file<P: AsRef<Path>>(path: P) -> IoResult<bool> {
+        let bytes = std::fs::read(path)?;
+        Ok(ciborium::de::from_reader::<EncryptedPrivateKey, _>(bytes.as_slice()).is_ok())
+    }
     pub fn new_key() -> Self {
-        PrivateKey(SigningKey::random(&mut rand::thread_rng()))
+        Self::from_rng(&mut rand::thread_rng())
+    }
+    /// Key drawn from `rng`, so a seeded generator gives the same key again
+    pub fn from_rng<R: rand::CryptoRng + rand::RngCore>(rng: &mut R) -> Self {
+        PrivateKey(SigningKey::random(rng))
     }
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
+    }
+    /// Check that this key is the private half of `expected`
+    pub fn ensure_matches(&self, expected: &PublicKey) -> Result<()> {
+        if self.public_key() != *expected {
+            return Err(EthError::KeyMismatch);
+        }
+        Ok(())
     }
 }
 
@@ -99,3 +291,59 @@ impl Signature {
             .is_ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::keys;
+
+    #[test]
+    fn address_round_trip_and_bad_checksum() {
+        for key in keys(814, 4) {
+            let pubkey = key.public_key();
+            let address = pubkey.to_address();
+            assert_eq!(PublicKey::from_address(&address).unwrap(), pubkey);
+
+            // one character changed breaks the checksum
+            let mut corrupted = address.into_bytes();
+            let last = corrupted.last_mut().unwrap();
+            *last = if *last == b'2' { b'3' } else { b'2' };
+            let corrupted = String::from_utf8(corrupted).unwrap();
+            assert!(matches!(
+                PublicKey::from_address(&corrupted),
+                Err(EthError::InvalidAddress)
+            ));
+        }
+        // 0, O, I and l are left out of the base58 alphabet
+        assert!(matches!(
+            PublicKey::from_address("0OIl"),
+            Err(EthError::InvalidAddress)
+        ));
+    }
+
+    #[test]
+    fn encrypted_key_needs_the_passphrase() {
+        let dir = std::env::temp_dir().join(format!("keys-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = keys(784, 1).remove(0);
+        let encrypted = dir.join("alice.priv.cbor");
+        key.save_encrypted(&encrypted, "correct horse").unwrap();
+        // older plaintext files still load
+        let plaintext = dir.join("bob.priv.cbor");
+        key.save_to_file(&plaintext).unwrap();
+
+        let loaded = PrivateKey::load_encrypted(&encrypted, "correct horse");
+        let wrong = PrivateKey::load_encrypted(&encrypted, "battery staple");
+        let from_plaintext = PrivateKey::load_encrypted(&plaintext, "ignored");
+        let flags = (
+            PrivateKey::is_encrypted_file(&encrypted).unwrap(),
+            PrivateKey::is_encrypted_file(&plaintext).unwrap(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.unwrap().public_key(), key.public_key());
+        assert!(matches!(wrong, Err(e) if e.kind() == IoErrorKind::InvalidData));
+        assert_eq!(from_plaintext.unwrap().public_key(), key.public_key());
+        assert_eq!(flags, (true, false));
+    }
+}
@@ -1,11 +1,12 @@
+use crate::sha256::Hash;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum EthError {
-    #[error("Invalid transaction")]
-    InvalidTransaction,
-    #[error("Invalid block")]
-    InvalidBlock,
+    #[error("Invalid transaction: {reason}")]
+    InvalidTransaction { reason: TxRejectReason },
+    #[error("Invalid block: {reason}")]
+    InvalidBlock { reason: BlockRejectReason },
     #[error("Invalid block header")]
     InvalidBlockHeader,
     #[error("Invalid transaction input")]
@@ -20,6 +21,8 @@ pub enum EthError {
     InvalidSignature,
     #[error("Invalid public key")]
     InvalidPublicKey,
+    #[error("Invalid address")]
+    InvalidAddress,
     #[error("Invalid private key")]
     InvalidPrivateKey,
     #[error("Invalid validator")]
@@ -28,10 +31,78 @@ pub enum EthError {
     InvalidStakeAmount,
     #[error("Stake is still locked")]
     StakeLocked,
+    #[error("Coinbase output is not mature yet")]
+    ImmatureCoinbase,
+    #[error("Transaction has expired")]
+    TransactionExpired,
     #[error("Validator has been slashed")]
     ValidatorSlashed,
     #[error("Double signing detected")]
     DoubleSigning,
+    #[error("Private key does not match the expected public key")]
+    KeyMismatch,
+    #[error("Amount overflow")]
+    AmountOverflow,
+    #[error("Invalid chain at height {0}")]
+    InvalidChain(u64),
+    #[error("Block would issue coins past the total supply cap")]
+    SupplyCapExceeded,
+}
+
+/// Why a transaction was rejected. Bad signatures, locked stakes and
+/// immature coinbases have their own `EthError` variants.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxRejectReason {
+    #[error("block has no coinbase")]
+    MissingCoinbase,
+    #[error("coinbase outside of the first position of a block")]
+    MisplacedCoinbase,
+    #[error("coinbases only come inside blocks")]
+    UnexpectedCoinbase,
+    #[error("no outputs")]
+    NoOutputs,
+    #[error("memo is too long")]
+    MemoTooLong,
+    #[error("spends unknown UTXO {0}")]
+    MissingUtxo(Hash),
+    #[error("UTXO {0} spent twice")]
+    DuplicateInput(Hash),
+    #[error("two outputs with hash {0}")]
+    DuplicateOutput(Hash),
+    #[error("amounts overflow")]
+    AmountOverflow,
+    #[error("outputs of {outputs} exceed inputs of {inputs}")]
+    Overspend { inputs: u64, outputs: u64 },
+    #[error("coinbase pays {paid} instead of {expected}")]
+    CoinbaseMismatch { paid: u64, expected: u64 },
+    #[error("conflicts with a final or better paying mempool transaction")]
+    Outbid,
+}
+
+/// Why a block was rejected. Bad validators, signatures and merkle roots
+/// have their own `EthError` variants.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockRejectReason {
+    #[error("no transactions")]
+    Empty,
+    #[error("{count} transactions, at most {cap} allowed")]
+    TooManyTransactions { count: usize, cap: usize },
+    #[error("timestamp too far in the future")]
+    FutureTimestamp,
+    #[error("randomness doesn't follow from its parent")]
+    BadRandomness,
+    #[error("produced too soon after its parent")]
+    TooSoon,
+    #[error("two outputs with hash {0}")]
+    DuplicateOutput(Hash),
+    #[error("parent {0} is not on our chain")]
+    UnknownParent(Hash),
+    #[error("fork starts before our snapshot")]
+    ForkBeforeSnapshot,
+    #[error("fork block does not extend the branch")]
+    NotExtendingBranch,
+    #[error("double-sign evidence is invalid, too old or already used")]
+    InvalidEvidence,
 }
 
 pub type Result<T> = std::result::Result<T, EthError>;
@@ -1,3 +1,5 @@
+// `construct_uint!` expands to code that trips this lint
+#![allow(clippy::manual_div_ceil)]
 use serde::{Deserialize, Serialize};
 use uint::construct_uint;
 construct_uint! {
@@ -8,25 +10,59 @@ pub struct U256(4);
 pub mod crypto;
 pub mod error;
 pub mod network;
+pub mod params;
 pub mod sha256;
 pub mod types;
 pub mod util;
 
-// initial reward in bitcoin - multiply by 10^8 to get satoshis
-pub const INITIAL_REWARD: u64 = 50;
-// halving interval in blocks
-pub const HALVING_INTERVAL: u64 = 210;
-pub const STAKE_MINIMUM_AMOUNT: u64 = 1000 * 10u64.pow(8); // 1000 coins in satoshis
-// maximum age of a transaction in the mempool in seconds -> btc 72h
-pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+pub use params::ChainParams;
 
-// maximum number of transactions in a block
-pub const BLOCK_TRANSACTION_CAP: usize = 20;
-pub const TOTAL_SUPPLY_CAP: u64 = 21_000_000 * 10u64.pow(8); // 21 million coins in satoshis
-// ===== Proof of Stake Constants =====
-// Stake lock period in blocks (unbonding period)
-pub const STAKE_LOCK_PERIOD: u64 = 10; // ~100 blocks before stake can be withdrawn
-// Slashing percentage for double-signing (in basis points, 1000 = 10%)
-pub const SLASHING_PENALTY_DOUBLE_SIGN: u64 = 1000;
-// Slashing percentage for being offline/missing blocks (in basis points)
-pub const SLASHING_PENALTY_DOWNTIME: u64 = 100; // 1%
+// ===== Consensus Constants =====
+// Changing any of these is a hard fork: every node must agree on them
+pub mod consensus {
+    // maximum number of transactions in a block, coinbase excluded
+    // blocks above this are rejected; validators may choose to include fewer
+    pub const BLOCK_TRANSACTION_CAP: usize = 20;
+    pub const STAKE_MINIMUM_AMOUNT: u64 = 1000 * 10u64.pow(8); // 1000 coins in satoshis
+    // Stake lock period in blocks (unbonding period)
+    pub const STAKE_LOCK_PERIOD: u64 = 10; // ~100 blocks before stake can be withdrawn
+    // minimum time between two consecutive blocks, in seconds
+    pub const MIN_BLOCK_INTERVAL: u64 = 5;
+    // blocks a coinbase output must wait before it can be spent, so rewards
+    // from a block that gets reorged away can't have been passed on
+    pub const COINBASE_MATURITY: u64 = 10;
+    // seconds the expected validator has to produce its block before the
+    // slot passes to a re-seeded pick and it is slashed for downtime
+    pub const SLOT_TIMEOUT: u64 = 30;
+    // largest memo a transaction may carry, in bytes
+    pub const MAX_MEMO_SIZE: usize = 80;
+    // seconds a block timestamp may be ahead of the local clock
+    pub const MAX_FUTURE_DRIFT: u64 = 120;
+    // blocks after which double-sign evidence can no longer be included
+    pub const MAX_EVIDENCE_AGE: u64 = 100;
+}
+
+// ===== Economic Constants =====
+// Issuance and penalties - also consensus-relevant once blocks are validated against them
+pub mod economics {
+    // initial reward in bitcoin - multiply by 10^8 to get satoshis
+    pub const INITIAL_REWARD: u64 = 50;
+    // halving interval in blocks
+    pub const HALVING_INTERVAL: u64 = 210;
+    pub const TOTAL_SUPPLY_CAP: u64 = 21_000_000 * 10u64.pow(8); // 21 million coins in satoshis
+    // Slashing percentage for double-signing (in basis points, 1000 = 10%)
+    pub const SLASHING_PENALTY_DOUBLE_SIGN: u64 = 1000;
+    // Slashing percentage for being offline/missing blocks (in basis points)
+    pub const SLASHING_PENALTY_DOWNTIME: u64 = 100; // 1%
+}
+
+// ===== Protocol Constants =====
+// Local node policy - nodes may disagree on these without forking
+pub mod protocol {
+    // maximum age of a transaction in the mempool in seconds -> btc 72h
+    pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+    // fee suggested to wallets when the mempool is empty, in satoshis
+    pub const MIN_FEE_ESTIMATE: u64 = 1000;
+    // blocks waiting for their parent kept at once, oldest evicted first
+    pub const MAX_ORPHAN_BLOCKS: usize = 100;
+}
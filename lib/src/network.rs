@@ -1,17 +1,55 @@
 use serde::{Deserialize, Serialize};
 
-use std::io::{Error as IoError, Read, Write};
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
 
-use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::crypto::{PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::types::{Block, ChainSnapshot, SlashingRecord, Transaction, TransactionOutput};
+
+/// Version of the message protocol, exchanged in `Message::Version`.
+/// Bump it when a change would make older nodes misread messages.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Maximum number of blocks sent in a single ChainSegment
+pub const MAX_CHAIN_SEGMENT: u64 = 500;
+/// Maximum number of UTXOs sent in a single UTXOs page
+pub const MAX_UTXO_PAGE: u64 = 1000;
+/// Largest frame we accept, in bytes. Checked against the length prefix
+/// before anything is allocated; leaves room for a full ChainSegment.
+/// Only messages carrying blocks, pages or snapshots may be this large.
+pub const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+/// Largest frame for a single transaction
+pub const MAX_TRANSACTION_MESSAGE_SIZE: u64 = 1024 * 1024;
+/// Largest frame for every other message: requests, answers made of a few
+/// numbers or keys, handshakes
+pub const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+/// Bytes of a frame read ahead of the rest, enough for the CBOR map and
+/// variant name that tell which message it holds
+const FRAME_HEAD: usize = 32;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
-    /// Fetch all UTXOs belonging to a public key
-    FetchUTXOs(PublicKey),
-    /// UTXOs belonging to a public key
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    /// First message on every connection, answered with our own Version.
+    /// A zero genesis hash (a wallet, or a node with no chain yet) matches
+    /// any network.
+    Version {
+        protocol_version: u32,
+        genesis_hash: Hash,
+    },
+    /// Fetch a page of the UTXOs belonging to a public key. Nodes answer
+    /// with at most `MAX_UTXO_PAGE` entries whatever `limit` asks for
+    FetchUTXOs {
+        key: PublicKey,
+        offset: u64,
+        limit: u64,
+    },
+    /// A page of UTXOs belonging to a public key, and whether more follow
+    UTXOs {
+        utxos: Vec<(TransactionOutput, bool)>,
+        has_more: bool,
+    },
     /// Send a transaction to the network
     SubmitTransaction(Transaction),
     /// Broadcast a new transaction to other nodes
@@ -33,18 +71,31 @@ pub enum Message {
     /// Submit a validated block to a node
     SubmitTemplate(Block),
 
-    /// Report a slashing event (double-signing, downtime)
+    /// Report a double sign. `evidence` is a CBOR `DoubleSignEvidence`;
+    /// the receiver only queues it for the next block it produces, the
+    /// validator is slashed once a block carrying it is applied.
+    /// Downtime is detected from the chain and needs no report.
     SlashValidator {
         validator: PublicKey,
         reason: String,
-        evidence: Vec<u8>, // Block signatures or other proof
+        evidence: Vec<u8>,
     },
     /// Ask a node to report all the other nodes it knows
     /// about. Includes the sender's listening port so the receiver
     /// can connect back.
-    DiscoverNodes(u16),
-    /// This is the response to DiscoverNodes
-    NodeList(Vec<String>),
+    /// The node id lets the receiver spot a connection to itself or a
+    /// peer it already knows under another address.
+    DiscoverNodes { port: u16, node_id: Uuid },
+    /// This is the response to DiscoverNodes, with the responder's node id
+    NodeList { node_id: Uuid, nodes: Vec<String> },
+    /// Ask a node to prove it holds the private half of its node key by
+    /// signing this nonce
+    Challenge(Hash),
+    /// Response to Challenge: the node key and its signature of the nonce
+    ChallengeResponse {
+        public_key: PublicKey,
+        signature: Signature,
+    },
     /// Ask a node whats the highest block it knows about
     /// in comparison to the local blockchain --> 🚨🚨🚨 TODO:: SHOULD BE USING A CONSENSUS ALGO INSTEAD TO AVOID  bogus attack with a node with a very long blockchains ....
     AskDifference(u32),
@@ -52,6 +103,19 @@ pub enum Message {
     Difference(i32),
     /// Ask a node to send a block with the specified height
     FetchBlock(usize),
+    /// Ask a node for the blocks produced and rewards earned by every validator
+    FetchValidatorStats,
+    /// Response to FetchValidatorStats: (blocks produced, rewards earned in
+    /// satoshis) by validator
+    ValidatorStats(HashMap<PublicKey, (u64, u64)>),
+    /// Response to FetchBlock when the node can't serve that height: it is
+    /// past the tip, or the block was skipped by a snapshot import
+    BlockNotFound(usize),
+    /// Ask a node for the blocks in heights `from..to`. Nodes answer with
+    /// at most `MAX_CHAIN_SEGMENT` blocks, so large ranges take several calls
+    FetchChain { from: u64, to: u64 },
+    /// Response to FetchChain, in height order
+    ChainSegment(Vec<Block>),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
     /// Ask the current block height from a node
@@ -60,6 +124,58 @@ pub enum Message {
     BlockHeight(u64),
     /// Response with the next expected validator's public key (None if no validators)
     NextValidator(Option<PublicKey>),
+    /// Ask a node for the transactions waiting in its mempool
+    GetMempool,
+    /// Response to GetMempool
+    Mempool(Vec<Transaction>),
+    /// Liveness check, answered with Pong on the same connection
+    Ping,
+    /// Response to Ping
+    Pong,
+    /// Ask a node how much of the supply has been issued
+    FetchSupplyInfo,
+    /// Response with issued and remaining supply, in satoshis
+    SupplyInfo { issued: u64, remaining: u64 },
+    /// Ask a node for the staking rules it enforces
+    FetchStakeParams,
+    /// Response with the minimum stake and lock period, in blocks
+    StakeParams { min_stake: u64, lock_period: u64 },
+    /// Ask a node what fees the transactions in its mempool pay
+    FetchFeeEstimate,
+    /// Response with the lowest, median and highest mempool fee, in satoshis
+    FeeEstimate { min: u64, median: u64, max: u64 },
+    /// Ask a node for a confirmed transaction by its hash
+    FetchTransaction(Hash),
+    /// Response to FetchTransaction: the height of the block holding the
+    /// transaction, and the transaction itself
+    TransactionFound(Option<(u64, Transaction)>),
+    /// Ask a node how many blocks bury a confirmed transaction
+    FetchConfirmations(Hash),
+    /// Response to FetchConfirmations, `None` if the transaction is not
+    /// in the chain
+    Confirmations(Option<u64>),
+    /// Ask a node for a validator's effective stake (after slashing)
+    FetchStake(PublicKey),
+    /// Response to FetchStake, in satoshis
+    Stake(u64),
+    /// Ask a node for a snapshot of its chain to fast sync from
+    FetchSnapshot,
+    /// Response to FetchSnapshot
+    Snapshot(ChainSnapshot),
+    /// Ask a node for the balance of a public key at its current height,
+    /// without downloading the UTXOs
+    FetchBalance(PublicKey),
+    /// Response to FetchBalance, in satoshis. `spendable` includes
+    /// unlocked stake
+    Balance {
+        spendable: u64,
+        active_stake: u64,
+        unlocked_stake: u64,
+    },
+    /// Ask a node for every slashing event recorded on its chain
+    FetchSlashingHistory,
+    /// Response to FetchSlashingHistory, oldest first
+    SlashingHistory(Vec<SlashingRecord>),
 }
 
 // We are going to use length-prefixed encoding for message
@@ -76,6 +192,68 @@ impl Message {
         ciborium::from_reader(data)
     }
 
+    /// Length of the frame announced by `len_bytes`, refusing any frame
+    /// larger than `MAX_MESSAGE_SIZE`
+    fn frame_len(len_bytes: [u8; 8]) -> Result<usize, ciborium::de::Error<IoError>> {
+        let len = u64::from_be_bytes(len_bytes);
+        if len > MAX_MESSAGE_SIZE {
+            return Err(ciborium::de::Error::Io(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "message of {} bytes exceeds the {} byte limit",
+                    len, MAX_MESSAGE_SIZE
+                ),
+            )));
+        }
+        Ok(len as usize)
+    }
+
+    /// Largest frame accepted for the message variant `name`. Unknown
+    /// names get the smallest limit, they won't decode anyway.
+    fn size_limit(name: &str) -> u64 {
+        match name {
+            "UTXOs" | "Template" | "ValidateTemplate" | "SubmitTemplate" | "NodeList"
+            | "ValidatorStats" | "ChainSegment" | "NewBlock" | "Mempool" | "Snapshot"
+            | "SlashingHistory" => MAX_MESSAGE_SIZE,
+            "SubmitTransaction" | "NewTransaction" | "TransactionFound" => {
+                MAX_TRANSACTION_MESSAGE_SIZE
+            }
+            _ => MAX_REQUEST_SIZE,
+        }
+    }
+
+    /// Name of the variant a frame starting with `head` encodes: a text
+    /// string for unit variants, a one-entry map keyed by it otherwise
+    fn variant_name(head: &[u8]) -> Option<&str> {
+        let head = match head.first()? {
+            0xa1 => &head[1..],
+            _ => head,
+        };
+        let (len, name) = match *head.first()? {
+            byte @ 0x60..=0x77 => ((byte - 0x60) as usize, &head[1..]),
+            0x78 => (*head.get(1)? as usize, &head[2..]),
+            _ => return None,
+        };
+        std::str::from_utf8(name.get(..len)?).ok()
+    }
+
+    /// Refuse a frame of `len` bytes starting with `head` if it is larger
+    /// than the message it holds may be, before the rest is allocated
+    fn check_frame(len: usize, head: &[u8]) -> Result<(), ciborium::de::Error<IoError>> {
+        let name = Self::variant_name(head).unwrap_or_default();
+        let limit = Self::size_limit(name);
+        if len as u64 > limit {
+            return Err(ciborium::de::Error::Io(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} message of {} bytes exceeds its {} byte limit",
+                    name, len, limit
+                ),
+            )));
+        }
+        Ok(())
+    }
+
     pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
         let bytes = self.encode()?;
         let len = bytes.len() as u64;
@@ -88,14 +266,69 @@ impl Message {
     pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = Self::frame_len(len_bytes)?;
 
-        let mut data = vec![0u8; len];
+        let head = len.min(FRAME_HEAD);
+        let mut data = vec![0u8; head];
         stream.read_exact(&mut data)?;
+        Self::check_frame(len, &data)?;
+        data.resize(len, 0);
+        stream.read_exact(&mut data[head..])?;
 
         Self::decode(&data)
     }
 
+    /// Why a peer announcing `protocol_version` and `genesis_hash` can't
+    /// talk to a node whose genesis is `our_genesis`, if it can't
+    pub fn check_version(
+        protocol_version: u32,
+        genesis_hash: Hash,
+        our_genesis: Hash,
+    ) -> Result<(), String> {
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "protocol version {} is not our version {}",
+                protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        if genesis_hash != Hash::zero()
+            && our_genesis != Hash::zero()
+            && genesis_hash != our_genesis
+        {
+            return Err(format!(
+                "genesis {} is not our genesis {}, wrong network",
+                genesis_hash, our_genesis
+            ));
+        }
+        Ok(())
+    }
+
+    /// Open a connection: send our Version and check the one sent back
+    pub async fn handshake_async(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        genesis_hash: Hash,
+    ) -> Result<(), IoError> {
+        let invalid = |e: String| IoError::new(ErrorKind::InvalidData, e);
+        Message::Version {
+            protocol_version: PROTOCOL_VERSION,
+            genesis_hash,
+        }
+        .send_async(stream)
+        .await
+        .map_err(|e| invalid(e.to_string()))?;
+        match Message::receive_async(stream)
+            .await
+            .map_err(|e| invalid(e.to_string()))?
+        {
+            Message::Version {
+                protocol_version,
+                genesis_hash: their_genesis,
+            } => Message::check_version(protocol_version, their_genesis, genesis_hash)
+                .map_err(invalid),
+            _ => Err(invalid("peer did not answer with its version".to_string())),
+        }
+    }
+
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
@@ -113,11 +346,95 @@ impl Message {
     ) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = Self::frame_len(len_bytes)?;
 
-        let mut data = vec![0u8; len];
+        let head = len.min(FRAME_HEAD);
+        let mut data = vec![0u8; head];
         stream.read_exact(&mut data).await?;
+        Self::check_frame(len, &data)?;
+        data.resize(len, 0);
+        stream.read_exact(&mut data[head..]).await?;
 
         Self::decode(&data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// `message` framed as if it were `len` bytes long, padded so the
+    /// head of the frame can be read
+    fn frame(len: u64, message: &Message) -> Cursor<Vec<u8>> {
+        let mut bytes = len.to_be_bytes().to_vec();
+        bytes.extend(message.encode().unwrap());
+        bytes.resize(bytes.len().max(8 + FRAME_HEAD), 0);
+        Cursor::new(bytes)
+    }
+
+    fn is_refused(result: Result<Message, ciborium::de::Error<IoError>>) -> bool {
+        matches!(result, Err(ciborium::de::Error::Io(e)) if e.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_refused() {
+        let mut stream = frame(
+            MAX_MESSAGE_SIZE + 1,
+            &Message::FetchChain { from: 0, to: 1 },
+        );
+        assert!(is_refused(Message::receive(&mut stream)));
+        let mut stream = frame(u64::MAX, &Message::Ping);
+        assert!(is_refused(Message::receive(&mut stream)));
+    }
+
+    #[test]
+    fn variant_is_read_from_the_frame_head() {
+        let name = |message: Message| {
+            Message::variant_name(&message.encode().unwrap())
+                .unwrap_or_default()
+                .to_string()
+        };
+        assert_eq!(name(Message::Ping), "Ping");
+        assert_eq!(name(Message::FetchChain { from: 0, to: 1 }), "FetchChain");
+        assert_eq!(name(Message::ChainSegment(vec![])), "ChainSegment");
+    }
+
+    #[test]
+    fn small_messages_have_a_lower_limit() {
+        let mut stream = frame(MAX_REQUEST_SIZE + 1, &Message::Ping);
+        assert!(is_refused(Message::receive(&mut stream)));
+        let mut stream = frame(MAX_REQUEST_SIZE + 1, &Message::FetchBlockHeight);
+        assert!(is_refused(Message::receive(&mut stream)));
+        // a segment that large is fine, the frame is just cut short
+        let mut stream = frame(MAX_REQUEST_SIZE + 1, &Message::ChainSegment(vec![]));
+        assert!(!is_refused(Message::receive(&mut stream)));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in [
+            Message::Ping,
+            Message::BlockHeight(42),
+            Message::ChainSegment(vec![]),
+        ] {
+            let mut bytes = Vec::new();
+            message.send(&mut bytes).unwrap();
+            let received = Message::receive(&mut Cursor::new(bytes)).unwrap();
+            assert_eq!(format!("{received:?}"), format!("{message:?}"));
+        }
+    }
+
+    #[test]
+    fn other_versions_and_networks_are_refused() {
+        let ours = Hash::hash(&"our genesis");
+        assert!(Message::check_version(PROTOCOL_VERSION, ours, ours).is_ok());
+        assert!(Message::check_version(PROTOCOL_VERSION - 1, ours, ours).is_err());
+        assert!(Message::check_version(PROTOCOL_VERSION + 1, ours, ours).is_err());
+        let theirs = Hash::hash(&"their genesis");
+        assert!(Message::check_version(PROTOCOL_VERSION, theirs, ours).is_err());
+        // a side without a chain yet can't tell, so any genesis goes
+        assert!(Message::check_version(PROTOCOL_VERSION, Hash::zero(), ours).is_ok());
+        assert!(Message::check_version(PROTOCOL_VERSION, theirs, Hash::zero()).is_ok());
+    }
+}
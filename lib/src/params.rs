@@ -0,0 +1,104 @@
+use crate::{consensus, economics, protocol};
+use serde::{Deserialize, Serialize};
+
+/// All tunable chain parameters in one place, so a network (mainnet,
+/// testnet, local devnet) can be described by a single value.
+/// `Default` matches the constants in `consensus`, `economics` and `protocol`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainParams {
+    pub consensus: ConsensusParams,
+    pub economics: EconomicParams,
+    pub protocol: ProtocolParams,
+}
+
+/// Hard-fork-relevant parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConsensusParams {
+    /// Hard limit on non-coinbase transactions per block
+    pub block_transaction_cap: usize,
+    pub stake_minimum_amount: u64,
+    pub stake_lock_period: u64,
+    /// In seconds, between a block and its parent
+    pub min_block_interval: u64,
+    /// In blocks, before a coinbase output can be spent
+    pub coinbase_maturity: u64,
+    /// In seconds, before a missed slot passes to the next validator
+    pub slot_timeout: u64,
+    pub selection_mode: SelectionMode,
+    /// In seconds, how far ahead of the local clock a block may be dated
+    pub max_future_drift: u64,
+    /// In blocks, between the parent of double-signed headers and the
+    /// block carrying the evidence
+    pub max_evidence_age: u64,
+}
+
+/// How the validator of each slot is picked among the staked validators
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SelectionMode {
+    /// Chance proportional to active stake, drawn from the beacon
+    #[default]
+    Weighted,
+    /// Every validator in turn regardless of stake, in public key order
+    RoundRobin,
+}
+
+/// Issuance and slashing parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EconomicParams {
+    /// In whole coins - multiply by 10^8 to get satoshis
+    pub initial_reward: u64,
+    pub halving_interval: u64,
+    pub total_supply_cap: u64,
+    /// Basis points, 10000 = 100%
+    pub slashing_penalty_double_sign: u64,
+    /// Basis points, 10000 = 100%
+    pub slashing_penalty_downtime: u64,
+}
+
+/// Local node policy parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProtocolParams {
+    /// In seconds
+    pub max_mempool_transaction_age: u64,
+    /// Blocks waiting for their parent kept at once
+    pub max_orphan_blocks: usize,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            block_transaction_cap: consensus::BLOCK_TRANSACTION_CAP,
+            stake_minimum_amount: consensus::STAKE_MINIMUM_AMOUNT,
+            stake_lock_period: consensus::STAKE_LOCK_PERIOD,
+            min_block_interval: consensus::MIN_BLOCK_INTERVAL,
+            coinbase_maturity: consensus::COINBASE_MATURITY,
+            slot_timeout: consensus::SLOT_TIMEOUT,
+            selection_mode: SelectionMode::default(),
+            max_future_drift: consensus::MAX_FUTURE_DRIFT,
+            max_evidence_age: consensus::MAX_EVIDENCE_AGE,
+        }
+    }
+}
+
+impl Default for EconomicParams {
+    fn default() -> Self {
+        EconomicParams {
+            initial_reward: economics::INITIAL_REWARD,
+            halving_interval: economics::HALVING_INTERVAL,
+            total_supply_cap: economics::TOTAL_SUPPLY_CAP,
+            slashing_penalty_double_sign: economics::SLASHING_PENALTY_DOUBLE_SIGN,
+            slashing_penalty_downtime: economics::SLASHING_PENALTY_DOWNTIME,
+        }
+    }
+}
+
+impl Default for ProtocolParams {
+    fn default() -> Self {
+        ProtocolParams {
+            max_mempool_transaction_age: protocol::MAX_MEMPOOL_TRANSACTION_AGE,
+            max_orphan_blocks: protocol::MAX_ORPHAN_BLOCKS,
+        }
+    }
+}
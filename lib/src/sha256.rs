@@ -8,6 +8,7 @@ pub struct Hash(U256);
 
 impl Hash {
     // hash anything that can be serde Serialized via ciborium
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
 
@@ -30,6 +31,22 @@ impl Hash {
         Hash(U256::zero())
     }
 
+    /// Lowercase hex, zero-padded to 64 characters
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.as_bytes())
+    }
+
+    /// Parse a hex hash, with or without a `0x` prefix or leading zeros
+    pub fn from_hex(s: &str) -> crate::error::Result<Self> {
+        let s = s.strip_prefix("0x").unwrap_or(s);
+        if s.is_empty() || s.len() > 64 {
+            return Err(crate::error::EthError::InvalidHash);
+        }
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| crate::error::EthError::InvalidHash)
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
         // let mut bytes: Vec<u8> = vec![0; 32];
         self.0.to_big_endian()
@@ -40,6 +57,37 @@ impl Hash {
 use std::fmt;
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:x}", self.0)
+        f.write_str(&self.to_hex())
+    }
+}
+/// Parse the hex form produced by `Display`
+impl std::str::FromStr for Hash {
+    type Err = crate::error::EthError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hash::from_hex(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EthError;
+
+    #[test]
+    fn hex_round_trip_and_bad_input() {
+        let hash = Hash::hash(&"block");
+        let hex = hash.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(Hash::from_hex(&hex).unwrap(), hash);
+        assert_eq!(format!("0x{hex}").parse::<Hash>().unwrap(), hash);
+        assert_eq!(Hash::zero().to_string(), "0".repeat(64));
+        assert_eq!(Hash::from_hex("0").unwrap(), Hash::zero());
+
+        for bad in ["", "0x", &"0".repeat(65), "not hex", "12g4"] {
+            assert!(
+                matches!(Hash::from_hex(bad), Err(EthError::InvalidHash)),
+                "{bad:?} parsed"
+            );
+        }
     }
 }
@@ -2,6 +2,12 @@ mod block;
 mod blockchain;
 mod transaction;
 
-pub use block::{Block, BlockHeader};
-pub use blockchain::{Blockchain, SlashingReason, SlashingRecord};
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
+
+pub use block::{Block, BlockHeader, DoubleSignEvidence};
+pub use blockchain::{
+    Blockchain, ChainSnapshot, MempoolOutcome, SizeEstimate, SlashingReason, SlashingRecord,
+    SlotRecord, StateSize, UtxoSnapshot,
+};
 pub use transaction::{Transaction, TransactionInput, TransactionOutput};
@@ -1,11 +1,11 @@
 use super::{Transaction, TransactionOutput};
-use crate::crypto::{PublicKey, Signature};
-use crate::error::{EthError, Result};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::error::{BlockRejectReason, EthError, Result, TxRejectReason};
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
+use crate::util::{MerkleRoot, sum_amounts};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::util::Saveable;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
@@ -36,47 +36,116 @@ impl Block {
             signature,
         }
     }
+    /// Hash of the whole block, signature included
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    /// Canonical block identity: the header hash, which is also what the
+    /// validator signs. Re-signing the same header keeps the same id, so
+    /// this is what chains link to (`prev_block_hash`) and what blocks are
+    /// indexed and compared by.
+    pub fn id(&self) -> Hash {
+        self.header.hash()
+    }
+    /// The coinbase, which must be the first transaction
+    pub fn coinbase(&self) -> Result<&Transaction> {
+        match self.transactions.first() {
+            Some(coinbase) if coinbase.is_coinbase() => Ok(coinbase),
+            _ => Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::MissingCoinbase,
+            }),
+        }
+    }
+    /// Every transaction after the coinbase. Fails if the block has no
+    /// coinbase or if a coinbase shows up anywhere else.
+    pub fn regular_transactions(&self) -> Result<&[Transaction]> {
+        self.coinbase()?;
+        let transactions = &self.transactions[1..];
+        if transactions.iter().any(Transaction::is_coinbase) {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::MisplacedCoinbase,
+            });
+        }
+        Ok(transactions)
+    }
+    /// `height` is the current chain height, used to reject spends of
+    /// stakes that are still locked
     pub fn verify_transactions(
         &self,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        reward: u64,
+        height: u64,
     ) -> Result<()> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         if self.transactions.is_empty() {
-            return Err(EthError::InvalidBlock);
+            return Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::Empty,
+            });
+        }
+        // outputs are keyed by hash, so two equal outputs would collapse
+        // into a single UTXO. Checked before the coinbase, whose fee sum
+        // would otherwise report it as a transaction error.
+        let mut output_hashes = HashSet::new();
+        for output in self.transactions.iter().flat_map(|tx| &tx.outputs) {
+            if !output_hashes.insert(output.hash()) {
+                return Err(EthError::InvalidBlock {
+                    reason: BlockRejectReason::DuplicateOutput(output.hash()),
+                });
+            }
+        }
+        self.verify_coinbase_transaction(utxos, reward)?;
+        if !self.transactions.iter().all(Transaction::has_valid_memo) {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::MemoTooLong,
+            });
         }
-        self.verify_coinbase_transaction(utxos)?;
-        for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+        for transaction in self.regular_transactions()? {
+            if transaction.is_expired(height) {
+                return Err(EthError::TransactionExpired);
+            }
+            let mut input_value = 0u64;
+            let mut output_value = 0u64;
             for input in &transaction.inputs {
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(EthError::InvalidTransaction);
+                    .map(|(_, output)| output)
+                    .ok_or(EthError::InvalidTransaction {
+                        reason: TxRejectReason::MissingUtxo(input.prev_transaction_output_hash),
+                    })?;
+                if prev_output.is_stake && prev_output.locked_until > height {
+                    return Err(EthError::StakeLocked);
                 }
-                let prev_output = prev_output.unwrap();
                 // 🚨 prevent same-block double-spending
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(EthError::InvalidTransaction);
+                    return Err(EthError::InvalidTransaction {
+                        reason: TxRejectReason::DuplicateInput(input.prev_transaction_output_hash),
+                    });
                 }
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
+                if !input.verify_signature(&prev_output.pubkey, transaction) {
                     return Err(EthError::InvalidSignature);
                 }
-                input_value += prev_output.value;
+                input_value = input_value.checked_add(prev_output.value).ok_or(
+                    EthError::InvalidTransaction {
+                        reason: TxRejectReason::AmountOverflow,
+                    },
+                )?;
                 inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
             for output in &transaction.outputs {
-                output_value += output.value;
+                output_value =
+                    output_value
+                        .checked_add(output.value)
+                        .ok_or(EthError::InvalidTransaction {
+                            reason: TxRejectReason::AmountOverflow,
+                        })?;
             }
             if input_value < output_value {
-                return Err(EthError::InvalidTransaction);
+                return Err(EthError::InvalidTransaction {
+                    reason: TxRejectReason::Overspend {
+                        inputs: input_value,
+                        outputs: output_value,
+                    },
+                });
             }
         }
         Ok(())
@@ -87,63 +156,108 @@ impl Block {
     ) -> Result<u64> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        for transaction in self.transactions.iter().skip(1) {
+        for transaction in self.regular_transactions()? {
             for input in &transaction.inputs {
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(EthError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
+                    .map(|(_, output)| output)
+                    .ok_or(EthError::InvalidTransaction {
+                        reason: TxRejectReason::MissingUtxo(input.prev_transaction_output_hash),
+                    })?;
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(EthError::InvalidTransaction);
+                    return Err(EthError::InvalidTransaction {
+                        reason: TxRejectReason::DuplicateInput(input.prev_transaction_output_hash),
+                    });
                 }
                 inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
             for output in &transaction.outputs {
                 if outputs.contains_key(&output.hash()) {
-                    return Err(EthError::InvalidTransaction);
+                    return Err(EthError::InvalidTransaction {
+                        reason: TxRejectReason::DuplicateOutput(output.hash()),
+                    });
                 }
                 outputs.insert(output.hash(), output.clone());
             }
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
+        let overflow = |_| EthError::InvalidTransaction {
+            reason: TxRejectReason::AmountOverflow,
+        };
+        let input_value =
+            sum_amounts(inputs.values().map(|output| output.value)).map_err(overflow)?;
+        let output_value =
+            sum_amounts(outputs.values().map(|output| output.value)).map_err(overflow)?;
         // Ex : send 100  -> received  90 = 10 fees 🐢
-        Ok(input_value - output_value)
+        input_value
+            .checked_sub(output_value)
+            .ok_or(EthError::InvalidTransaction {
+                reason: TxRejectReason::Overspend {
+                    inputs: input_value,
+                    outputs: output_value,
+                },
+            })
     }
+    /// The coinbase must pay out exactly the block subsidy plus the fees
     pub fn verify_coinbase_transaction(
         &self,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        reward: u64,
     ) -> Result<()> {
-        // coinbase tx is the first transaction in the block
-        let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
-            return Err(EthError::InvalidTransaction);
-        }
-        if coinbase_transaction.outputs.len() == 0 {
-            return Err(EthError::InvalidTransaction);
+        let coinbase_transaction = self.coinbase()?;
+        if coinbase_transaction.outputs.is_empty() {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::NoOutputs,
+            });
         }
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != miner_fees {
-            return Err(EthError::InvalidTransaction);
+        let total_coinbase_outputs = sum_amounts(
+            coinbase_transaction
+                .outputs
+                .iter()
+                .map(|output| output.value),
+        )?;
+        // the validator may claim exactly the block subsidy plus the fees
+        let expected = miner_fees
+            .checked_add(reward)
+            .ok_or(EthError::AmountOverflow)?;
+        if total_coinbase_outputs != expected {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::CoinbaseMismatch {
+                    paid: total_coinbase_outputs,
+                    expected,
+                },
+            });
         }
         Ok(())
     }
 }
 
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+impl Eq for Block {}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
     pub timestamp: DateTime<Utc>,
     pub prev_block_hash: Hash,
     pub merkle_root: MerkleRoot,
     pub validator: PublicKey,
+    /// Randomness beacon output: `Hash::hash(&(prev_randomness, randomness_proof))`.
+    /// Absent on blocks produced before the beacon, which use their id instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub randomness: Option<Hash>,
+    /// The validator's signature over the previous block's randomness.
+    /// Signing is deterministic, so the validator can't grind the beacon.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub randomness_proof: Option<Signature>,
+    /// Proof of validators signing two blocks with the same parent. Part
+    /// of the header so it is signed, kept by snapshots, and slashes the
+    /// same validators on every node applying the block.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<DoubleSignEvidence>,
 }
 impl BlockHeader {
     pub fn new(
@@ -157,9 +271,164 @@ impl BlockHeader {
             prev_block_hash,
             merkle_root,
             validator,
+            randomness: None,
+            randomness_proof: None,
+            evidence: vec![],
         }
     }
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    /// Fill in the randomness beacon from the previous block's randomness
+    pub fn set_randomness(&mut self, prev_randomness: &Hash, private_key: &PrivateKey) {
+        let proof = Signature::sign_output(prev_randomness, private_key);
+        self.randomness = Some(Hash::hash(&(prev_randomness, &proof)));
+        self.randomness_proof = Some(proof);
+    }
+    /// Check the beacon against the previous block's randomness.
+    /// Headers without a beacon are accepted for backward compatibility.
+    pub fn verify_randomness(&self, prev_randomness: &Hash) -> bool {
+        match (&self.randomness, &self.randomness_proof) {
+            (None, None) => true,
+            (Some(randomness), Some(proof)) => {
+                proof.verify(prev_randomness, &self.validator)
+                    && *randomness == Hash::hash(&(prev_randomness, proof))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Saveable for DoubleSignEvidence {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize DoubleSignEvidence",
+            )
+        })
+    }
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize DoubleSignEvidence",
+            )
+        })
+    }
+}
+
+/// Two different headers with the same parent, both signed by the same
+/// validator: proof that it signed two blocks for one height
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DoubleSignEvidence {
+    pub first: (BlockHeader, Signature),
+    pub second: (BlockHeader, Signature),
+}
+impl DoubleSignEvidence {
+    /// Evidence from two signed headers, ordered by id so the same pair
+    /// always makes the same evidence
+    pub fn new(a: (BlockHeader, Signature), b: (BlockHeader, Signature)) -> Self {
+        if a.0.hash().as_bytes() <= b.0.hash().as_bytes() {
+            DoubleSignEvidence {
+                first: a,
+                second: b,
+            }
+        } else {
+            DoubleSignEvidence {
+                first: b,
+                second: a,
+            }
+        }
+    }
+    pub fn validator(&self) -> &PublicKey {
+        &self.first.0.validator
+    }
+    /// Parent both headers build on
+    pub fn parent(&self) -> Hash {
+        self.first.0.prev_block_hash
+    }
+    /// Whether the headers really are two different blocks on the same
+    /// parent, both signed by the validator
+    pub fn is_valid(&self) -> bool {
+        let (first, first_signature) = &self.first;
+        let (second, second_signature) = &self.second;
+        first.validator == second.validator
+            && first.prev_block_hash == second.prev_block_hash
+            && first.hash() != second.hash()
+            && first_signature.verify(&first.hash(), &first.validator)
+            && second_signature.verify(&second.hash(), &second.validator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionInput;
+    use crate::types::fixtures::*;
+
+    fn is_rejected_for(result: Result<()>, expected: TxRejectReason) -> bool {
+        matches!(result, Err(EthError::InvalidTransaction { reason }) if reason == expected)
+    }
+
+    #[test]
+    fn coinbase_must_claim_exactly_subsidy_and_fees() {
+        let node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        let block = node.next_block(vec![payment]);
+        let utxos = node.chain.utxos();
+        let reward = node.chain.calculate_block_reward();
+        block.verify_coinbase_transaction(utxos, reward).unwrap();
+
+        let mut overpaying = block.clone();
+        overpaying.transactions[0].outputs[0].value += 1;
+        assert!(is_rejected_for(
+            overpaying.verify_coinbase_transaction(utxos, reward),
+            TxRejectReason::CoinbaseMismatch {
+                paid: reward + 1001,
+                expected: reward + 1000,
+            }
+        ));
+
+        // with an input it is no longer a coinbase
+        let mut with_input = block.clone();
+        with_input.transactions[0]
+            .inputs
+            .push(TransactionInput::new(
+                spent,
+                TransactionInput::SEQUENCE_FINAL,
+                &owner,
+            ));
+        assert!(is_rejected_for(
+            with_input.verify_coinbase_transaction(utxos, reward),
+            TxRejectReason::MissingCoinbase
+        ));
+    }
+
+    #[test]
+    fn output_repeated_across_transactions_is_refused() {
+        let node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let recipient = keys(798, 1).remove(0).public_key();
+        // two payers reusing one unique_id for the same output
+        let paid = output(COIN, &recipient);
+        let payments: Vec<_> = (0..2)
+            .map(|index| {
+                let owner = &node.validators[index];
+                let (spent, value) = node.allocation_of(index);
+                let input = TransactionInput::new(spent, TransactionInput::SEQUENCE_FINAL, owner);
+                let change = output(value - COIN - 1000, &owner.public_key());
+                Transaction::new(vec![input], vec![paid.clone(), change])
+            })
+            .collect();
+        let block = node.next_block(payments);
+        let reward = node.chain.calculate_block_reward();
+        assert!(matches!(
+            block.verify_transactions(node.chain.utxos(), reward, node.chain.block_height()),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::DuplicateOutput(hash)
+            }) if hash == paid.hash()
+        ));
+    }
 }
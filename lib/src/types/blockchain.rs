@@ -1,18 +1,38 @@
-use super::{Block, Transaction, TransactionOutput};
-use crate::crypto::PublicKey;
-use crate::error::{EthError, Result};
+use super::{Block, BlockHeader, DoubleSignEvidence, Transaction, TransactionOutput};
+use crate::ChainParams;
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BlockRejectReason, EthError, Result, TxRejectReason};
+use crate::params::SelectionMode;
 use crate::sha256::Hash;
 use crate::util::MerkleRoot;
-use crate::util::Saveable;
+use crate::util::{Saveable, write_atomically};
 use chrono::{DateTime, Utc};
-use ecdsa::signature::rand_core::block;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{
+    BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
+};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::{debug, info, trace, warn};
+
+/// First bytes of every zstd frame, how compressed chain files are told apart
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// zstd level for `save_compressed`, the library default
+const COMPRESSION_LEVEL: i32 = 3;
 
 impl Saveable for Blockchain {
+    /// Reads both plain CBOR and the zstd-compressed CBOR written by
+    /// `save_compressed`
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
+        let mut reader = BufReader::new(reader);
+        let result = if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+            ciborium::de::from_reader(zstd::Decoder::with_buffer(reader)?)
+        } else {
+            ciborium::de::from_reader(reader)
+        };
+        result
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Blockchain"))
     }
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -21,10 +41,94 @@ impl Saveable for Blockchain {
     }
 }
 
+impl Saveable for UtxoSnapshot {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize UtxoSnapshot",
+            )
+        })
+    }
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize UtxoSnapshot"))
+    }
+}
+
+/// All unspent outputs as of a given height, for auditing.
+/// Outputs are sorted by hash so two nodes produce identical dumps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoSnapshot {
+    pub height: u64,
+    pub total_value: u64,
+    pub utxos: Vec<(Hash, TransactionOutput)>,
+}
+
+/// Number of entries in one part of the chain state and their encoded size
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct SizeEstimate {
+    pub count: usize,
+    /// Size in the CBOR encoding, close to what it takes on disk
+    pub bytes: u64,
+}
+
+/// How large each part of the chain state has grown, see `state_size`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct StateSize {
+    pub blocks: SizeEstimate,
+    pub utxos: SizeEstimate,
+    pub mempool: SizeEstimate,
+    pub orphans: SizeEstimate,
+}
+
+/// Counts the bytes written to it, to size an encoding without keeping it
+struct ByteCounter(u64);
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Earlier rounds of a slot whose validators are held responsible for
+/// missing it, so a long halt costs a bounded amount of work
+const MAX_MISSED_ROUNDS: u64 = 16;
+
+/// Who was expected to produce a block and who did. A validator misses
+/// its slot when it doesn't produce within `slot_timeout` seconds of the
+/// parent block, and the slot passes to a pick from a re-seeded beacon.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SlotRecord {
+    pub height: u64,
+    pub producer: PublicKey,
+    /// Validators of the earlier rounds of this slot, producer excluded
+    pub missed: Vec<PublicKey>,
+    /// Whether `penalize_missed_slots` has slashed the missed validators
+    pub penalized: bool,
+}
+
+/// Everything a node needs to join at the tip without replaying the chain:
+/// the header and signature of every block, the UTXO set and issuance at
+/// the tip, and the slashing state. Only as trustworthy as its source.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChainSnapshot {
+    pub height: u64,
+    pub headers: Vec<(BlockHeader, Signature)>,
+    pub utxos: Vec<(Hash, TransactionOutput)>,
+    pub total_issued: u64,
+    pub slashing_history: Vec<SlashingRecord>,
+    pub slashed_amounts: HashMap<PublicKey, u64>,
+}
+
 /// Record of a slashing event
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SlashingRecord {
     pub validator: PublicKey,
+    /// Chain height right after the block that triggered the slashing
     pub block_height: u64,
     pub reason: SlashingReason,
     pub penalty_amount: u64,
@@ -36,6 +140,18 @@ pub enum SlashingReason {
     Downtime,
 }
 
+/// What `add_to_mempool` did with a valid transaction.
+/// Only `Added` and `Replaced` are worth relaying to peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MempoolOutcome {
+    /// The transaction was new and is now in the mempool
+    Added,
+    /// The exact same transaction was already in the mempool
+    AlreadyPresent,
+    /// The transaction evicted one or more conflicting mempool transactions
+    Replaced,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 
 pub struct Blockchain {
@@ -45,52 +161,173 @@ pub struct Blockchain {
     mempool: Vec<(DateTime<Utc>, Transaction)>,
     #[serde(default, skip_serializing)]
     orphan_children: HashMap<Hash, Vec<Block>>,
+    /// Ids of the blocks in `orphan_children`, oldest first
+    #[serde(default, skip_serializing)]
+    orphan_order: VecDeque<Hash>,
     /// Slashing records for accountability
     #[serde(default)]
     slashing_history: Vec<SlashingRecord>,
     /// Slashed validators - reduced stake amounts (pubkey -> slashed amount)
     #[serde(default)]
     slashed_amounts: HashMap<PublicKey, u64>,
+    /// Network parameters this chain is validated against
+    #[serde(default)]
+    params: ChainParams,
+    /// Height of every block by id. Rebuilt by `rebuild_utxos`
+    #[serde(skip)]
+    block_index: HashMap<Hash, u64>,
+    /// Validator and parent of every double sign the chain has slashed,
+    /// so the same evidence can't be used twice. Rebuilt by `rebuild_utxos`
+    #[serde(skip)]
+    punished: HashSet<(PublicKey, Hash)>,
+    /// First signed header seen from each validator on each recent parent,
+    /// chain or not, to catch a second one. Local observation only.
+    #[serde(skip)]
+    seen_headers: HashMap<(PublicKey, Hash), (BlockHeader, Signature)>,
+    /// Double signs seen but not yet slashed by a block, for our next
+    /// block to carry
+    #[serde(skip)]
+    pending_evidence: Vec<DoubleSignEvidence>,
+    /// Expected and actual producer of every block past genesis
+    #[serde(default)]
+    slot_records: Vec<SlotRecord>,
+    /// Running total of new coins (genesis allocation plus block subsidies,
+    /// fees excluded), recomputed by `rebuild_utxos`
+    #[serde(default, skip_serializing)]
+    total_issued: u64,
+    /// Height and position of every confirmed transaction, by hash.
+    /// Only kept on archive nodes: it grows by an entry per transaction
+    /// forever, which is the memory cost of answering lookups for any
+    /// historical transaction. Rebuilt by `rebuild_utxos`
+    #[serde(default, skip_serializing)]
+    transaction_index: HashMap<Hash, (u64, usize)>,
+    /// Whether `transaction_index` is maintained, see `set_archive`
+    #[serde(skip)]
+    archive: bool,
+    /// Height at which each coinbase output past genesis was created.
+    /// Rebuilt by `rebuild_utxos`
+    #[serde(default, skip_serializing)]
+    coinbase_heights: HashMap<Hash, u64>,
+    /// Blocks below this height came from a snapshot and have no
+    /// transactions, so they can't be re-validated or served to peers
+    #[serde(default)]
+    pruned_height: u64,
+    /// UTXO set and issuance at `pruned_height`, the starting point for
+    /// replaying the blocks after it
+    #[serde(default)]
+    snapshot_utxos: HashMap<Hash, TransactionOutput>,
+    #[serde(default)]
+    snapshot_issued: u64,
+    /// Cache of `validator_stats`, cleared whenever the chain changes
+    #[serde(skip)]
+    validator_stats: OnceLock<HashMap<PublicKey, (u64, u64)>>,
+}
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Blockchain {
     pub fn new() -> Self {
+        Self::with_params(ChainParams::default())
+    }
+    pub fn with_params(params: ChainParams) -> Self {
         Blockchain {
             blocks: vec![],
             utxos: HashMap::new(),
             mempool: vec![],
             orphan_children: HashMap::new(),
+            orphan_order: VecDeque::new(),
             slashing_history: vec![],
             slashed_amounts: HashMap::new(),
+            params,
+            block_index: HashMap::new(),
+            punished: HashSet::new(),
+            seen_headers: HashMap::new(),
+            pending_evidence: vec![],
+            slot_records: vec![],
+            total_issued: 0,
+            transaction_index: HashMap::new(),
+            archive: false,
+            coinbase_heights: HashMap::new(),
+            pruned_height: 0,
+            snapshot_utxos: HashMap::new(),
+            snapshot_issued: 0,
+            validator_stats: OnceLock::new(),
         }
     }
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+    /// Like `save`, zstd-compressed. `load` detects the format by itself.
+    pub fn save_compressed<O: Write>(&self, writer: O) -> IoResult<()> {
+        let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+        self.save(&mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+    /// Replaces the file atomically, see `write_atomically`
+    pub fn save_to_file_compressed<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        write_atomically(path, |file| self.save_compressed(file))
+    }
     pub fn add_block(&mut self, block: Block) -> Result<()> {
+        // coinbase + at most block_transaction_cap transactions. Checked
+        // first so oversized blocks are never hashed or kept as orphans.
+        if block.transactions.len() > self.params.consensus.block_transaction_cap + 1 {
+            warn!("too many transactions in block");
+            return Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::TooManyTransactions {
+                    count: block.transactions.len(),
+                    cap: self.params.consensus.block_transaction_cap + 1,
+                },
+            });
+        }
+        // a block dated far ahead would hold back every later timestamp
+        // and slot, so it isn't kept even as an orphan
+        let max_drift = chrono::Duration::seconds(self.params.consensus.max_future_drift as i64);
+        if block.header.timestamp > Utc::now() + max_drift {
+            warn!(
+                "block timestamp {} is too far in the future",
+                block.header.timestamp
+            );
+            return Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::FutureTimestamp,
+            });
+        }
+        self.detect_double_sign(&block);
+        let slot_validators;
         if self.blocks.is_empty() {
+            slot_validators = vec![];
             if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                self.orphan_children
-                    .entry(block.header.prev_block_hash)
-                    .or_default()
-                    .push(block);
+                debug!("genesis candidate has a parent, keeping it as an orphan");
+                self.stash_orphan(block);
                 return Ok(());
             }
         } else {
             let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash() {
-                self.orphan_children
-                    .entry(block.header.prev_block_hash)
-                    .or_default()
-                    .push(block);
+            if block.header.prev_block_hash != last_block.id() {
+                self.stash_orphan(block);
                 return Ok(());
             }
             // check if the block's validator is the expected one
-            let expected_validator = self.get_next_validator(&block.header.prev_block_hash);
-            if let Some(validator) = expected_validator {
-                if block.header.validator != validator {
-                    println!("invalid validator");
-                    return Err(EthError::InvalidValidator);
-                }
-            } else {
-                println!("no stakes found");
+            // once a chain uses the beacon, every later block must carry it
+            let prev_randomness = self.randomness();
+            if !block.header.verify_randomness(&prev_randomness)
+                || (last_block.header.randomness.is_some() && block.header.randomness.is_none())
+            {
+                warn!("invalid randomness");
+                return Err(EthError::InvalidBlock {
+                    reason: BlockRejectReason::BadRandomness,
+                });
+            }
+            slot_validators = self
+                .slot_validators(block.header.timestamp)
+                .ok_or_else(|| {
+                    warn!("no stakes found");
+                    EthError::InvalidValidator
+                })?;
+            if slot_validators.last() != Some(&block.header.validator) {
+                warn!("invalid validator");
                 return Err(EthError::InvalidValidator);
             }
             // check if the block's signature is valid
@@ -98,53 +335,263 @@ impl Blockchain {
                 .signature
                 .verify(&block.header.hash(), &block.header.validator)
             {
-                println!("invalid signature");
+                warn!("invalid signature");
                 return Err(EthError::InvalidSignature);
             }
             let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
             if calculated_merkle_root != block.header.merkle_root {
-                println!("invalid merkle root");
+                warn!("invalid merkle root");
                 return Err(EthError::InvalidMerkleRoot);
             }
-            // check if the block's timestamp is after the
-            // last block's timestamp
-            if block.header.timestamp <= last_block.header.timestamp {
-                return Err(EthError::InvalidBlock);
+            // each double sign is slashed once, by the first block carrying it
+            let mut carried = HashSet::new();
+            if !block.header.evidence.iter().all(|evidence| {
+                self.evidence_is_usable(evidence, self.block_height())
+                    && carried.insert((evidence.validator().clone(), evidence.parent()))
+            }) {
+                warn!("invalid double-sign evidence");
+                return Err(EthError::InvalidBlock {
+                    reason: BlockRejectReason::InvalidEvidence,
+                });
+            }
+            // check if the block's timestamp is at least the minimum
+            // interval after the last block's timestamp
+            let min_interval =
+                chrono::Duration::seconds(self.params.consensus.min_block_interval as i64);
+            if block.header.timestamp <= last_block.header.timestamp
+                || block.header.timestamp < last_block.header.timestamp + min_interval
+            {
+                warn!("block produced too soon after its parent");
+                return Err(EthError::InvalidBlock {
+                    reason: BlockRejectReason::TooSoon,
+                });
+            }
+            // the coinbase may only create new coins up to the supply cap
+            let subsidy = Self::coinbase_value(&block)
+                .saturating_sub(block.calculate_miner_fees(&self.utxos)?);
+            if subsidy > self.remaining_supply() {
+                warn!("block would issue past the supply cap");
+                return Err(EthError::SupplyCapExceeded);
             }
             // Verify all transactions in the block
-            block.verify_transactions(&self.utxos)?;
+            block.verify_transactions(
+                &self.utxos,
+                self.calculate_block_reward(),
+                self.block_height(),
+            )?;
+            if block.regular_transactions()?.iter().any(|tx| {
+                Self::spends_immature_coinbase(
+                    &self.coinbase_heights,
+                    tx,
+                    self.block_height(),
+                    self.params.consensus.coinbase_maturity,
+                )
+            }) {
+                warn!("block spends an immature coinbase output");
+                return Err(EthError::ImmatureCoinbase);
+            }
         }
         let block_transactions: HashSet<_> =
             block.transactions.iter().map(|tx| tx.hash()).collect();
-        self.mempool
-            .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
-        self.blocks.push(block);
+        // the next block is one higher, so drop what expires with this one
+        let next_height = self.block_height() + 1;
+        self.mempool.retain(|(_, tx)| {
+            !block_transactions.contains(&tx.hash()) && !tx.is_expired(next_height)
+        });
+        self.commit_block(block, slot_validators);
+        self.prune_evidence();
 
-        let new_tip_hash = self.blocks.last().unwrap().hash();
+        let new_tip_hash = self.blocks.last().unwrap().id();
         self.process_orphans(new_tip_hash);
 
         Ok(())
     }
+    /// Validators of every round of the slot after the tip up to the one
+    /// `timestamp` falls in, the producer's pick last. Rounds past
+    /// `MAX_MISSED_ROUNDS` are not held responsible.
+    fn slot_validators(&self, timestamp: DateTime<Utc>) -> Option<Vec<PublicKey>> {
+        let round = self.slot_round(timestamp);
+        let stakes = self.calculate_stakes();
+        let seed = self.randomness();
+        (0..round.min(MAX_MISSED_ROUNDS))
+            .chain([round])
+            .map(|round| self.round_validator(&stakes, &seed, round))
+            .collect()
+    }
+    /// Apply an already validated block on top of the tip: issuance,
+    /// indexes, UTXO set, slot record and the slashing it triggers.
+    /// `slot_validators` is what `slot_validators` returned before it.
+    /// Shared by `add_block` and the replay in `rebuild_utxos`, so a
+    /// rebuilt chain ends up with exactly the state it was built with.
+    fn commit_block(&mut self, block: Block, slot_validators: Vec<PublicKey>) {
+        let height = self.block_height();
+        self.total_issued =
+            self.total_issued
+                .saturating_add(Self::block_subsidy(&block, &self.utxos, height == 0));
+        self.index_transactions(height, &block);
+        // mempool marks on outputs the block doesn't touch are kept
+        Self::apply_block(&mut self.utxos, &block);
+        self.block_index.insert(block.id(), height);
+        // a later sibling of a chain block is a double sign too
+        self.seen_headers
+            .entry((block.header.validator.clone(), block.header.prev_block_hash))
+            .or_insert_with(|| (block.header.clone(), block.signature.clone()));
+        let double_signers: Vec<_> = block
+            .header
+            .evidence
+            .iter()
+            .map(|evidence| evidence.validator().clone())
+            .collect();
+        for evidence in &block.header.evidence {
+            self.punished
+                .insert((evidence.validator().clone(), evidence.parent()));
+        }
+        // blocks below the snapshot only count through its slashing state
+        let replayed = height >= self.pruned_height;
+        if height > 0 && replayed {
+            let mut missed = vec![];
+            for validator in slot_validators {
+                if validator != block.header.validator && !missed.contains(&validator) {
+                    missed.push(validator);
+                }
+            }
+            self.slot_records.push(SlotRecord {
+                height,
+                producer: block.header.validator.clone(),
+                missed,
+                penalized: false,
+            });
+        }
+        self.blocks.push(block);
+        self.validator_stats.take();
+        if replayed {
+            for validator in double_signers {
+                warn!("slashing {} for double signing", validator);
+                if let Err(e) = self.slash_validator(&validator, SlashingReason::DoubleSigning) {
+                    debug!("could not slash double signer {}: {}", validator, e);
+                }
+            }
+        }
+        self.penalize_missed_slots();
+    }
+    /// Remember the first header each validator signs on each recent
+    /// parent, and keep the evidence when it signs a second one for our
+    /// next block to carry. Nothing is slashed here: which blocks reach
+    /// this node is up to the network, so only evidence included in a
+    /// block changes stakes. Blocks whose parent we don't know yet are
+    /// checked again when `process_orphans` retries them.
+    pub fn detect_double_sign(&mut self, block: &Block) -> Option<DoubleSignEvidence> {
+        let parent = block.header.prev_block_hash;
+        let parent_height = *self.block_index.get(&parent)?;
+        if parent_height + self.params.consensus.max_evidence_age < self.block_height() {
+            return None;
+        }
+        // only a genuine signature is evidence, anyone could forge the header
+        if !block
+            .signature
+            .verify(&block.header.hash(), &block.header.validator)
+        {
+            return None;
+        }
+        let key = (block.header.validator.clone(), parent);
+        let signed = (block.header.clone(), block.signature.clone());
+        let Some(first) = self.seen_headers.get(&key) else {
+            self.seen_headers.insert(key, signed);
+            return None;
+        };
+        if first.0.hash() == block.id() || self.punished.contains(&key) || self.is_pending(&key) {
+            return None;
+        }
+        warn!(
+            "double signing by {} at height {} detected",
+            key.0,
+            parent_height + 1
+        );
+        let evidence = DoubleSignEvidence::new(first.clone(), signed);
+        self.pending_evidence.push(evidence.clone());
+        Some(evidence)
+    }
+    /// Queue double-sign evidence reported by a peer for our next block
+    pub fn report_evidence(&mut self, evidence: DoubleSignEvidence) -> Result<()> {
+        let key = (evidence.validator().clone(), evidence.parent());
+        if !self.evidence_is_usable(&evidence, self.block_height()) {
+            return Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::InvalidEvidence,
+            });
+        }
+        if !self.is_pending(&key) {
+            self.pending_evidence.push(evidence);
+        }
+        Ok(())
+    }
+    /// Evidence the next block should carry
+    pub fn pending_evidence(&self) -> Vec<DoubleSignEvidence> {
+        self.pending_evidence
+            .iter()
+            .filter(|evidence| self.evidence_is_usable(evidence, self.block_height()))
+            .cloned()
+            .collect()
+    }
+    fn is_pending(&self, key: &(PublicKey, Hash)) -> bool {
+        self.pending_evidence
+            .iter()
+            .any(|evidence| *evidence.validator() == key.0 && evidence.parent() == key.1)
+    }
+    /// Whether the block at `height` may carry `evidence`: genuine, on a
+    /// parent of ours at most `max_evidence_age` blocks back, and not
+    /// slashed yet. Only depends on the chain, so every node agrees.
+    fn evidence_is_usable(&self, evidence: &DoubleSignEvidence, height: u64) -> bool {
+        let Some(&parent_height) = self.block_index.get(&evidence.parent()) else {
+            return false;
+        };
+        parent_height + self.params.consensus.max_evidence_age >= height
+            && !self
+                .punished
+                .contains(&(evidence.validator().clone(), evidence.parent()))
+            && evidence.is_valid()
+    }
+    /// Forget observations too old to make usable evidence anymore
+    fn prune_evidence(&mut self) {
+        let horizon = self
+            .block_height()
+            .saturating_sub(self.params.consensus.max_evidence_age);
+        let recent = |parent: &Hash| {
+            self.block_index
+                .get(parent)
+                .is_some_and(|height| *height >= horizon)
+        };
+        self.seen_headers.retain(|(_, parent), _| recent(parent));
+        let pending = std::mem::take(&mut self.pending_evidence);
+        self.pending_evidence = pending
+            .into_iter()
+            .filter(|evidence| {
+                recent(&evidence.parent())
+                    && !self
+                        .punished
+                        .contains(&(evidence.validator().clone(), evidence.parent()))
+            })
+            .collect();
+    }
     pub fn calculate_stakes(&self) -> HashMap<PublicKey, u64> {
         let mut stakes = HashMap::new();
         let current_height = self.block_height();
 
-        println!("=== DEBUG calculate_stakes ===");
-        println!("Current height: {}", current_height);
-        println!("Total UTXOs: {}", self.utxos.len());
+        debug!(
+            height = current_height,
+            utxos = self.utxos.len(),
+            "calculating stakes"
+        );
 
-        for (hash, (marked, output)) in self.utxos.iter() {
+        for (_, output) in self.utxos.values() {
             if output.is_stake {
-                // println!(
-                //     "  Found stake UTXO: value={}, locked_until={}, pubkey={:?}",
-                //     output.value, output.locked_until, output.pubkey
-                // );
                 // Only count stakes that are locked (active validators must have locked stake)
                 if output.locked_until > current_height {
-                    println!("  ✅ -> Counted as active stake");
-                    *stakes.entry(output.pubkey.clone()).or_insert(0) += output.value;
+                    trace!(value = output.value, "counted as active stake");
+                    // delegated stake counts towards the delegate
+                    let stake = stakes.entry(output.staked_for().clone()).or_insert(0u64);
+                    *stake = stake.saturating_add(output.value);
                 } else {
-                    println!("  ❌ -> NOT counted (lock expired)");
+                    trace!(value = output.value, "stake not counted, lock expired");
                 }
             }
         }
@@ -156,21 +603,88 @@ impl Blockchain {
             }
         }
 
-        stakes.retain(|_, v| *v >= Self::get_min_stake_amount());
-        // println!("Final stakes: {:?}", stakes);
-        println!("==============================");
+        let min_stake = self.get_min_stake_amount();
+        stakes.retain(|_, v| *v >= min_stake);
         stakes
     }
-    pub fn get_min_stake_amount() -> u64 {
-        crate::STAKE_MINIMUM_AMOUNT
+    pub fn get_min_stake_amount(&self) -> u64 {
+        self.params.consensus.stake_minimum_amount
+    }
+    /// Randomness of the tip block, used as the seed for picking the next
+    /// validator. Blocks without a beacon fall back to their id, which is
+    /// the previous-hash seed used before the beacon existed.
+    pub fn randomness(&self) -> Hash {
+        self.blocks
+            .last()
+            .map(|b| b.header.randomness.unwrap_or_else(|| b.id()))
+            .unwrap_or(Hash::zero())
     }
     pub fn get_next_validator(&self, seed: &Hash) -> Option<PublicKey> {
-        let stakes = self.calculate_stakes();
-        let total_stake: u64 = stakes.values().sum();
+        Self::select_validator(&self.calculate_stakes(), seed)
+    }
+    /// Round of the slot after the tip that `timestamp` falls in: 0 while
+    /// the validator picked from the beacon may still produce, then one
+    /// more for every `slot_timeout` seconds it has let pass
+    pub fn slot_round(&self, timestamp: DateTime<Utc>) -> u64 {
+        let Some(tip) = self.blocks.last() else {
+            return 0;
+        };
+        let elapsed = (timestamp - tip.header.timestamp).num_seconds().max(0) as u64;
+        elapsed / self.params.consensus.slot_timeout.max(1)
+    }
+    /// Validator allowed to produce the block after the tip at `timestamp`
+    pub fn slot_validator(&self, timestamp: DateTime<Utc>) -> Option<PublicKey> {
+        self.round_validator(
+            &self.calculate_stakes(),
+            &self.randomness(),
+            self.slot_round(timestamp),
+        )
+    }
+    /// Weighted selection picks round 0 from the beacon itself, later
+    /// rounds from the beacon re-seeded with the round number. Round-robin
+    /// moves one validator further along for every missed round.
+    fn round_validator(
+        &self,
+        stakes: &HashMap<PublicKey, u64>,
+        seed: &Hash,
+        round: u64,
+    ) -> Option<PublicKey> {
+        if self.params.consensus.selection_mode == SelectionMode::RoundRobin {
+            // the slot of the block after the tip
+            let slot = self.block_height().wrapping_add(round);
+            Self::select_round_robin(stakes, slot)
+        } else if round == 0 {
+            Self::select_validator(stakes, seed)
+        } else {
+            Self::select_validator(stakes, &Hash::hash(&(seed, round)))
+        }
+    }
+    /// Equal-weight pick from `stakes`: validators with any stake take
+    /// turns in public key order, one per `slot`
+    pub fn select_round_robin(stakes: &HashMap<PublicKey, u64>, slot: u64) -> Option<PublicKey> {
+        let mut validators: Vec<_> = stakes
+            .iter()
+            .filter(|(_, stake)| **stake > 0)
+            .map(|(pubkey, _)| pubkey)
+            .collect();
+        if validators.is_empty() {
+            warn!("0 crypto staked 🐒");
+            return None;
+        }
+        validators.sort();
+        Some(validators[(slot % validators.len() as u64) as usize].clone())
+    }
+    /// Stake-weighted pick from `stakes`, driven only by `seed`.
+    /// Pure function of its inputs so the distribution can be checked
+    /// without building a chain.
+    pub fn select_validator(stakes: &HashMap<PublicKey, u64>, seed: &Hash) -> Option<PublicKey> {
+        let total_stake = stakes
+            .values()
+            .fold(0u64, |sum, stake| sum.saturating_add(*stake));
 
         // Avoid cancel genesis block
         if total_stake == 0 {
-            println!("0 crypto staked 🐒 ");
+            warn!("0 crypto staked 🐒");
             return None;
         }
 
@@ -178,39 +692,452 @@ impl Blockchain {
         bytes.copy_from_slice(&seed.as_bytes()[0..8]);
         let random_value = u64::from_be_bytes(bytes) % total_stake;
 
-        let mut current_sum = 0;
+        let mut current_sum = 0u64;
         // sort stakes by pubkey to ensure deterministic behavior !!!!
-        let mut sorted_stakes: Vec<_> = stakes.into_iter().collect();
-        sorted_stakes.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut sorted_stakes: Vec<_> = stakes.iter().collect();
+        sorted_stakes.sort_by(|a, b| a.0.cmp(b.0));
 
         for (pubkey, stake) in sorted_stakes {
-            current_sum += stake;
+            current_sum = current_sum.saturating_add(*stake);
             if current_sum > random_value {
-                return Some(pubkey);
+                return Some(pubkey.clone());
             }
         }
         None
     }
+    /// Id of the first block, zero while the chain is empty
+    pub fn genesis_hash(&self) -> Hash {
+        self.blocks.first().map(Block::id).unwrap_or(Hash::zero())
+    }
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
     }
+    /// Recompute the UTXO set, indexes, slot records and slashing state
+    /// by replaying the blocks on top of the snapshot they start from.
+    /// `add_block` keeps them current, so this is only needed after
+    /// loading a chain or rolling blocks back, where it also drops the
+    /// slashing done by the blocks that are gone.
     pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.utxos = self.snapshot_utxo_set();
+        self.total_issued = self.snapshot_issued;
+        self.transaction_index.clear();
+        self.coinbase_heights.clear();
+        self.block_index.clear();
+        self.punished.clear();
+        self.slot_records.clear();
+        // slashing records carry the height after the block that caused
+        // them, so the snapshot's own end at `pruned_height`
+        let pruned_height = self.pruned_height;
+        self.slashing_history
+            .retain(|record| record.block_height <= pruned_height);
+        self.slashed_amounts.clear();
+        for record in &self.slashing_history {
+            let slashed = self
+                .slashed_amounts
+                .entry(record.validator.clone())
+                .or_insert(0);
+            *slashed = slashed.saturating_add(record.penalty_amount);
+        }
+        for block in blocks {
+            let slot_validators = if self.blocks.is_empty() || self.block_height() < pruned_height {
+                vec![]
+            } else {
+                self.slot_validators(block.header.timestamp)
+                    .unwrap_or_default()
+            };
+            self.commit_block(block, slot_validators);
+        }
+        // pending transactions still hold their inputs
+        for (_, transaction) in &self.mempool {
+            for input in &transaction.inputs {
+                self.utxos
+                    .entry(input.prev_transaction_output_hash)
+                    .and_modify(|(marked, _)| *marked = true);
+            }
+        }
+    }
+
+    /// Height below which blocks were imported from a snapshot as headers
+    /// only (0 for a fully replayed chain)
+    pub fn pruned_height(&self) -> u64 {
+        self.pruned_height
+    }
+
+    /// The snapshot's UTXO set in the live set's shape (empty if the
+    /// chain was not imported from a snapshot)
+    fn snapshot_utxo_set(&self) -> HashMap<Hash, (bool, TransactionOutput)> {
+        self.snapshot_utxos
+            .iter()
+            .map(|(hash, output)| (*hash, (false, output.clone())))
+            .collect()
+    }
+
+    /// Headers, UTXO set and issuance at the tip, see `import_snapshot`
+    pub fn export_snapshot(&self) -> ChainSnapshot {
+        let mut utxos: Vec<_> = self
+            .utxos
+            .iter()
+            .map(|(hash, (_, output))| (*hash, output.clone()))
+            .collect();
+        utxos.sort_by_key(|(hash, _)| hash.as_bytes());
+        ChainSnapshot {
+            height: self.block_height(),
+            headers: self
+                .blocks
+                .iter()
+                .map(|block| (block.header.clone(), block.signature.clone()))
+                .collect(),
+            utxos,
+            total_issued: self.total_issued,
+            slashing_history: self.slashing_history.clone(),
+            slashed_amounts: self.slashed_amounts.clone(),
+        }
+    }
+
+    /// Replace this chain with `snapshot`. Header links and signatures are
+    /// checked, but the UTXO set and issuance are taken on trust instead of
+    /// being replayed from the transactions.
+    pub fn import_snapshot(&mut self, snapshot: ChainSnapshot) -> Result<()> {
+        if snapshot.headers.len() as u64 != snapshot.height {
+            return Err(EthError::InvalidChain(snapshot.height));
+        }
+        let mut prev_hash = Hash::zero();
+        for (height, (header, signature)) in snapshot.headers.iter().enumerate() {
+            if header.prev_block_hash != prev_hash
+                || !signature.verify(&header.hash(), &header.validator)
+            {
+                warn!("invalid snapshot header at height {}", height);
+                return Err(EthError::InvalidChain(height as u64));
+            }
+            prev_hash = header.hash();
+        }
+        if snapshot
+            .utxos
+            .iter()
+            .any(|(hash, output)| output.hash() != *hash)
+        {
+            warn!("snapshot UTXO does not match its hash");
+            return Err(EthError::InvalidHash);
+        }
+
+        let mut imported = Blockchain::with_params(self.params.clone());
+        imported.archive = self.archive;
+        imported.blocks = snapshot
+            .headers
+            .into_iter()
+            .map(|(header, signature)| Block::new(header, vec![], signature))
+            .collect();
+        imported.snapshot_utxos = snapshot.utxos.into_iter().collect();
+        imported.snapshot_issued = snapshot.total_issued;
+        imported.pruned_height = snapshot.height;
+        imported.slashing_history = snapshot.slashing_history;
+        imported.slashed_amounts = snapshot.slashed_amounts;
+        imported.rebuild_utxos();
+        info!(
+            "imported snapshot at height {} with {} UTXOs",
+            imported.pruned_height,
+            imported.utxos.len()
+        );
+        *self = imported;
+        Ok(())
+    }
+
+    fn index_transactions(&mut self, height: u64, block: &Block) {
+        if self.archive {
+            for (position, transaction) in block.transactions.iter().enumerate() {
+                self.transaction_index
+                    .insert(transaction.hash(), (height, position));
+            }
+        }
+        Self::record_coinbase(&mut self.coinbase_heights, height, block);
+    }
+    /// Keep an index of every confirmed transaction so `find_transaction`
+    /// and `confirmations` work for the whole history. Other nodes skip it
+    /// to save memory and answer those lookups with `None`.
+    pub fn set_archive(&mut self, archive: bool) {
+        self.archive = archive;
+        self.transaction_index.clear();
+        if archive {
+            for (height, block) in self.blocks.iter().enumerate() {
+                for (position, transaction) in block.transactions.iter().enumerate() {
+                    self.transaction_index
+                        .insert(transaction.hash(), (height as u64, position));
+                }
+            }
+        }
+    }
+    pub fn is_archive(&self) -> bool {
+        self.archive
+    }
+    /// Remember the creation height of the coinbase outputs of `block`.
+    /// The genesis allocation can't be reorged away, so it is exempt.
+    fn record_coinbase(coinbase_heights: &mut HashMap<Hash, u64>, height: u64, block: &Block) {
+        if height == 0 {
+            return;
+        }
+        if let Ok(coinbase) = block.coinbase() {
+            for output in &coinbase.outputs {
+                coinbase_heights.insert(output.hash(), height);
+            }
+        }
+    }
+    /// Whether `transaction`, included at `height`, spends a coinbase output
+    /// created less than `maturity` blocks earlier
+    fn spends_immature_coinbase(
+        coinbase_heights: &HashMap<Hash, u64>,
+        transaction: &Transaction,
+        height: u64,
+        maturity: u64,
+    ) -> bool {
+        transaction.inputs.iter().any(|input| {
+            coinbase_heights
+                .get(&input.prev_transaction_output_hash)
+                .is_some_and(|&created| height < created.saturating_add(maturity))
+        })
+    }
+    /// Confirmed transaction with this hash, and the height of its block.
+    /// Always `None` unless this is an archive node
+    pub fn find_transaction(&self, hash: &Hash) -> Option<(u64, Transaction)> {
+        let (height, position) = *self.transaction_index.get(hash)?;
+        let transaction = self
+            .blocks
+            .get(height as usize)?
+            .transactions
+            .get(position)?
+            .clone();
+        Some((height, transaction))
+    }
+    /// Blocks burying a confirmed transaction, its own included: 1 in the
+    /// tip, 5 four blocks below it. `None` if unknown or still pending,
+    /// or if this is not an archive node.
+    pub fn confirmations(&self, hash: &Hash) -> Option<u64> {
+        let (height, _) = self.transaction_index.get(hash)?;
+        Some(self.block_height() - height)
+    }
+    /// New coins created by `block`: its coinbase minus the fees it collects
+    /// from the transactions spending `utxos`, or the whole coinbase for
+    /// genesis
+    fn block_subsidy(
+        block: &Block,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        genesis: bool,
+    ) -> u64 {
+        let fees = if genesis {
+            0
+        } else {
+            block.calculate_miner_fees(utxos).unwrap_or(0)
+        };
+        Self::coinbase_value(block).saturating_sub(fees)
+    }
+    /// Spend the inputs and add the outputs of every transaction in `block`
+    fn apply_block(utxos: &mut HashMap<Hash, (bool, TransactionOutput)>, block: &Block) {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                utxos.remove(&input.prev_transaction_output_hash);
+            }
+            for output in &transaction.outputs {
+                utxos.insert(output.hash(), (false, output.clone()));
+            }
+        }
+    }
+
+    /// Fees collected by each block, replaying the UTXO set they were
+    /// spent against. Genesis collects none.
+    pub fn fees_per_block(&self) -> Vec<u64> {
+        let mut utxos = self.snapshot_utxo_set();
+        let mut fees = Vec::with_capacity(self.blocks.len());
+        for (height, block) in self.blocks.iter().enumerate() {
+            fees.push(if height == 0 {
+                0
+            } else {
+                block.calculate_miner_fees(&utxos).unwrap_or(0)
+            });
+            Self::apply_block(&mut utxos, block);
+        }
+        fees
+    }
+
+    /// Replay the first `height` blocks and return the resulting UTXO set
+    pub fn utxo_snapshot(&self, height: u64) -> UtxoSnapshot {
+        let height = height.min(self.block_height());
+        let mut utxos: HashMap<Hash, TransactionOutput> = HashMap::new();
+        for block in self.blocks.iter().take(height as usize) {
             for transaction in &block.transactions {
                 for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
+                    utxos.remove(&input.prev_transaction_output_hash);
                 }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(output.hash(), (false, output.clone()));
+                for output in &transaction.outputs {
+                    utxos.insert(output.hash(), output.clone());
                 }
             }
         }
+        let mut utxos: Vec<_> = utxos.into_iter().collect();
+        utxos.sort_by_key(|(hash, _)| hash.as_bytes());
+        let total_value = utxos
+            .iter()
+            .fold(0u64, |sum, (_, output)| sum.saturating_add(output.value));
+        UtxoSnapshot {
+            height,
+            total_value,
+            utxos,
+        }
+    }
+
+    /// Walk the whole chain checking prev-hash links, merkle roots,
+    /// signatures and, past genesis, the transactions and coinbase amounts
+    /// against the UTXO set replayed so far. Meant for chains loaded from
+    /// disk, which never went through `add_block`.
+    ///
+    /// Validator selection is not re-checked: the slot validator depends
+    /// on the stakes left after slashing and on the rounds missed before
+    /// each block, which only `rebuild_utxos` replays. A producer signs
+    /// its header, so a block can't be passed off as another validator's.
+    pub fn validate_full_chain(&self) -> Result<()> {
+        let mut utxos = self.snapshot_utxo_set();
+        let mut prev_hash = Hash::zero();
+        let mut issued = self.snapshot_issued;
+        let mut coinbase_heights = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            let height = height as u64;
+            let invalid = |what: &str| {
+                warn!("invalid {} at height {}", what, height);
+                EthError::InvalidChain(height)
+            };
+            if block.header.prev_block_hash != prev_hash {
+                return Err(invalid("prev-hash link"));
+            }
+            // snapshot blocks only have a header left to check
+            if height < self.pruned_height {
+                if !block
+                    .signature
+                    .verify(&block.header.hash(), &block.header.validator)
+                {
+                    return Err(invalid("signature"));
+                }
+                prev_hash = block.id();
+                continue;
+            }
+            if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+                return Err(invalid("merkle root"));
+            }
+            if !block
+                .signature
+                .verify(&block.header.hash(), &block.header.validator)
+            {
+                return Err(invalid("signature"));
+            }
+            if height > 0 {
+                block
+                    .verify_transactions(&utxos, self.reward_within_cap(height, issued), height)
+                    .map_err(|_| invalid("transactions"))?;
+                let transactions = block
+                    .regular_transactions()
+                    .map_err(|_| invalid("transactions"))?;
+                if transactions.iter().any(|tx| {
+                    Self::spends_immature_coinbase(
+                        &coinbase_heights,
+                        tx,
+                        height,
+                        self.params.consensus.coinbase_maturity,
+                    )
+                }) {
+                    return Err(invalid("coinbase maturity"));
+                }
+            }
+            Self::record_coinbase(&mut coinbase_heights, height, block);
+            issued = issued.saturating_add(Self::block_subsidy(block, &utxos, height == 0));
+            Self::apply_block(&mut utxos, block);
+            prev_hash = block.id();
+        }
+        Ok(())
+    }
+
+    /// Blocks waiting for their parent to arrive
+    pub fn orphan_count(&self) -> usize {
+        self.orphan_children.values().map(Vec::len).sum()
+    }
+    /// Whether the block `id` is part of our chain
+    pub fn contains_block(&self, id: &Hash) -> bool {
+        self.block_index.contains_key(id)
+    }
+    /// The stashed orphans leading up to `tip`, oldest first, if they
+    /// branch off a block of our chain. This is what `consider_fork` needs
+    /// when a competing branch arrives one block at a time.
+    pub fn orphan_branch(&self, tip: &Hash) -> Option<Vec<Block>> {
+        let mut branch = vec![];
+        let mut id = *tip;
+        while branch.len() < self.orphan_order.len() {
+            let block = self
+                .orphan_children
+                .values()
+                .flatten()
+                .find(|block| block.id() == id)?;
+            id = block.header.prev_block_hash;
+            branch.push(block.clone());
+            if self.contains_block(&id) {
+                branch.reverse();
+                return Some(branch);
+            }
+        }
+        None
+    }
+    /// Entries and encoded size of the blocks, UTXO set, mempool and
+    /// orphan pool, for deciding when to snapshot. Encodes the whole state,
+    /// so it costs about as much as saving the chain.
+    pub fn state_size(&self) -> StateSize {
+        fn estimate<T: Serialize>(items: impl Iterator<Item = T>) -> SizeEstimate {
+            let mut counter = ByteCounter(0);
+            let mut count = 0;
+            for item in items {
+                // writing to a counter can't fail
+                let _ = ciborium::ser::into_writer(&item, &mut counter);
+                count += 1;
+            }
+            SizeEstimate {
+                count,
+                bytes: counter.0,
+            }
+        }
+        StateSize {
+            blocks: estimate(self.blocks.iter()),
+            utxos: estimate(self.utxos.iter()),
+            mempool: estimate(self.mempool.iter()),
+            orphans: estimate(self.orphan_children.values().flatten()),
+        }
+    }
+
+    /// Keep `block` until its parent arrives. Past `max_orphan_blocks`
+    /// the oldest orphan is dropped, so blocks with made-up parents can't
+    /// pile up forever.
+    fn stash_orphan(&mut self, block: Block) {
+        let id = block.id();
+        if self.orphan_order.contains(&id) {
+            return;
+        }
+        self.orphan_order.push_back(id);
+        self.orphan_children
+            .entry(block.header.prev_block_hash)
+            .or_default()
+            .push(block);
+        while self.orphan_order.len() > self.params.protocol.max_orphan_blocks {
+            let Some(oldest) = self.orphan_order.pop_front() else {
+                break;
+            };
+            debug!("orphan pool full, dropping {}", oldest);
+            self.orphan_children.retain(|_, children| {
+                children.retain(|child| child.id() != oldest);
+                !children.is_empty()
+            });
+        }
     }
 
     pub fn process_orphans(&mut self, parent_hash: Hash) {
         let mut stack = vec![parent_hash];
         while let Some(current_parent) = stack.pop() {
             if let Some(children) = self.orphan_children.remove(&current_parent) {
+                let ids: HashSet<_> = children.iter().map(Block::id).collect();
+                self.orphan_order.retain(|id| !ids.contains(id));
                 for child in children {
                     // Try to add each child. add_block may in turn call process_orphans
                     // recursively when it succeeds. If it fails validation, we drop it
@@ -218,11 +1145,11 @@ impl Blockchain {
                     match self.add_block(child) {
                         Ok(()) => {
                             // child appended: push its hash to stack to process grandchildren
-                            let tip_hash = self.blocks.last().unwrap().hash();
+                            let tip_hash = self.blocks.last().unwrap().id();
                             stack.push(tip_hash);
                         }
                         Err(e) => {
-                            eprintln!("Failed to attach orphan child: {:?}", e);
+                            warn!("failed to attach orphan child: {:?}", e);
                             // If add_block fails (invalid merkle/target/etc.), we simply skip it.
                         }
                     }
@@ -230,49 +1157,213 @@ impl Blockchain {
             }
         }
     }
-    // mempool
+    /// Switch to a competing branch if it is strictly longer than ours from
+    /// the common ancestor. `blocks` must start right after a block we have.
+    /// The branch is validated on a rolled back copy, so an invalid branch
+    /// leaves the chain untouched. Returns the transactions of the abandoned
+    /// blocks that went back into the mempool.
+    pub fn consider_fork(&mut self, blocks: Vec<Block>) -> Result<Vec<Transaction>> {
+        let Some(first) = blocks.first() else {
+            return Ok(vec![]);
+        };
+        let ancestor =
+            *self
+                .block_index
+                .get(&first.header.prev_block_hash)
+                .ok_or(EthError::InvalidBlock {
+                    reason: BlockRejectReason::UnknownParent(first.header.prev_block_hash),
+                })? as usize
+                + 1;
+        if (ancestor as u64) < self.pruned_height {
+            warn!("fork starts before our snapshot, can't replay it");
+            return Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::ForkBeforeSnapshot,
+            });
+        }
+        if blocks.len() <= self.blocks.len() - ancestor {
+            debug!("fork is not longer than the current chain");
+            return Ok(vec![]);
+        }
+
+        let mut candidate = self.clone();
+        candidate.mempool.clear();
+        candidate.orphan_children.clear();
+        candidate.orphan_order.clear();
+        let abandoned = candidate.blocks.split_off(ancestor);
+        candidate.rebuild_utxos();
+        for block in blocks {
+            let id = block.id();
+            candidate.add_block(block)?;
+            // add_block stashes blocks that don't extend the tip as orphans
+            if candidate.blocks.last().map(Block::id) != Some(id) {
+                warn!("fork block does not extend the branch");
+                return Err(EthError::InvalidBlock {
+                    reason: BlockRejectReason::NotExtendingBranch,
+                });
+            }
+        }
+        info!(
+            "reorg: dropping {} blocks after height {}, new height {}",
+            abandoned.len(),
+            ancestor,
+            candidate.block_height()
+        );
+
+        let old_mempool = std::mem::take(&mut self.mempool);
+        let orphans = std::mem::take(&mut self.orphan_children);
+        let orphan_order = std::mem::take(&mut self.orphan_order);
+        *self = candidate;
+        self.orphan_children = orphans;
+        self.orphan_order = orphan_order;
+        // the branch may have come in as orphans, they are on the chain now
+        self.orphan_order
+            .retain(|id| !self.block_index.contains_key(id));
+        self.orphan_children.retain(|_, children| {
+            children.retain(|block| !self.block_index.contains_key(&block.id()));
+            !children.is_empty()
+        });
+
+        // transactions only the abandoned blocks confirmed are pending again,
+        // the ones the new branch also confirmed fail on their spent inputs
+        let mut returned = vec![];
+        for transaction in abandoned
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|transaction| !transaction.is_coinbase())
+        {
+            if self.add_to_mempool(transaction.clone()).is_ok() {
+                info!("transaction {} returned to mempool", transaction.hash());
+                returned.push(transaction);
+            }
+        }
+        for (timestamp, transaction) in old_mempool {
+            let hash = transaction.hash();
+            if self.add_to_mempool(transaction).is_err() {
+                continue;
+            }
+            if let Some((added_at, _)) = self.mempool.iter_mut().find(|(_, tx)| tx.hash() == hash) {
+                *added_at = timestamp;
+            }
+        }
+
+        let tip = self.blocks.last().unwrap().id();
+        self.process_orphans(tip);
+        Ok(returned)
+    }
+    // mempool, highest fee first
     pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
         // later, we will also need to keep track
         &self.mempool
     }
 
     // add a transaction to mempool
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<MempoolOutcome> {
+        // resubmitting the same transaction is a no-op, not a conflict
+        let tx_hash = transaction.hash();
+        if self.mempool.iter().any(|(_, tx)| tx.hash() == tx_hash) {
+            return Ok(MempoolOutcome::AlreadyPresent);
+        }
+        // coinbases only ever come inside blocks
+        if transaction.is_coinbase() {
+            warn!("coinbase transaction submitted to the mempool");
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::UnexpectedCoinbase,
+            });
+        }
+        if !transaction.has_valid_memo() {
+            warn!("transaction memo is too long");
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::MemoTooLong,
+            });
+        }
         // validate transaction before insertion
         // all inputs must match known UTXOs, and must be unique
         let current_height = self.block_height();
+        if transaction.is_expired(current_height) {
+            warn!("transaction expired at block {:?}", transaction.valid_until);
+            return Err(EthError::TransactionExpired);
+        }
         let mut known_inputs = HashSet::new();
 
         for input in &transaction.inputs {
             if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                println!("UTXO not found");
-                dbg!(&self.utxos);
-                return Err(EthError::InvalidTransaction);
+                warn!("UTXO {} not found", input.prev_transaction_output_hash);
+                return Err(EthError::InvalidTransaction {
+                    reason: TxRejectReason::MissingUtxo(input.prev_transaction_output_hash),
+                });
             }
 
             // Check if the UTXO is a locked stake
-            if let Some((_, utxo)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                if utxo.is_stake && utxo.locked_until > current_height {
-                    println!(
-                        "Stake is still locked until block {}, current height is {}",
-                        utxo.locked_until, current_height
-                    );
-                    return Err(EthError::StakeLocked);
-                }
+            if let Some((_, utxo)) = self.utxos.get(&input.prev_transaction_output_hash)
+                && utxo.is_stake
+                && utxo.locked_until > current_height
+            {
+                warn!(
+                    "Stake is still locked until block {}, current height is {}",
+                    utxo.locked_until, current_height
+                );
+                return Err(EthError::StakeLocked);
+            }
+
+            // the input must be signed by the owner of the UTXO it spends
+            if let Some((_, utxo)) = self.utxos.get(&input.prev_transaction_output_hash)
+                && !input.verify_signature(&utxo.pubkey, &transaction)
+            {
+                warn!("invalid input signature");
+                return Err(EthError::InvalidSignature);
             }
 
             if known_inputs.contains(&input.prev_transaction_output_hash) {
-                println!("duplicate input");
-                return Err(EthError::InvalidTransaction);
+                warn!("duplicate input");
+                return Err(EthError::InvalidTransaction {
+                    reason: TxRejectReason::DuplicateInput(input.prev_transaction_output_hash),
+                });
             }
 
             known_inputs.insert(input.prev_transaction_output_hash);
         }
 
+        // block rewards can't be spent until they are buried deep enough
+        if Self::spends_immature_coinbase(
+            &self.coinbase_heights,
+            &transaction,
+            current_height,
+            self.params.consensus.coinbase_maturity,
+        ) {
+            warn!("coinbase output is not mature yet");
+            return Err(EthError::ImmatureCoinbase);
+        }
+
+        // all inputs must be lower than all outputs
+        let fee = match transaction.fee(&self.utxos) {
+            Ok(fee) => fee,
+            Err(e) => {
+                warn!("{e}");
+                return Err(e);
+            }
+        };
+
+        // replace-by-fee: a conflicting mempool transaction is only evicted
+        // if it signals replaceability, and by one paying a strictly higher fee
+        let outbid = self.mempool.iter().any(|(_, other)| {
+            other.inputs.iter().any(|other_input| {
+                transaction.inputs.iter().any(|input| {
+                    input.prev_transaction_output_hash == other_input.prev_transaction_output_hash
+                })
+            }) && (!other.is_replaceable() || other.fee(&self.utxos).unwrap_or(0) >= fee)
+        });
+        if outbid {
+            warn!("conflicting mempool transaction is final or pays an equal or higher fee");
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::Outbid,
+            });
+        }
+
         // check if any of the utxos have the bool mark set to true
         // and if so, find the transaction that references them
         // in mempool, remove it, and set all the utxos it references
         // to false
+        let mut replaced = false;
         for input in &transaction.inputs {
             if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
                 // find the transaction that references the UTXO
@@ -282,10 +1373,10 @@ impl Blockchain {
                         .iter()
                         .enumerate()
                         .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.prev_transaction_output_hash)
+                            transaction.inputs.iter().any(|other| {
+                                other.prev_transaction_output_hash
+                                    == input.prev_transaction_output_hash
+                            })
                         });
 
                 // If we have found one, unmark all of its UTXOs
@@ -301,6 +1392,7 @@ impl Blockchain {
 
                     // remove the transaction from the mempool
                     self.mempool.remove(idx);
+                    replaced = true;
                 } else {
                     // if, somehow, there is no matching transaction,
                     // set this utxo to false
@@ -313,25 +1405,6 @@ impl Blockchain {
             }
         }
 
-        // all inputs must be lower than all outputs
-        let all_inputs = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("BUG: impossible")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs = transaction.outputs.iter().map(|output| output.value).sum();
-
-        if all_inputs < all_outputs {
-            print!("inputs are lower than outputs");
-            return Err(EthError::InvalidTransaction);
-        }
-
         // Mark the UTXOs as used
         for input in &transaction.inputs {
             self.utxos
@@ -344,36 +1417,74 @@ impl Blockchain {
         // push the transaction to the mempool
         self.mempool.push((Utc::now(), transaction));
 
-        // sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("BUG: impossible")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-
-            let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
+        // highest fee first, ties broken by hash so every node agrees
+        let utxos = &self.utxos;
+        self.mempool.sort_by_cached_key(|(_, transaction)| {
+            (
+                Reverse(transaction.fee(utxos).unwrap_or(0)),
+                transaction.hash().as_bytes(),
+            )
         });
 
-        Ok(())
+        if replaced {
+            Ok(MempoolOutcome::Replaced)
+        } else {
+            Ok(MempoolOutcome::Added)
+        }
+    }
+    /// Lowest, median and highest fee paid by the mempool transactions.
+    /// An empty mempool reports `MIN_FEE_ESTIMATE` for all three.
+    pub fn fee_estimate(&self) -> (u64, u64, u64) {
+        let mut fees: Vec<u64> = self
+            .mempool
+            .iter()
+            .filter_map(|(_, transaction)| transaction.fee(&self.utxos).ok())
+            .collect();
+        if fees.is_empty() {
+            let floor = crate::protocol::MIN_FEE_ESTIMATE;
+            return (floor, floor, floor);
+        }
+        fees.sort_unstable();
+        (fees[0], fees[fees.len() / 2], fees[fees.len() - 1])
+    }
+    /// Write the pending transactions, with their arrival time, as CBOR.
+    /// The mempool is not part of the saved blockchain, so this is kept
+    /// in a separate file.
+    pub fn save_mempool<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(&self.mempool, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize mempool"))
+    }
+    /// Re-admit saved transactions through `add_to_mempool`, dropping the
+    /// ones that are no longer valid or too old. Returns how many were kept.
+    /// UTXOs must be rebuilt before calling this.
+    pub fn load_mempool<I: Read>(&mut self, reader: I) -> IoResult<usize> {
+        let saved: Vec<(DateTime<Utc>, Transaction)> = ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize mempool"))?;
+        for (timestamp, transaction) in saved {
+            let hash = transaction.hash();
+            if self.add_to_mempool(transaction).is_err() {
+                continue;
+            }
+            // keep the original arrival time so the transaction still ages out
+            if let Some((added_at, _)) = self.mempool.iter_mut().find(|(_, tx)| tx.hash() == hash) {
+                *added_at = timestamp;
+            }
+        }
+        self.clean_mempool();
+        Ok(self.mempool.len())
+    }
+    /// Seconds a transaction may wait in the mempool before
+    /// `clean_mempool` drops it. Local policy, so each node picks its own.
+    pub fn set_max_mempool_transaction_age(&mut self, seconds: u64) {
+        self.params.protocol.max_mempool_transaction_age = seconds;
     }
     pub fn clean_mempool(&mut self) {
         let now = Utc::now();
         let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        let max_age = self.params.protocol.max_mempool_transaction_age;
 
         self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
+            if now - *timestamp > chrono::Duration::seconds(max_age as i64) {
                 utxo_hashes_to_unmark.extend(
                     transaction
                         .inputs
@@ -391,10 +1502,66 @@ impl Blockchain {
             });
         }
     }
+    fn coinbase_value(block: &Block) -> u64 {
+        block
+            .coinbase()
+            .map(|coinbase| {
+                coinbase
+                    .outputs
+                    .iter()
+                    .fold(0u64, |sum, output| sum.saturating_add(output.value))
+            })
+            .unwrap_or(0)
+    }
+    /// Blocks produced and coinbase value earned (subsidy plus fees) by
+    /// every validator since genesis. Blocks skipped by a snapshot import
+    /// count without their reward. Computed once per tip.
+    pub fn validator_stats(&self) -> HashMap<PublicKey, (u64, u64)> {
+        self.validator_stats
+            .get_or_init(|| {
+                let mut stats: HashMap<PublicKey, (u64, u64)> = HashMap::new();
+                // the genesis coinbase is the initial allocation, not a reward
+                for block in self.blocks.iter().skip(1) {
+                    let entry = stats.entry(block.header.validator.clone()).or_default();
+                    entry.0 += 1;
+                    entry.1 = entry.1.saturating_add(Self::coinbase_value(block));
+                }
+                stats
+            })
+            .clone()
+    }
+    /// New coins issued so far: the genesis allocation plus every block
+    /// subsidy. Fees only move existing coins so they are not counted.
+    pub fn total_issued(&self) -> u64 {
+        self.total_issued
+    }
+    /// What is left to issue before hitting the supply cap
+    pub fn remaining_supply(&self) -> u64 {
+        self.params
+            .economics
+            .total_supply_cap
+            .saturating_sub(self.total_issued)
+    }
+    /// Subsidy for the next block, never more than what is left to issue
     pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / crate::HALVING_INTERVAL;
-        (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+        self.reward_within_cap(self.block_height(), self.total_issued)
+    }
+    /// Subsidy at `height` once `issued` coins exist, clamped to the cap
+    fn reward_within_cap(&self, height: u64, issued: u64) -> u64 {
+        self.block_reward_at(height).min(
+            self.params
+                .economics
+                .total_supply_cap
+                .saturating_sub(issued),
+        )
+    }
+    /// Subsidy for the block at `height`, zero once it has been halved
+    /// away
+    fn block_reward_at(&self, height: u64) -> u64 {
+        let halvings = height / self.params.economics.halving_interval;
+        (self.params.economics.initial_reward * 10u64.pow(8))
+            .checked_shr(u32::try_from(halvings).unwrap_or(u32::MAX))
+            .unwrap_or(0)
     }
     //🚨 Better to have getters than public fields --> for futur stockage purposes
 
@@ -408,8 +1575,8 @@ impl Blockchain {
         }
 
         let penalty_rate = match reason {
-            SlashingReason::DoubleSigning => crate::SLASHING_PENALTY_DOUBLE_SIGN,
-            SlashingReason::Downtime => crate::SLASHING_PENALTY_DOWNTIME,
+            SlashingReason::DoubleSigning => self.params.economics.slashing_penalty_double_sign,
+            SlashingReason::Downtime => self.params.economics.slashing_penalty_downtime,
         };
 
         // Calculate penalty (basis points: 10000 = 100%)
@@ -427,18 +1594,42 @@ impl Blockchain {
         // Add to slashed amounts
         *self.slashed_amounts.entry(pubkey.clone()).or_insert(0) += penalty_amount;
 
-        println!(
-            "🔪 Validator {:?} slashed for {} coins",
+        warn!(
+            "🔪 Validator {} slashed for {} coins",
             pubkey, penalty_amount
         );
         Ok(penalty_amount)
     }
 
+    /// Slash every validator that missed its slot in a block not yet
+    /// penalized, in height order. Only depends on the chain, so every
+    /// node applying the same blocks records the same penalties.
+    pub fn penalize_missed_slots(&mut self) {
+        let mut missed = vec![];
+        for record in self.slot_records.iter_mut().filter(|r| !r.penalized) {
+            record.penalized = true;
+            missed.extend(record.missed.iter().map(|v| (record.height, v.clone())));
+        }
+        for (height, validator) in missed {
+            warn!(
+                "validator {} missed its slot at height {}",
+                validator, height
+            );
+            // a validator that has since unstaked has nothing left to slash
+            if let Err(e) = self.slash_validator(&validator, SlashingReason::Downtime) {
+                debug!("could not slash {} for downtime: {}", validator, e);
+            }
+        }
+    }
+
+    /// Expected and actual producer of every block past genesis
+    pub fn slot_records(&self) -> &[SlotRecord] {
+        &self.slot_records
+    }
+
     /// Check if a validator is currently slashed (has any pending slashing)
     pub fn is_validator_slashed(&self, pubkey: &PublicKey) -> bool {
-        self.slashed_amounts
-            .get(pubkey)
-            .map_or(false, |&amt| amt > 0)
+        self.slashed_amounts.get(pubkey).is_some_and(|&amt| amt > 0)
     }
 
     /// Get the effective stake after slashing penalties
@@ -457,8 +1648,1044 @@ impl Blockchain {
     pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
         &self.utxos
     }
+    /// Value of the UTXOs owned by `pubkey` at `current_height`, as
+    /// (spendable, locked stake, unlocked stake). Spendable counts unlocked
+    /// stake too but not outputs already spent by a mempool transaction.
+    pub fn balance_for(&self, pubkey: &PublicKey, current_height: u64) -> (u64, u64, u64) {
+        let (mut spendable, mut active_stake, mut unlocked_stake) = (0u64, 0u64, 0u64);
+        for (marked, output) in self.utxos.values() {
+            if output.pubkey != *pubkey {
+                continue;
+            }
+            if !marked && !output.is_locked(current_height) {
+                spendable = spendable.saturating_add(output.value);
+            }
+            if output.is_locked(current_height) {
+                active_stake = active_stake.saturating_add(output.value);
+            } else if output.is_stake {
+                unlocked_stake = unlocked_stake.saturating_add(output.value);
+            }
+        }
+        (spendable, active_stake, unlocked_stake)
+    }
+    /// UTXOs owned by `pubkey`, ordered by hash so that offsets stay
+    /// stable between calls while the set is unchanged
+    pub fn iter_utxos_for<'a>(
+        &'a self,
+        pubkey: &PublicKey,
+    ) -> impl Iterator<Item = (&'a Hash, &'a (bool, TransactionOutput))> + use<'a> {
+        let mut owned: Vec<_> = self
+            .utxos
+            .iter()
+            .filter(|(_, (_, output))| output.pubkey == *pubkey)
+            .collect();
+        owned.sort_unstable_by_key(|(hash, _)| hash.as_bytes());
+        owned.into_iter()
+    }
     // blocks
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::types::TransactionInput;
+    use crate::types::fixtures::*;
+
+    /// A block after the tip of `chain` whose producer is not the round 0
+    /// validator, so the round 0 validator gets slashed for downtime
+    fn block_missing_a_slot(chain: &TestChain) -> Block {
+        let expected = chain.chain.slot_validator(chain.next_timestamp()).unwrap();
+        let slot_timeout = chain.chain.params().consensus.slot_timeout as i64;
+        (1..)
+            .map(|round| chain.next_timestamp() + chrono::Duration::seconds(round * slot_timeout))
+            .find(|timestamp| chain.chain.slot_validator(*timestamp).unwrap() != expected)
+            .map(|timestamp| chain.block_at(timestamp, vec![]))
+            .unwrap()
+    }
+
+    #[test]
+    fn reorg_drops_slashing_of_abandoned_blocks() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN, 1000 * COIN]);
+        node.extend(vec![]);
+        let mut other = node.clone();
+
+        let missed = block_missing_a_slot(&node);
+        node.chain.add_block(missed).unwrap();
+        assert_eq!(node.chain.slashing_history().len(), 1);
+
+        let branch = vec![other.extend(vec![]), other.extend(vec![])];
+        node.chain.consider_fork(branch).unwrap();
+
+        assert_eq!(node.chain.block_height(), other.chain.block_height());
+        assert!(node.chain.slashing_history().is_empty());
+        assert_eq!(
+            node.chain.calculate_stakes(),
+            other.chain.calculate_stakes()
+        );
+        assert_eq!(
+            node.chain.slot_records().len(),
+            other.chain.slot_records().len()
+        );
+        assert_eq!(
+            node.chain.slot_validator(node.next_timestamp()),
+            other.chain.slot_validator(other.next_timestamp())
+        );
+    }
+
+    #[test]
+    fn reorg_returns_abandoned_transactions_to_the_mempool() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        node.extend(vec![]);
+        let mut other = node.clone();
+        let (spent, _) = node.allocation_of(0);
+        let payment = spend(
+            &node.validators[0],
+            &[node.allocation_of(0)],
+            &node.validators[1].public_key(),
+            COIN,
+            1000,
+        );
+        node.extend(vec![payment.clone()]);
+        assert!(node.chain.mempool().is_empty());
+
+        let branch = vec![other.extend(vec![]), other.extend(vec![])];
+        let returned = node.chain.consider_fork(branch).unwrap();
+
+        assert_eq!(node.chain.block_height(), other.chain.block_height());
+        assert_eq!(
+            returned.iter().map(Transaction::hash).collect::<Vec<_>>(),
+            vec![payment.hash()]
+        );
+        assert!(
+            node.chain
+                .mempool()
+                .iter()
+                .any(|(_, tx)| tx.hash() == payment.hash())
+        );
+        // spendable again on the new branch, and reserved by the mempool
+        assert_eq!(
+            node.chain.utxos().get(&spent).map(|(marked, _)| *marked),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn memo_and_expiry_are_signed() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let memo = b"invoice 42".to_vec();
+        let input = TransactionInput::signed_for(
+            spent,
+            TransactionInput::SEQUENCE_FINAL,
+            Some(100),
+            Some(&memo),
+            &owner,
+        );
+        let signed = Transaction::new(vec![input], vec![output(value - 1000, &owner.public_key())])
+            .with_valid_until(Some(100))
+            .with_memo(Some(memo));
+        for tampered in [
+            signed.clone().with_memo(Some(b"invoice 43".to_vec())),
+            signed.clone().with_memo(None),
+            signed.clone().with_valid_until(Some(101)),
+        ] {
+            assert!(matches!(
+                node.chain.add_to_mempool(tampered),
+                Err(EthError::InvalidSignature)
+            ));
+        }
+        node.chain.add_to_mempool(signed).unwrap();
+    }
+
+    #[test]
+    fn fork_arriving_as_orphans_is_adopted() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        node.extend(vec![]);
+        let mut other = node.clone();
+        node.extend(vec![]);
+        let branch = [other.extend(vec![]), other.extend(vec![])];
+        let tip = branch[1].id();
+
+        node.chain.add_block(branch[1].clone()).unwrap();
+        assert!(node.chain.orphan_branch(&tip).is_none());
+        node.chain.add_block(branch[0].clone()).unwrap();
+        assert_eq!(node.chain.orphan_count(), 2);
+
+        let found = node.chain.orphan_branch(&tip).unwrap();
+        assert_eq!(
+            found.iter().map(Block::id).collect::<Vec<_>>(),
+            branch.iter().map(Block::id).collect::<Vec<_>>()
+        );
+        node.chain.consider_fork(found).unwrap();
+        assert_eq!(node.chain.blocks().last().map(Block::id), Some(tip));
+        assert_eq!(node.chain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn replacement_must_pay_a_higher_fee() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let payee = PrivateKey::new_key().public_key();
+        let allocation = [node.allocation_of(0)];
+        let paying = |fee| spend_with_sequence(&owner, &allocation, 0, &payee, COIN, fee);
+
+        let first = paying(1000);
+        assert!(matches!(
+            node.chain.add_to_mempool(first.clone()),
+            Ok(MempoolOutcome::Added)
+        ));
+        for fee in [1000, 999] {
+            assert!(matches!(
+                node.chain.add_to_mempool(paying(fee)),
+                Err(EthError::InvalidTransaction {
+                    reason: TxRejectReason::Outbid
+                })
+            ));
+        }
+        let replacement = paying(1001);
+        assert!(matches!(
+            node.chain.add_to_mempool(replacement.clone()),
+            Ok(MempoolOutcome::Replaced)
+        ));
+        let pending: Vec<Hash> = node
+            .chain
+            .mempool()
+            .iter()
+            .map(|(_, tx)| tx.hash())
+            .collect();
+        assert_eq!(pending, vec![replacement.hash()]);
+        assert_eq!(
+            node.chain
+                .utxos()
+                .get(&allocation[0].0)
+                .map(|(marked, _)| *marked),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn mempool_refuses_inputs_signed_by_someone_else() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let thief = PrivateKey::new_key();
+        let allocation = node.allocation_of(0);
+        let stolen = spend(&thief, &[allocation], &thief.public_key(), COIN, 1000);
+        assert!(matches!(
+            node.chain.add_to_mempool(stolen),
+            Err(EthError::InvalidSignature)
+        ));
+        assert!(node.chain.mempool().is_empty());
+        assert_eq!(
+            node.chain
+                .utxos()
+                .get(&allocation.0)
+                .map(|(marked, _)| *marked),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn only_signaling_transactions_are_replaced() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let payee = PrivateKey::new_key().public_key();
+        let allocation = [node.allocation_of(0)];
+
+        let last = spend(&owner, &allocation, &payee, COIN, 1000);
+        assert!(!last.is_replaceable());
+        node.chain.add_to_mempool(last).unwrap();
+        let outbidding = spend_with_sequence(&owner, &allocation, 0, &payee, COIN, 5000);
+        assert!(outbidding.is_replaceable());
+        assert!(matches!(
+            node.chain.add_to_mempool(outbidding),
+            Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::Outbid
+            })
+        ));
+    }
+
+    #[test]
+    fn sequence_is_signed() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let allocation = [node.allocation_of(0)];
+        let mut tampered =
+            spend_with_sequence(&owner, &allocation, 0, &owner.public_key(), COIN, 1000);
+        // made final after signing, so it could no longer be replaced
+        tampered.inputs[0].sequence = TransactionInput::SEQUENCE_FINAL;
+        assert!(matches!(
+            node.chain.add_to_mempool(tampered),
+            Err(EthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn full_orphan_pool_drops_the_oldest() {
+        let mut params = ChainParams::default();
+        params.protocol.max_orphan_blocks = 2;
+        let mut node = TestChain::with_params(&[1000 * COIN], params);
+        let mut other = node.clone();
+        let blocks: Vec<Block> = (0..4).map(|_| other.extend(vec![])).collect();
+
+        for block in &blocks[1..] {
+            node.chain.add_block(block.clone()).unwrap();
+        }
+        // stashing one again doesn't make room for it twice
+        node.chain.add_block(blocks[3].clone()).unwrap();
+        assert_eq!(node.chain.orphan_count(), 2);
+
+        // blocks[1] was dropped, so its children stay orphans
+        node.chain.add_block(blocks[0].clone()).unwrap();
+        assert_eq!(node.chain.block_height(), 2);
+        assert_eq!(node.chain.orphan_count(), 2);
+        node.chain.add_block(blocks[1].clone()).unwrap();
+        assert_eq!(node.chain.block_height(), 5);
+        assert_eq!(node.chain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn rebuild_replays_the_same_state() {
+        let mut node = TestChain::new(&[1000 * COIN, 2000 * COIN]);
+        node.extend(vec![]);
+        let missed = block_missing_a_slot(&node);
+        node.chain.add_block(missed).unwrap();
+        let payment = spend(
+            &node.validators[0],
+            &[node.allocation_of(0)],
+            &node.validators[1].public_key(),
+            COIN,
+            1000,
+        );
+        node.extend(vec![payment]);
+
+        let mut rebuilt = node.chain.clone();
+        rebuilt.rebuild_utxos();
+
+        assert_eq!(utxo_set(&rebuilt), utxo_set(&node.chain));
+        assert_eq!(rebuilt.total_issued(), node.chain.total_issued());
+        assert_eq!(
+            rebuilt.slashing_history().len(),
+            node.chain.slashing_history().len()
+        );
+        assert_eq!(rebuilt.calculate_stakes(), node.chain.calculate_stakes());
+        assert_eq!(
+            rebuilt.slot_records().len(),
+            node.chain.slot_records().len()
+        );
+    }
+
+    #[test]
+    fn double_sign_is_slashed_only_through_a_block() {
+        let mut node = TestChain::new(&[2000 * COIN, 2000 * COIN, 2000 * COIN]);
+        node.extend(vec![]);
+        let first = node.next_block(vec![]);
+        let second = node.next_block(vec![]);
+        let signer = first.header.validator.clone();
+        node.chain.add_block(first.clone()).unwrap();
+        // never receives the second block
+        let mut bystander = node.clone();
+        let stakes = node.chain.calculate_stakes();
+
+        // the sibling is only kept as an orphan, the double sign noted
+        node.chain.add_block(second).unwrap();
+        assert_eq!(node.chain.calculate_stakes(), stakes);
+        let evidence = node.chain.pending_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(*evidence[0].validator(), signer);
+
+        let carrier = node.next_block(vec![]);
+        assert_eq!(carrier.header.evidence.len(), 1);
+        node.chain.add_block(carrier.clone()).unwrap();
+        bystander.chain.add_block(carrier).unwrap();
+        for chain in [&node.chain, &bystander.chain] {
+            assert_eq!(chain.calculate_stakes()[&signer], 1800 * COIN);
+            assert!(matches!(
+                chain.slashing_history(),
+                [SlashingRecord {
+                    reason: SlashingReason::DoubleSigning,
+                    ..
+                }]
+            ));
+        }
+        assert!(node.chain.pending_evidence().is_empty());
+
+        // the same double sign can't be slashed twice
+        let mut replay = node.next_block(vec![]);
+        replay.header.evidence = evidence;
+        let key = node.key_of(&replay.header.validator).clone();
+        let replay = seal(replay.header, replay.transactions, &key);
+        assert!(matches!(
+            node.chain.add_block(replay),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::InvalidEvidence
+            })
+        ));
+    }
+
+    #[test]
+    fn forged_evidence_is_refused() {
+        let mut node = TestChain::new(&[2000 * COIN, 2000 * COIN]);
+        node.extend(vec![]);
+        let first = node.next_block(vec![]);
+        // same parent, but signed by someone else
+        let mut header = first.header.clone();
+        header.validator = node.validators[1].public_key();
+        if header.validator == first.header.validator {
+            header.validator = node.validators[0].public_key();
+        }
+        let other = seal(header.clone(), vec![], node.key_of(&header.validator));
+        let evidence = DoubleSignEvidence::new(
+            (first.header.clone(), first.signature.clone()),
+            (other.header, other.signature),
+        );
+        assert!(node.chain.report_evidence(evidence).is_err());
+        assert!(node.chain.pending_evidence().is_empty());
+    }
+
+    #[test]
+    fn reward_halves_until_nothing_is_left() {
+        let chain = Blockchain::new();
+        let interval = chain.params().economics.halving_interval;
+        assert_eq!(chain.block_reward_at(0), 50 * COIN);
+        assert_eq!(chain.block_reward_at(interval - 1), 50 * COIN);
+        assert_eq!(chain.block_reward_at(interval), 25 * COIN);
+        assert_eq!(chain.block_reward_at(64 * interval), 0);
+        assert_eq!(chain.block_reward_at(u64::MAX), 0);
+    }
+
+    #[test]
+    fn reward_within_cap_never_exceeds_the_supply() {
+        let chain = Blockchain::new();
+        let cap = chain.params().economics.total_supply_cap;
+        assert_eq!(chain.reward_within_cap(0, 0), 50 * COIN);
+        assert_eq!(chain.reward_within_cap(0, cap - COIN), COIN);
+        assert_eq!(chain.reward_within_cap(0, cap), 0);
+        assert_eq!(chain.reward_within_cap(0, cap + COIN), 0);
+    }
+
+    #[test]
+    fn selection_follows_stake_weights() {
+        let validators: Vec<_> = keys(502, 3).iter().map(PrivateKey::public_key).collect();
+        let stakes: HashMap<_, _> = validators
+            .iter()
+            .cloned()
+            .zip([1000 * COIN, 2000 * COIN, 3000 * COIN])
+            .collect();
+        let draws = 6000u64;
+        let mut picks: HashMap<PublicKey, u64> = HashMap::new();
+        for seed in 0..draws {
+            let picked = Blockchain::select_validator(&stakes, &Hash::hash(&seed)).unwrap();
+            *picks.entry(picked).or_default() += 1;
+        }
+        for (validator, weight) in validators.iter().zip([1, 2, 3]) {
+            let expected = draws * weight / 6;
+            let got = picks.get(validator).copied().unwrap_or(0);
+            // within 5% of the expected share
+            assert!(
+                got.abs_diff(expected) * 20 <= expected,
+                "weight {weight}: {got} picks, expected about {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn selection_is_deterministic() {
+        let validators: Vec<_> = keys(7, 4).iter().map(PrivateKey::public_key).collect();
+        let stakes: HashMap<_, _> = validators.iter().cloned().map(|v| (v, COIN)).collect();
+        let seed = Hash::hash(&"beacon");
+        let picked = Blockchain::select_validator(&stakes, &seed);
+        assert!(picked.is_some());
+        // insertion order of the map must not matter
+        let reversed: HashMap<_, _> = validators
+            .iter()
+            .rev()
+            .cloned()
+            .map(|v| (v, COIN))
+            .collect();
+        assert_eq!(Blockchain::select_validator(&reversed, &seed), picked);
+        assert_eq!(Blockchain::select_validator(&HashMap::new(), &seed), None);
+    }
+
+    #[test]
+    fn outputs_summing_past_u64_max_are_refused() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, _) = node.allocation_of(0);
+        let input = TransactionInput::new(spent, TransactionInput::SEQUENCE_FINAL, &owner);
+        let wrapping = Transaction::new(
+            vec![input],
+            vec![
+                output(u64::MAX, &owner.public_key()),
+                output(2, &owner.public_key()),
+            ],
+        );
+        assert!(matches!(
+            node.chain.add_to_mempool(wrapping),
+            Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::AmountOverflow
+            })
+        ));
+        assert!(node.chain.mempool().is_empty());
+    }
+
+    /// The UTXO set of `chain` as (mempool mark, output hash) per entry
+    fn utxo_set(chain: &Blockchain) -> HashMap<Hash, (bool, Hash)> {
+        chain
+            .utxos()
+            .iter()
+            .map(|(hash, (marked, output))| (*hash, (*marked, output.hash())))
+            .collect()
+    }
+
+    #[test]
+    fn incremental_utxos_match_a_rebuild() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let payee = node.validators[1].public_key();
+        let mut funds = node.allocation_of(0);
+        for _ in 0..5 {
+            let payment = spend(&owner, &[funds], &payee, COIN, 1000);
+            let change = &payment.outputs[1];
+            funds = (change.hash(), change.value);
+            node.extend(vec![payment]);
+        }
+        // a pending spend keeps its input marked through the rebuild
+        let pending = spend(&owner, &[funds], &payee, COIN, 1000);
+        node.chain.add_to_mempool(pending).unwrap();
+
+        let mut rebuilt = node.chain.clone();
+        rebuilt.rebuild_utxos();
+        assert_eq!(utxo_set(&rebuilt), utxo_set(&node.chain));
+        assert!(rebuilt.utxos()[&funds.0].0);
+    }
+
+    #[test]
+    fn snapshot_round_trip_keeps_the_state() {
+        let mut node = TestChain::new(&[1000 * COIN, 2000 * COIN]);
+        node.extend(vec![]);
+        let missed = block_missing_a_slot(&node);
+        node.chain.add_block(missed).unwrap();
+        let payment = spend(
+            &node.validators[0],
+            &[node.allocation_of(0)],
+            &node.validators[1].public_key(),
+            COIN,
+            1000,
+        );
+        node.extend(vec![payment]);
+
+        // the way it travels between nodes
+        let mut bytes = vec![];
+        crate::network::Message::Snapshot(node.chain.export_snapshot())
+            .send(&mut bytes)
+            .unwrap();
+        let crate::network::Message::Snapshot(snapshot) =
+            crate::network::Message::receive(&mut std::io::Cursor::new(bytes)).unwrap()
+        else {
+            panic!("not a snapshot");
+        };
+        let mut imported = Blockchain::with_params(node.chain.params().clone());
+        imported.import_snapshot(snapshot).unwrap();
+
+        assert_eq!(imported.pruned_height(), node.chain.block_height());
+        assert_eq!(
+            imported.blocks().last().map(Block::id),
+            node.chain.blocks().last().map(Block::id)
+        );
+        assert_eq!(utxo_set(&imported), utxo_set(&node.chain));
+        assert_eq!(imported.calculate_stakes(), node.chain.calculate_stakes());
+        assert_eq!(imported.total_issued(), node.chain.total_issued());
+        assert_eq!(
+            imported.slashing_history().len(),
+            node.chain.slashing_history().len()
+        );
+        // and it carries on from there
+        let next = node.next_block(vec![]);
+        imported.add_block(next).unwrap();
+    }
+
+    #[test]
+    fn immature_coinbase_cannot_be_spent() {
+        let mut params = ChainParams::default();
+        params.consensus.coinbase_maturity = 3;
+        let mut node = TestChain::with_params(&[1000 * COIN], params);
+        let owner = node.validators[0].clone();
+        let reward = &node.extend(vec![]).transactions[0].outputs[0];
+        let reward = (reward.hash(), reward.value);
+        let cashing = spend(&owner, &[reward], &owner.public_key(), COIN, 1000);
+
+        assert!(matches!(
+            node.chain.add_to_mempool(cashing.clone()),
+            Err(EthError::ImmatureCoinbase)
+        ));
+        // on a copy, the signer of a refused block can't sign another one
+        // for the same height without double signing
+        let early = node.next_block(vec![cashing.clone()]);
+        assert!(matches!(
+            node.chain.clone().add_block(early),
+            Err(EthError::ImmatureCoinbase)
+        ));
+
+        // created at height 1, spendable from height 4 on
+        node.extend(vec![]);
+        node.extend(vec![]);
+        assert_eq!(node.chain.block_height(), 4);
+        node.chain.add_to_mempool(cashing.clone()).unwrap();
+        node.extend(vec![cashing]);
+    }
+
+    #[test]
+    fn block_dated_past_the_drift_is_refused() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let drift =
+            chrono::Duration::seconds(node.chain.params().consensus.max_future_drift as i64);
+
+        let ahead = node.block_at(Utc::now() + drift + chrono::Duration::minutes(1), vec![]);
+        assert!(matches!(
+            node.chain.add_block(ahead),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::FutureTimestamp
+            })
+        ));
+        assert_eq!(node.chain.block_height(), 1);
+        assert_eq!(node.chain.orphan_count(), 0);
+
+        // slightly ahead of our clock is tolerated
+        let close = node.block_at(Utc::now() + drift / 2, vec![]);
+        node.chain.add_block(close).unwrap();
+        assert_eq!(node.chain.block_height(), 2);
+    }
+
+    #[test]
+    fn block_under_the_minimum_interval_is_refused() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        node.extend(vec![]);
+        let hasty = node.block_at(node.next_timestamp() - chrono::Duration::seconds(1), vec![]);
+        // refused on a copy, so the next block isn't a double sign
+        assert!(matches!(
+            node.chain.clone().add_block(hasty),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::TooSoon
+            })
+        ));
+        // exactly the minimum interval is fine
+        let next = node.next_block(vec![]);
+        node.chain.add_block(next).unwrap();
+    }
+
+    #[test]
+    fn oversized_block_is_refused_before_anything_else() {
+        let mut params = ChainParams::default();
+        params.consensus.block_transaction_cap = 1;
+        let mut node = TestChain::with_params(&[1000 * COIN], params);
+        // unknown parent, stale signature and merkle root: none of it is
+        // looked at
+        let mut block = node.next_block(vec![]);
+        let filler = block.transactions[0].clone();
+        block.transactions.extend([filler.clone(), filler]);
+        block.header.prev_block_hash = Hash::hash(&"unknown parent");
+
+        assert!(matches!(
+            node.chain.add_block(block),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::TooManyTransactions { count: 3, cap: 2 }
+            })
+        ));
+        assert_eq!(node.chain.orphan_count(), 0);
+        assert!(node.chain.pending_evidence().is_empty());
+        // not taken for a double sign either
+        node.extend(vec![]);
+        assert!(node.chain.pending_evidence().is_empty());
+    }
+
+    #[test]
+    fn expired_transaction_is_dropped_and_refused() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        // still valid at the current height, expired at the next one
+        let valid_until = node.chain.block_height();
+        let input = TransactionInput::signed_for(
+            spent,
+            TransactionInput::SEQUENCE_FINAL,
+            Some(valid_until),
+            None,
+            &owner,
+        );
+        let payment =
+            Transaction::new(vec![input], vec![output(value - 1000, &owner.public_key())])
+                .with_valid_until(Some(valid_until));
+        node.chain.add_to_mempool(payment.clone()).unwrap();
+
+        node.extend(vec![]);
+        assert!(node.chain.mempool().is_empty());
+        assert!(matches!(
+            node.chain.add_to_mempool(payment.clone()),
+            Err(EthError::TransactionExpired)
+        ));
+        let block = node.next_block(vec![payment]);
+        assert!(matches!(
+            node.chain.clone().add_block(block),
+            Err(EthError::TransactionExpired)
+        ));
+    }
+
+    #[test]
+    fn fee_estimate_reports_min_median_and_max() {
+        let mut node = TestChain::new(&[1000 * COIN; 3]);
+        let floor = crate::protocol::MIN_FEE_ESTIMATE;
+        assert_eq!(node.chain.fee_estimate(), (floor, floor, floor));
+
+        for (index, fee) in [(0, 3000), (1, 1000), (2, 2000)] {
+            let owner = &node.validators[index];
+            let (spent, value) = node.allocation_of(index);
+            let payment = spend(owner, &[(spent, value)], &owner.public_key(), COIN, fee);
+            node.chain.add_to_mempool(payment).unwrap();
+        }
+        assert_eq!(node.chain.fee_estimate(), (1000, 2000, 3000));
+    }
+
+    #[test]
+    fn find_transaction_returns_the_block_height() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        node.chain.set_archive(true);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        node.extend(vec![payment.clone()]);
+        let height = node.chain.block_height() - 1;
+
+        let (found_height, found) = node.chain.find_transaction(&payment.hash()).unwrap();
+        assert_eq!(found_height, height);
+        assert_eq!(found.hash(), payment.hash());
+        assert!(
+            node.chain
+                .find_transaction(&Hash::hash(&"unknown"))
+                .is_none()
+        );
+        // only archive nodes keep the index
+        node.chain.set_archive(false);
+        assert!(node.chain.find_transaction(&payment.hash()).is_none());
+    }
+
+    #[test]
+    fn confirmations_grow_by_one_per_block() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        node.chain.set_archive(true);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        node.chain.add_to_mempool(payment.clone()).unwrap();
+        // pending transactions aren't confirmed yet
+        assert_eq!(node.chain.confirmations(&payment.hash()), None);
+
+        node.extend(vec![payment.clone()]);
+        assert_eq!(node.chain.confirmations(&payment.hash()), Some(1));
+        for expected in 2..=4 {
+            node.extend(vec![]);
+            assert_eq!(node.chain.confirmations(&payment.hash()), Some(expected));
+        }
+    }
+
+    #[test]
+    fn validator_stats_count_blocks_and_rewards() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+
+        let mut expected: HashMap<PublicKey, (u64, u64)> = HashMap::new();
+        fn credit(expected: &mut HashMap<PublicKey, (u64, u64)>, block: &Block, reward: u64) {
+            let entry = expected.entry(block.header.validator.clone()).or_default();
+            entry.0 += 1;
+            entry.1 += reward;
+        }
+        // until both validators have produced
+        while expected.len() < 2 {
+            let reward = node.chain.calculate_block_reward();
+            credit(&mut expected, &node.extend(vec![]), reward);
+        }
+        // the fees go to the producer along with the subsidy
+        let reward = node.chain.calculate_block_reward() + 1000;
+        credit(&mut expected, &node.extend(vec![payment]), reward);
+        // a missed slot is credited to whoever produced it instead
+        let reward = node.chain.calculate_block_reward();
+        let late = block_missing_a_slot(&node);
+        node.chain.add_block(late.clone()).unwrap();
+        credit(&mut expected, &late, reward);
+
+        assert_eq!(node.chain.validator_stats(), expected);
+    }
+
+    #[test]
+    fn state_size_counts_every_part() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let before = node.chain.state_size();
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        node.extend(vec![payment]);
+        node.extend(vec![]);
+        node.extend(vec![]);
+        let other = node.validators[1].clone();
+        let (spent, value) = node.allocation_of(1);
+        let pending = spend(&other, &[(spent, value)], &other.public_key(), COIN, 1000);
+        node.chain.add_to_mempool(pending).unwrap();
+
+        let size = node.chain.state_size();
+        assert_eq!(size.blocks.count as u64, node.chain.block_height());
+        assert_eq!(size.utxos.count, node.chain.utxos().len());
+        assert_eq!(size.mempool.count, 1);
+        assert_eq!(size.orphans.count, 0);
+        assert!(size.blocks.bytes > before.blocks.bytes);
+        assert!(size.utxos.bytes > before.utxos.bytes);
+        assert_eq!(before.mempool.bytes, 0);
+        assert!(size.mempool.bytes > 0);
+    }
+
+    #[test]
+    fn balance_for_separates_spendable_and_stake() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let alice = keys(99, 1).remove(0);
+        let (spent, value) = node.allocation_of(0);
+        let height = node.chain.block_height();
+        let stake = |value, locked_until| TransactionOutput {
+            is_stake: true,
+            locked_until,
+            ..output(value, &alice.public_key())
+        };
+        let payment = output(30 * COIN, &alice.public_key());
+        let input = TransactionInput::signed_for(
+            spent,
+            TransactionInput::SEQUENCE_FINAL,
+            None,
+            None,
+            &owner,
+        );
+        let outputs = vec![
+            stake(100 * COIN, height + 10),
+            stake(50 * COIN, height),
+            payment.clone(),
+            output(value - 180 * COIN - 1000, &owner.public_key()),
+        ];
+        node.extend(vec![Transaction::new(vec![input], outputs)]);
+        let height = node.chain.block_height();
+        assert_eq!(
+            node.chain.balance_for(&alice.public_key(), height),
+            (80 * COIN, 100 * COIN, 50 * COIN)
+        );
+
+        // reserved by the mempool, so no longer spendable
+        let pending = spend(
+            &alice,
+            &[(payment.hash(), payment.value)],
+            &owner.public_key(),
+            COIN,
+            1000,
+        );
+        node.chain.add_to_mempool(pending).unwrap();
+        assert_eq!(
+            node.chain.balance_for(&alice.public_key(), height),
+            (50 * COIN, 100 * COIN, 50 * COIN)
+        );
+        // the same sums the wallet would make from the UTXOs
+        let spendable: u64 = node
+            .chain
+            .iter_utxos_for(&alice.public_key())
+            .filter(|(_, (marked, output))| !marked && !output.is_locked(height))
+            .map(|(_, (_, output))| output.value)
+            .sum();
+        assert_eq!(spendable, 50 * COIN);
+        // once the lock ends the stake is unlocked and spendable
+        assert_eq!(
+            node.chain.balance_for(&alice.public_key(), height + 10),
+            (150 * COIN, 0, 150 * COIN)
+        );
+    }
+
+    #[test]
+    fn delegated_stake_weighs_for_the_delegate() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let delegate = node.validators[1].public_key();
+        let alice = keys(803, 1).remove(0).public_key();
+        let (spent, value) = node.allocation_of(0);
+        let input = TransactionInput::signed_for(
+            spent,
+            TransactionInput::SEQUENCE_FINAL,
+            None,
+            None,
+            &owner,
+        );
+        let delegation = TransactionOutput {
+            is_stake: true,
+            locked_until: STAKE_LOCKED_UNTIL,
+            delegate_to: Some(delegate.clone()),
+            ..output(3000 * COIN, &alice)
+        };
+        let change = output(value - 3000 * COIN - 1000, &owner.public_key());
+        node.extend(vec![Transaction::new(
+            vec![input],
+            vec![delegation, change],
+        )]);
+
+        let stakes = node.chain.calculate_stakes();
+        assert_eq!(stakes.get(&delegate), Some(&(4000 * COIN)));
+        assert_eq!(stakes.get(&owner.public_key()), Some(&(1000 * COIN)));
+        assert!(!stakes.contains_key(&alice));
+        // the delegate is picked for about four slots out of five
+        let draws = 2000u64;
+        let picked = (0..draws)
+            .filter(|seed| {
+                node.chain.get_next_validator(&Hash::hash(seed)) == Some(delegate.clone())
+            })
+            .count() as u64;
+        let expected = draws * 4 / 5;
+        assert!(
+            picked.abs_diff(expected) * 20 <= expected,
+            "{picked} picks, expected about {expected}"
+        );
+    }
+
+    #[test]
+    fn round_robin_rotates_regardless_of_stake() {
+        let mut params = ChainParams::default();
+        params.consensus.selection_mode = SelectionMode::RoundRobin;
+        let mut node = TestChain::with_params(&[1000 * COIN, 5000 * COIN, 3000 * COIN], params);
+        let mut order: Vec<_> = node.validators.iter().map(PrivateKey::public_key).collect();
+        order.sort();
+
+        let mut produced: HashMap<PublicKey, u64> = HashMap::new();
+        for _ in 0..9 {
+            let slot = node.chain.block_height();
+            let block = node.extend(vec![]);
+            assert_eq!(block.header.validator, order[(slot % 3) as usize]);
+            *produced.entry(block.header.validator).or_default() += 1;
+        }
+        assert!(
+            order
+                .iter()
+                .all(|validator| produced.get(validator) == Some(&3))
+        );
+        // a missed round passes the slot to the next one in line
+        let slot = node.chain.block_height();
+        let late = block_missing_a_slot(&node);
+        assert_eq!(late.header.validator, order[((slot + 1) % 3) as usize]);
+    }
+
+    #[test]
+    fn corrupted_merkle_root_fails_full_validation() {
+        let mut node = TestChain::new(&[1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        node.extend(vec![payment]);
+        node.extend(vec![]);
+        node.chain.validate_full_chain().unwrap();
+
+        // the payment no longer matches the merkle root its block committed to
+        node.chain.blocks[1].transactions[1].outputs[0].value += 1;
+        assert!(matches!(
+            node.chain.validate_full_chain(),
+            Err(EthError::InvalidChain(1))
+        ));
+    }
+
+    #[test]
+    fn mempool_survives_a_save_and_restore() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        for index in 0..2 {
+            let owner = node.validators[index].clone();
+            let (spent, value) = node.allocation_of(index);
+            let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+            node.chain.add_to_mempool(payment).unwrap();
+        }
+        let mut saved = vec![];
+        node.chain.save_mempool(&mut saved).unwrap();
+
+        // a restarted node: same blocks, nothing pending
+        let restart = |node: &TestChain| {
+            let mut restarted = node.clone();
+            restarted.chain.mempool.clear();
+            restarted.chain.rebuild_utxos();
+            restarted
+        };
+        let mut restarted = restart(&node).chain;
+        assert_eq!(restarted.load_mempool(saved.as_slice()).unwrap(), 2);
+        let pending = |chain: &Blockchain| -> Vec<_> {
+            chain
+                .mempool()
+                .iter()
+                .map(|(added_at, tx)| (*added_at, tx.hash()))
+                .collect()
+        };
+        assert_eq!(pending(&restarted), pending(&node.chain));
+        assert_eq!(utxo_set(&restarted), utxo_set(&node.chain));
+
+        // a transaction confirmed in the meantime isn't restored
+        let mut restarted = restart(&node);
+        restarted.extend(vec![node.chain.mempool()[0].1.clone()]);
+        assert_eq!(restarted.chain.load_mempool(saved.as_slice()).unwrap(), 1);
+        assert!(matches!(
+            restarted.chain.load_mempool(&b"not a mempool"[..]),
+            Err(error) if error.kind() == IoErrorKind::InvalidData
+        ));
+    }
+
+    #[test]
+    fn mempool_drops_transactions_past_the_max_age() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        node.chain.set_max_mempool_transaction_age(60);
+        for index in 0..2 {
+            let owner = node.validators[index].clone();
+            let (spent, value) = node.allocation_of(index);
+            let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+            node.chain.add_to_mempool(payment).unwrap();
+        }
+        let (old, kept) = (
+            node.chain.mempool[0].1.clone(),
+            node.chain.mempool[1].1.clone(),
+        );
+        node.chain.mempool[0].0 = Utc::now() - chrono::Duration::seconds(61);
+
+        node.chain.clean_mempool();
+        assert_eq!(node.chain.mempool().len(), 1);
+        assert_eq!(node.chain.mempool()[0].1.hash(), kept.hash());
+        // the evicted transaction's input is spendable again
+        let marked =
+            |tx: &Transaction| node.chain.utxos()[&tx.inputs[0].prev_transaction_output_hash].0;
+        assert!(!marked(&old));
+        assert!(marked(&kept));
+    }
+
+    #[test]
+    fn compressed_chain_loads_back() {
+        let mut node = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let owner = node.validators[0].clone();
+        let (spent, value) = node.allocation_of(0);
+        let payment = spend(&owner, &[(spent, value)], &owner.public_key(), COIN, 1000);
+        node.extend(vec![payment]);
+        for _ in 0..10 {
+            node.extend(vec![]);
+        }
+        let (mut plain, mut compressed) = (vec![], vec![]);
+        node.chain.save(&mut plain).unwrap();
+        node.chain.save_compressed(&mut compressed).unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+        assert!(compressed.len() < plain.len());
+
+        // `load` tells the two formats apart by itself
+        for saved in [plain, compressed] {
+            let mut loaded = Blockchain::load(saved.as_slice()).unwrap();
+            loaded.rebuild_utxos();
+            let ids = |chain: &Blockchain| chain.blocks().map(Block::id).collect::<Vec<_>>();
+            assert_eq!(ids(&loaded), ids(&node.chain));
+            assert_eq!(utxo_set(&loaded), utxo_set(&node.chain));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Chains built from scratch for the unit tests, here and in the crates
+//! enabling the `test-fixtures` feature
+//!
+//! Validator keys come from a seeded generator so failures can be
+//! replayed. Blocks are dated in the past, one `min_block_interval` apart,
+//! and always produced by the validator the slot belongs to.
+
+use super::{Block, BlockHeader, Blockchain, Transaction, TransactionInput, TransactionOutput};
+use crate::ChainParams;
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::util::MerkleRoot;
+use chrono::{DateTime, Duration, Utc};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use uuid::Uuid;
+
+/// One coin, in satoshis
+pub const COIN: u64 = 10u64.pow(8);
+/// Spendable coins each genesis validator starts with
+pub const ALLOCATION: u64 = 10_000 * COIN;
+/// Height until which the genesis stakes are locked
+pub const STAKE_LOCKED_UNTIL: u64 = 1000;
+
+/// `count` keys, the same ones for the same `seed`
+pub fn keys(seed: u64, count: usize) -> Vec<PrivateKey> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count).map(|_| PrivateKey::from_rng(&mut rng)).collect()
+}
+
+pub fn output(value: u64, pubkey: &PublicKey) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: Uuid::new_v4(),
+        pubkey: pubkey.clone(),
+        is_stake: false,
+        locked_until: 0,
+        delegate_to: None,
+    }
+}
+
+/// Sign `header` with `key` into a block holding `transactions`
+pub fn seal(header: BlockHeader, transactions: Vec<Transaction>, key: &PrivateKey) -> Block {
+    let signature = Signature::sign_output(&header.hash(), key);
+    Block::new(header, transactions, signature)
+}
+
+/// Final transaction spending the outputs `spent` (hash and value) of
+/// `owner`: `value` goes to `to`, the change back to `owner`, `fee` is left
+pub fn spend(
+    owner: &PrivateKey,
+    spent: &[(Hash, u64)],
+    to: &PublicKey,
+    value: u64,
+    fee: u64,
+) -> Transaction {
+    spend_with_sequence(
+        owner,
+        spent,
+        TransactionInput::SEQUENCE_FINAL,
+        to,
+        value,
+        fee,
+    )
+}
+
+/// `spend` with every input carrying `sequence`, replaceable unless final
+pub fn spend_with_sequence(
+    owner: &PrivateKey,
+    spent: &[(Hash, u64)],
+    sequence: u32,
+    to: &PublicKey,
+    value: u64,
+    fee: u64,
+) -> Transaction {
+    let inputs = spent
+        .iter()
+        .map(|(hash, _)| TransactionInput::new(*hash, sequence, owner))
+        .collect();
+    let total: u64 = spent.iter().map(|(_, value)| value).sum();
+    let mut outputs = vec![output(value, to)];
+    if total > value + fee {
+        outputs.push(output(total - value - fee, &owner.public_key()));
+    }
+    Transaction::new(inputs, outputs)
+}
+
+/// A chain and the keys of its validators
+#[derive(Clone)]
+pub struct TestChain {
+    pub chain: Blockchain,
+    pub validators: Vec<PrivateKey>,
+}
+
+impl TestChain {
+    /// Genesis giving every validator `ALLOCATION` spendable coins and one
+    /// of `stakes` locked until `STAKE_LOCKED_UNTIL`
+    pub fn new(stakes: &[u64]) -> Self {
+        Self::with_params(stakes, ChainParams::default())
+    }
+
+    pub fn with_params(stakes: &[u64], params: ChainParams) -> Self {
+        let validators = keys(stakes.len() as u64, stakes.len());
+        let mut outputs = vec![];
+        for (key, stake) in validators.iter().zip(stakes) {
+            outputs.push(output(ALLOCATION, &key.public_key()));
+            outputs.push(TransactionOutput {
+                is_stake: true,
+                locked_until: STAKE_LOCKED_UNTIL,
+                ..output(*stake, &key.public_key())
+            });
+        }
+        // old enough that thousands of blocks still end in the past
+        let timestamp = Utc::now() - Duration::days(1);
+        let transactions = vec![Transaction::new(vec![], outputs)];
+        let header = BlockHeader::new(
+            timestamp,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            validators[0].public_key(),
+        );
+        let genesis = seal(header, transactions, &validators[0]);
+        let mut chain = Blockchain::with_params(params);
+        chain.add_block(genesis).expect("genesis is valid");
+        TestChain { chain, validators }
+    }
+
+    pub fn tip_timestamp(&self) -> DateTime<Utc> {
+        self.chain.blocks().last().unwrap().header.timestamp
+    }
+
+    /// Earliest timestamp a block after the tip may carry
+    pub fn next_timestamp(&self) -> DateTime<Utc> {
+        self.tip_timestamp()
+            + Duration::seconds(self.chain.params().consensus.min_block_interval as i64)
+    }
+
+    pub fn key_of(&self, pubkey: &PublicKey) -> &PrivateKey {
+        self.validators
+            .iter()
+            .find(|key| key.public_key() == *pubkey)
+            .expect("not one of our validators")
+    }
+
+    /// Valid block after the tip holding `transactions` and the pending
+    /// double-sign evidence, produced at `timestamp` by whoever the slot
+    /// belongs to then
+    pub fn block_at(&self, timestamp: DateTime<Utc>, transactions: Vec<Transaction>) -> Block {
+        let producer = self
+            .chain
+            .slot_validator(timestamp)
+            .expect("someone has stake");
+        let key = self.key_of(&producer);
+        let fees: u64 = transactions
+            .iter()
+            .map(|tx| tx.fee(self.chain.utxos()).expect("transaction is valid"))
+            .sum();
+        let coinbase = Transaction::new(
+            vec![],
+            vec![output(
+                self.chain.calculate_block_reward() + fees,
+                &producer,
+            )],
+        );
+        let mut all = vec![coinbase];
+        all.extend(transactions);
+        let mut header = BlockHeader::new(
+            timestamp,
+            self.chain.blocks().last().unwrap().id(),
+            MerkleRoot::calculate(&all),
+            producer,
+        );
+        header.evidence = self.chain.pending_evidence();
+        header.set_randomness(&self.chain.randomness(), key);
+        seal(header, all, key)
+    }
+
+    /// Valid block after the tip holding `transactions`, in the first slot
+    pub fn next_block(&self, transactions: Vec<Transaction>) -> Block {
+        self.block_at(self.next_timestamp(), transactions)
+    }
+
+    /// Add a block holding `transactions` and return it
+    pub fn extend(&mut self, transactions: Vec<Transaction>) -> Block {
+        let block = self.next_block(transactions);
+        self.chain
+            .add_block(block.clone())
+            .expect("fixture block is valid");
+        block
+    }
+
+    /// The genesis allocation of validator `index`
+    pub fn allocation_of(&self, index: usize) -> (Hash, u64) {
+        let pubkey = self.validators[index].public_key();
+        self.chain.blocks().next().unwrap().transactions[0]
+            .outputs
+            .iter()
+            .find(|output| output.pubkey == pubkey && !output.is_stake)
+            .map(|output| (output.hash(), output.value))
+            .unwrap()
+    }
+}
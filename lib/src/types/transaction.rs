@@ -1,7 +1,9 @@
-use crate::crypto::{PublicKey, Signature};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::error::{EthError, Result, TxRejectReason};
 use crate::sha256::Hash;
-use crate::util::Saveable;
+use crate::util::{Saveable, sum_amounts};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 use uuid::Uuid;
 
@@ -24,20 +26,217 @@ impl Saveable for Transaction {
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Last block height this transaction may be included at (None = never
+    /// expires). Covered by the input signatures. Left out of the encoding
+    /// when unset so older transactions keep their hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<u64>,
+    /// Free-form data for the recipient (an invoice id, a note), at most
+    /// `MAX_MEMO_SIZE` bytes. It is not an output, so it never becomes a
+    /// UTXO. Covered by the input signatures. Left out of the encoding when
+    /// unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<Vec<u8>>,
 }
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Transaction { inputs, outputs }
+        Transaction {
+            inputs,
+            outputs,
+            valid_until: None,
+            memo: None,
+        }
+    }
+    /// Only valid up to and including block `height`. The inputs must be
+    /// signed for it, see `TransactionInput::signed_for`.
+    pub fn with_valid_until(mut self, height: Option<u64>) -> Self {
+        self.valid_until = height;
+        self
+    }
+    /// Attach a memo, see `has_valid_memo` for the size limit. The inputs
+    /// must be signed for it, see `TransactionInput::signed_for`.
+    pub fn with_memo(mut self, memo: Option<Vec<u8>>) -> Self {
+        self.memo = memo;
+        self
+    }
+    /// Whether the memo, if any, fits in `MAX_MEMO_SIZE`
+    pub fn has_valid_memo(&self) -> bool {
+        self.memo
+            .as_ref()
+            .is_none_or(|memo| memo.len() <= crate::consensus::MAX_MEMO_SIZE)
+    }
+    /// Whether the transaction can no longer be included at `height`
+    pub fn is_expired(&self, height: u64) -> bool {
+        self.valid_until
+            .is_some_and(|valid_until| valid_until < height)
     }
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    /// Whether a mempool transaction paying a higher fee may take its
+    /// place, signaled by any input with a non-final sequence
+    pub fn is_replaceable(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|input| input.sequence != TransactionInput::SEQUENCE_FINAL)
+    }
+    /// A coinbase creates coins out of nothing, so it has no inputs
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+    /// Structural checks that need no chain state, so a wallet can catch a
+    /// malformed transaction before sending it: some outputs, none of them
+    /// worthless or repeated, no input spent twice, a memo within
+    /// `MAX_MEMO_SIZE`, and inputs at all since only blocks may carry a
+    /// coinbase.
+    pub fn validate_standalone(&self) -> Result<()> {
+        if self.is_coinbase() {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::UnexpectedCoinbase,
+            });
+        }
+        if self.outputs.is_empty() {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::NoOutputs,
+            });
+        }
+        if self.outputs.iter().any(|output| output.value == 0) {
+            return Err(EthError::InvalidTransactionOutput);
+        }
+        // equal outputs share a hash and would become a single UTXO
+        let mut hashes = HashSet::new();
+        if let Some(repeated) = self
+            .outputs
+            .iter()
+            .find(|output| !hashes.insert(output.hash()))
+        {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::DuplicateOutput(repeated.hash()),
+            });
+        }
+        let mut spent = HashSet::new();
+        if !self
+            .inputs
+            .iter()
+            .all(|input| spent.insert(input.prev_transaction_output_hash))
+        {
+            return Err(EthError::InvalidTransactionInput);
+        }
+        if !self.has_valid_memo() {
+            return Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::MemoTooLong,
+            });
+        }
+        Ok(())
+    }
+    /// Input value minus output value. Fails with `InvalidTransaction` if an
+    /// input spends an unknown UTXO, the outputs exceed the inputs, or a
+    /// sum overflows, the reason telling which.
+    pub fn fee(&self, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<u64> {
+        let input_values = self
+            .inputs
+            .iter()
+            .map(|input| {
+                utxos
+                    .get(&input.prev_transaction_output_hash)
+                    .map(|(_, output)| output.value)
+                    .ok_or(EthError::InvalidTransaction {
+                        reason: TxRejectReason::MissingUtxo(input.prev_transaction_output_hash),
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let overflow = |_| EthError::InvalidTransaction {
+            reason: TxRejectReason::AmountOverflow,
+        };
+        let input_value = sum_amounts(input_values).map_err(overflow)?;
+        let output_value =
+            sum_amounts(self.outputs.iter().map(|output| output.value)).map_err(overflow)?;
+        input_value
+            .checked_sub(output_value)
+            .ok_or(EthError::InvalidTransaction {
+                reason: TxRejectReason::Overspend {
+                    inputs: input_value,
+                    outputs: output_value,
+                },
+            })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
     pub prev_transaction_output_hash: Hash,
     pub signature: Signature,
+    /// Anything below `SEQUENCE_FINAL` signals that the transaction may be
+    /// replaced in the mempool. Left out of the encoding when final so
+    /// older transactions keep their hash.
+    #[serde(
+        default = "TransactionInput::final_sequence",
+        skip_serializing_if = "TransactionInput::is_final_sequence"
+    )]
+    pub sequence: u32,
+}
+impl TransactionInput {
+    pub const SEQUENCE_FINAL: u32 = u32::MAX;
+    /// Spend the output with hash `output_hash`, signed by its owner, in a
+    /// transaction without expiry or memo
+    pub fn new(output_hash: Hash, sequence: u32, private_key: &PrivateKey) -> Self {
+        Self::signed_for(output_hash, sequence, None, None, private_key)
+    }
+    /// Spend the output with hash `output_hash`, signed by its owner, in a
+    /// transaction with this `valid_until` and `memo`
+    pub fn signed_for(
+        output_hash: Hash,
+        sequence: u32,
+        valid_until: Option<u64>,
+        memo: Option<&[u8]>,
+        private_key: &PrivateKey,
+    ) -> Self {
+        TransactionInput {
+            prev_transaction_output_hash: output_hash,
+            signature: Signature::sign_output(
+                &Self::signing_hash(&output_hash, sequence, valid_until, memo),
+                private_key,
+            ),
+            sequence,
+        }
+    }
+    /// What the owner signs: the spent output's hash, bound to the
+    /// sequence when it isn't final and to the transaction's expiry and
+    /// memo when it has them, so none of them can be changed afterwards.
+    /// Inputs without any sign what they did before these existed.
+    pub fn signing_hash(
+        output_hash: &Hash,
+        sequence: u32,
+        valid_until: Option<u64>,
+        memo: Option<&[u8]>,
+    ) -> Hash {
+        if valid_until.is_some() || memo.is_some() {
+            Hash::hash(&(output_hash, sequence, valid_until, memo))
+        } else if Self::is_final_sequence(&sequence) {
+            *output_hash
+        } else {
+            Hash::hash(&(output_hash, sequence))
+        }
+    }
+    /// Whether the input is signed by `public_key` for `transaction`,
+    /// sequence, expiry and memo included
+    pub fn verify_signature(&self, public_key: &PublicKey, transaction: &Transaction) -> bool {
+        self.signature.verify(
+            &Self::signing_hash(
+                &self.prev_transaction_output_hash,
+                self.sequence,
+                transaction.valid_until,
+                transaction.memo.as_deref(),
+            ),
+            public_key,
+        )
+    }
+    fn final_sequence() -> u32 {
+        Self::SEQUENCE_FINAL
+    }
+    fn is_final_sequence(sequence: &u32) -> bool {
+        *sequence == Self::SEQUENCE_FINAL
+    }
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
@@ -49,14 +248,90 @@ pub struct TransactionOutput {
     /// Block height until which staked funds are locked (0 = not locked)
     #[serde(default)]
     pub locked_until: u64,
+    /// Validator a stake output counts towards, if not the owner itself.
+    /// The coins stay spendable by `pubkey` only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delegate_to: Option<PublicKey>,
 }
 impl TransactionOutput {
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
-    
+
+    /// Validator whose weight this stake output adds to
+    pub fn staked_for(&self) -> &PublicKey {
+        self.delegate_to.as_ref().unwrap_or(&self.pubkey)
+    }
+
     /// Check if this output is currently locked at the given block height
     pub fn is_locked(&self, current_block_height: u64) -> bool {
         self.is_stake && self.locked_until > current_block_height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::{keys, output};
+
+    fn is_rejected_for(transaction: &Transaction, expected: TxRejectReason) -> bool {
+        matches!(
+            transaction.validate_standalone(),
+            Err(EthError::InvalidTransaction { reason }) if reason == expected
+        )
+    }
+
+    #[test]
+    fn standalone_checks_need_no_chain() {
+        let owner = keys(0, 1).remove(0);
+        let pubkey = owner.public_key();
+        let spent = Hash::hash(&"spent output");
+        let input = || TransactionInput::new(spent, TransactionInput::SEQUENCE_FINAL, &owner);
+
+        let valid = Transaction::new(vec![input()], vec![output(10, &pubkey)]);
+        assert!(valid.validate_standalone().is_ok());
+        let coinbase = Transaction::new(vec![], vec![output(10, &pubkey)]);
+        assert!(is_rejected_for(
+            &coinbase,
+            TxRejectReason::UnexpectedCoinbase
+        ));
+        let no_outputs = Transaction::new(vec![input()], vec![]);
+        assert!(is_rejected_for(&no_outputs, TxRejectReason::NoOutputs));
+        let long_memo = valid
+            .clone()
+            .with_memo(Some(vec![0; crate::consensus::MAX_MEMO_SIZE + 1]));
+        assert!(is_rejected_for(&long_memo, TxRejectReason::MemoTooLong));
+
+        let worthless = Transaction::new(vec![input()], vec![output(0, &pubkey)]);
+        assert!(matches!(
+            worthless.validate_standalone(),
+            Err(EthError::InvalidTransactionOutput)
+        ));
+        let spent_twice = Transaction::new(vec![input(), input()], vec![output(10, &pubkey)]);
+        assert!(matches!(
+            spent_twice.validate_standalone(),
+            Err(EthError::InvalidTransactionInput)
+        ));
+    }
+
+    #[test]
+    fn repeated_output_is_rejected() {
+        let owner = keys(798, 1).remove(0);
+        let spent = Hash::hash(&"spent output");
+        let input = TransactionInput::new(spent, TransactionInput::SEQUENCE_FINAL, &owner);
+        let paid = output(10, &owner.public_key());
+
+        let repeated = Transaction::new(vec![input.clone()], vec![paid.clone(), paid.clone()]);
+        assert!(is_rejected_for(
+            &repeated,
+            TxRejectReason::DuplicateOutput(paid.hash())
+        ));
+        // sharing the unique_id only collapses outputs that are otherwise equal
+        let same_id = TransactionOutput {
+            value: 20,
+            ..paid.clone()
+        };
+        let distinct = Transaction::new(vec![input], vec![paid, same_id]);
+        assert!(distinct.validate_standalone().is_ok());
+    }
+}
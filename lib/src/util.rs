@@ -1,5 +1,7 @@
+use crate::error::{EthError, Result};
 use crate::sha256::Hash;
 use crate::types::Transaction;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
@@ -21,23 +23,173 @@ impl MerkleRoot {
         }
         MerkleRoot(layer[0])
     }
+
+    /// Sibling hashes from the transaction at `index` up to the root.
+    /// The flag is `true` when the sibling sits on the left.
+    /// Together with a block header this proves a transaction is included
+    /// without downloading the block.
+    pub fn proof(transactions: &[Transaction], index: usize) -> Vec<(Hash, bool)> {
+        let mut layer: Vec<Hash> = transactions.iter().map(Hash::hash).collect();
+        let mut index = index;
+        let mut proof = vec![];
+        if index >= layer.len() {
+            return proof;
+        }
+        while layer.len() > 1 {
+            // an odd node out is paired with itself, as in `calculate`
+            let sibling = if index.is_multiple_of(2) {
+                (*layer.get(index + 1).unwrap_or(&layer[index]), false)
+            } else {
+                (layer[index - 1], true)
+            };
+            proof.push(sibling);
+            layer = layer
+                .chunks(2)
+                .map(|pair| Hash::hash(&[pair[0], *pair.get(1).unwrap_or(&pair[0])]))
+                .collect();
+            index /= 2;
+        }
+        proof
+    }
+
+    /// Check that `tx_hash` hashes up to `root` along `proof`
+    pub fn verify_proof(tx_hash: &Hash, proof: &[(Hash, bool)], root: &MerkleRoot) -> bool {
+        let computed = proof.iter().fold(*tx_hash, |current, (sibling, is_left)| {
+            if *is_left {
+                Hash::hash(&[*sibling, current])
+            } else {
+                Hash::hash(&[current, *sibling])
+            }
+        });
+        computed == root.0
+    }
 }
 
+/// Sum satoshi amounts, failing instead of wrapping on overflow
+pub fn sum_amounts<I: IntoIterator<Item = u64>>(values: I) -> Result<u64> {
+    values
+        .into_iter()
+        .try_fold(0u64, |sum, value| sum.checked_add(value))
+        .ok_or(EthError::AmountOverflow)
+}
+
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Result as IoResult, Write};
-use std::path::Path;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+
+/// Write `path` through `write` without ever leaving it half-written: the
+/// data goes to `<path>.tmp` first, which then replaces `path` in a single
+/// rename. A crash before the rename leaves the previous file untouched.
+pub fn write_atomically<P, F>(path: P, write: F) -> IoResult<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut File) -> IoResult<()>,
+{
+    let path = path.as_ref();
+    let mut temp_name = OsString::from(path.as_os_str());
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    let mut file = File::create(&temp_path)?;
+    write(&mut file)?;
+    // the data must be on disk before the rename makes it the real file
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)
+}
 pub trait Saveable
 where
     Self: Sized,
 {
     fn load<I: Read>(reader: I) -> IoResult<Self>;
     fn save<O: Write>(&self, writer: O) -> IoResult<()>;
+    /// Replaces the file atomically, see `write_atomically`
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        let file = File::create(&path)?;
-        self.save(file)
+        write_atomically(path, |file| self.save(file))
     }
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
         let file = File::open(&path)?;
         Self::load(file)
     }
+    /// Pretty-printed JSON, for inspecting and hand-editing fixtures.
+    /// Types with non-string map keys (e.g. `Blockchain`) can't be written
+    /// this way; `save` stays the on-disk format.
+    fn save_json<O: Write>(&self, writer: O) -> IoResult<()>
+    where
+        Self: Serialize,
+    {
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+    }
+    fn load_json<I: Read>(reader: I) -> IoResult<Self>
+    where
+        Self: DeserializeOwned,
+    {
+        serde_json::from_reader(reader).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+    }
+    fn save_json_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()>
+    where
+        Self: Serialize,
+    {
+        let file = File::create(&path)?;
+        self.save_json(file)
+    }
+    fn load_json_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self>
+    where
+        Self: DeserializeOwned,
+    {
+        let file = File::open(&path)?;
+        Self::load_json(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::{keys, output};
+
+    #[test]
+    fn failed_write_leaves_the_old_file() {
+        let dir = std::env::temp_dir().join(format!("atomic-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chain.cbor");
+        write_atomically(&path, |file| file.write_all(b"old")).unwrap();
+
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"half of the new")?;
+            Err(IoError::other("disk full"))
+        });
+        let contents = std::fs::read(&path).unwrap();
+        write_atomically(&path, |file| file.write_all(b"new")).unwrap();
+        let replaced = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failed.is_err());
+        assert_eq!(contents, b"old");
+        assert_eq!(replaced, b"new");
+    }
+
+    #[test]
+    fn merkle_proofs_verify_against_the_root() {
+        let pubkey = keys(761, 1)[0].public_key();
+        // odd counts pair the last transaction with itself
+        for count in [1, 2, 3, 5, 8] {
+            let transactions: Vec<_> = (1..=count)
+                .map(|value| Transaction::new(vec![], vec![output(value, &pubkey)]))
+                .collect();
+            let root = MerkleRoot::calculate(&transactions);
+            for (index, transaction) in transactions.iter().enumerate() {
+                let proof = MerkleRoot::proof(&transactions, index);
+                assert!(
+                    MerkleRoot::verify_proof(&transaction.hash(), &proof, &root),
+                    "transaction {index} of {count}"
+                );
+                // the proof is for this transaction only
+                let other = &transactions[(index + 1) % transactions.len()];
+                if other.hash() != transaction.hash() {
+                    assert!(!MerkleRoot::verify_proof(&other.hash(), &proof, &root));
+                }
+            }
+            assert!(MerkleRoot::proof(&transactions, transactions.len()).is_empty());
+        }
+    }
 }
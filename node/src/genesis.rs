@@ -0,0 +1,155 @@
+//! Genesis block configuration
+//!
+//! Describes who holds coins and stake when a new network starts. Key
+//! paths are relative to the node's data directory. Without a config file
+//! the network starts with the development validators Alice and Bob.
+
+use anyhow::{Context, Result, bail};
+use poslib::params::{ChainParams, SelectionMode};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GenesisConfig {
+    /// Private key signing the genesis block; its public key is recorded
+    /// as the genesis validator
+    pub signer_private_key: String,
+    pub signer_public_key: String,
+    pub validators: Vec<GenesisValidator>,
+    /// Stake slashed for double signing, in basis points (defaults to
+    /// `SLASHING_PENALTY_DOUBLE_SIGN`)
+    #[serde(default)]
+    pub slashing_penalty_double_sign: Option<u64>,
+    /// Stake slashed for downtime, in basis points (defaults to
+    /// `SLASHING_PENALTY_DOWNTIME`)
+    #[serde(default)]
+    pub slashing_penalty_downtime: Option<u64>,
+    /// How slot validators are picked (defaults to stake-weighted)
+    #[serde(default)]
+    pub selection_mode: SelectionMode,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GenesisValidator {
+    pub public_key: String,
+    /// Spendable coins, in satoshis
+    pub allocation: u64,
+    /// Staked coins, in satoshis
+    pub stake: u64,
+    /// Block height until which the stake is locked
+    pub locked_until: u64,
+}
+
+impl GenesisConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read genesis config {}", path.display()))?;
+        let config: GenesisConfig = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse genesis config {}", path.display()))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Reject penalties over 100% and a genesis block issuing more than
+    /// the supply cap, which would leave nothing for block subsidies
+    fn check(&self) -> Result<()> {
+        for penalty in [
+            self.slashing_penalty_double_sign,
+            self.slashing_penalty_downtime,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if penalty > 10000 {
+                bail!("slashing penalty of {} basis points is over 100%", penalty);
+            }
+        }
+        let cap = self.chain_params().economics.total_supply_cap;
+        let total = self.validators.iter().try_fold(0u64, |total, validator| {
+            total
+                .checked_add(validator.allocation)?
+                .checked_add(validator.stake)
+        });
+        if total.is_none_or(|total| total > cap) {
+            bail!(
+                "genesis allocations and stakes exceed the supply cap of {} satoshis",
+                cap
+            );
+        }
+        Ok(())
+    }
+
+    /// Chain parameters for the new network, defaults with the configured
+    /// slashing penalties and selection mode applied
+    pub fn chain_params(&self) -> ChainParams {
+        let mut params = ChainParams::default();
+        params.consensus.selection_mode = self.selection_mode;
+        if let Some(penalty) = self.slashing_penalty_double_sign {
+            params.economics.slashing_penalty_double_sign = penalty;
+        }
+        if let Some(penalty) = self.slashing_penalty_downtime {
+            params.economics.slashing_penalty_downtime = penalty;
+        }
+        params
+    }
+}
+
+/// Alice and Bob share half the supply, each staking the minimum for 100
+/// blocks. The rest stays unissued so block subsidies can be paid.
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        let validators = ["validator/alice.pub.pem", "validator/bob.pub.pem"];
+        let allocation = poslib::economics::TOTAL_SUPPLY_CAP / 2 / validators.len() as u64;
+        GenesisConfig {
+            signer_private_key: "validator/alice.priv.cbor".to_string(),
+            signer_public_key: "validator/alice.pub.pem".to_string(),
+            validators: validators
+                .into_iter()
+                .map(|public_key| GenesisValidator {
+                    public_key: public_key.to_string(),
+                    allocation,
+                    stake: poslib::consensus::STAKE_MINIMUM_AMOUNT,
+                    locked_until: 100,
+                })
+                .collect(),
+            slashing_penalty_double_sign: None,
+            slashing_penalty_downtime: None,
+            selection_mode: SelectionMode::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_leaves_room_for_subsidies() {
+        let config = GenesisConfig::default();
+        config.check().unwrap();
+        let issued: u64 = config
+            .validators
+            .iter()
+            .map(|validator| validator.allocation + validator.stake)
+            .sum();
+        assert!(issued < poslib::economics::TOTAL_SUPPLY_CAP);
+    }
+
+    #[test]
+    fn over_the_cap_is_rejected() {
+        let mut config = GenesisConfig::default();
+        config.validators[0].allocation = poslib::economics::TOTAL_SUPPLY_CAP;
+        assert!(config.check().is_err());
+        config.validators[0].allocation = u64::MAX;
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn penalty_over_100_percent_is_rejected() {
+        let config = GenesisConfig {
+            slashing_penalty_downtime: Some(10001),
+            ..GenesisConfig::default()
+        };
+        assert!(config.check().is_err());
+    }
+}
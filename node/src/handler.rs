@@ -1,12 +1,20 @@
-use chrono::Utc;
-use poslib::crypto::{PublicKey, Signature};
-use poslib::network::Message;
+use crate::peer::Peer;
+use crate::rate_limit::{MAX_DROPPED_TRANSACTIONS, TokenBucket};
+use poslib::crypto::Signature;
+use poslib::network::{MAX_CHAIN_SEGMENT, MAX_UTXO_PAGE, Message, PROTOCOL_VERSION};
 use poslib::sha256::Hash;
-use poslib::types::{Block, BlockHeader, Transaction, TransactionOutput};
-use poslib::util::MerkleRoot;
+use poslib::types::{Block, DoubleSignEvidence, MempoolOutcome, Transaction};
+use poslib::util::Saveable;
 use tokio::net::TcpStream;
-use uuid::Uuid;
+use tracing::{debug, info, warn};
 pub async fn handle_connection(mut socket: TcpStream) {
+    // The NODES key of the peer on the other end, once it has told us its
+    // listening port. Used to avoid relaying gossip straight back to it.
+    let mut origin: Option<String> = None;
+    let mut tx_bucket = TokenBucket::new(crate::rate_limit::tx_rate_limit());
+    let mut dropped_in_a_row = 0u32;
+    // every connection starts with a Version exchange
+    let mut handshaken = false;
     loop {
         // read a message from the socket
         let message = match Message::receive_async(&mut socket).await {
@@ -18,52 +26,142 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     // Normal disconnect - peer closed the connection
                     return;
                 }
-                println!("invalid message from peer: {e}, closing that connection");
+                warn!("invalid message from peer: {e}, closing that connection");
                 return;
             }
         };
 
         use poslib::network::Message::*;
+        if !handshaken {
+            let Version {
+                protocol_version,
+                genesis_hash,
+            } = message
+            else {
+                warn!("peer skipped the version handshake, closing connection");
+                return;
+            };
+            let our_genesis = crate::BLOCKCHAIN.read().await.genesis_hash();
+            if let Err(reason) = Message::check_version(protocol_version, genesis_hash, our_genesis)
+            {
+                warn!("incompatible peer: {}, closing connection", reason);
+                return;
+            }
+            let message = Version {
+                protocol_version: PROTOCOL_VERSION,
+                genesis_hash: our_genesis,
+            };
+            if message.send_async(&mut socket).await.is_err() {
+                return;
+            }
+            handshaken = true;
+            continue;
+        }
         match message {
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_)
-            | BlockHeight(_) | NextValidator(_) => {
-                println!("I am neither a validator nor a wallet! Goodbye peer 💅");
+            Version { .. } => {
+                warn!("repeated version handshake, closing connection");
+                return;
+            }
+            UTXOs { .. }
+            | Template(_)
+            | Difference(_)
+            | TemplateValidity(_)
+            | NodeList { .. }
+            | BlockHeight(_)
+            | NextValidator(_)
+            | SupplyInfo { .. }
+            | StakeParams { .. }
+            | FeeEstimate { .. }
+            | TransactionFound(_)
+            | Confirmations(_)
+            | Stake(_)
+            | Snapshot(_)
+            | SlashingHistory(_)
+            | ValidatorStats(_)
+            | Balance { .. }
+            | ChallengeResponse { .. }
+            | ChainSegment(_)
+            | BlockNotFound(_)
+            | Mempool(_)
+            | Pong => {
+                info!("I am neither a validator nor a wallet! Goodbye peer 💅");
                 return;
             }
             FetchBlock(height) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-                let Some(block) = blockchain.blocks().nth(height as usize).cloned() else {
-                    return;
+                // header-only snapshot blocks would be rejected anyway
+                let block = if (height as u64) < blockchain.pruned_height() {
+                    None
+                } else {
+                    blockchain.blocks().nth(height).cloned()
+                };
+                let message = match block {
+                    Some(block) => NewBlock(block),
+                    None => BlockNotFound(height),
+                };
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchChain { from, to } => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let count = to.saturating_sub(from).min(MAX_CHAIN_SEGMENT);
+                // an empty segment sends the peer to someone with full blocks
+                let count = if from < blockchain.pruned_height() {
+                    0
+                } else {
+                    count
                 };
-                let message = NewBlock(block);
+                let blocks = blockchain
+                    .blocks()
+                    .skip(from as usize)
+                    .take(count as usize)
+                    .cloned()
+                    .collect();
+                let message = ChainSegment(blocks);
                 message.send_async(&mut socket).await.unwrap();
             }
 
-            DiscoverNodes(sender_port) => {
+            DiscoverNodes {
+                port: sender_port,
+                node_id,
+            } => {
                 // Get the peer's IP address from the socket
                 let peer_addr = match socket.peer_addr() {
                     Ok(addr) => addr,
                     Err(_) => {
-                        println!("❌ Could not get peer address");
+                        warn!("❌ Could not get peer address");
                         continue;
                     }
                 };
-                let peer_ip = peer_addr.ip();
-                let peer_connect_addr = format!("{}:{}", peer_ip, sender_port);
+                // SocketAddr formatting brackets IPv6 addresses
+                let peer_connect_addr =
+                    std::net::SocketAddr::new(peer_addr.ip(), sender_port).to_string();
+                origin = Some(peer_connect_addr.clone());
 
-                // Add the peer to our node list if not already present
-                if !crate::NODES.contains_key(&peer_connect_addr) {
-                    println!(
+                // Add the peer to our node list unless it is us, or a node
+                // we already know (possibly under another address)
+                if node_id == *crate::NODE_ID {
+                    debug!("🪞 {} is ourselves, not connecting back", peer_connect_addr);
+                } else if crate::NODES.contains_key(&peer_connect_addr)
+                    || crate::util::is_known_node(node_id)
+                {
+                    debug!("🤝 {} is already a peer", peer_connect_addr);
+                } else {
+                    info!(
                         "🤝 New peer discovered: {}, connecting back...",
                         peer_connect_addr
                     );
-                    match tokio::net::TcpStream::connect(&peer_connect_addr).await {
-                        Ok(new_stream) => {
-                            crate::NODES.insert(peer_connect_addr.clone(), new_stream);
-                            println!("✅ Connected back to peer: {}", peer_connect_addr);
-                        }
+                    match Peer::connect_with_id(&peer_connect_addr, node_id).await {
+                        Ok(mut peer) => match peer.verify_identity().await {
+                            Ok(()) => {
+                                crate::NODES.insert(peer_connect_addr.clone(), peer);
+                                info!("✅ Connected back to peer: {}", peer_connect_addr);
+                            }
+                            Err(e) => {
+                                warn!("❌ Not adding {}: {}", peer_connect_addr, e);
+                            }
+                        },
                         Err(e) => {
-                            println!("❌ Failed to connect back to {}: {}", peer_connect_addr, e);
+                            warn!("❌ Failed to connect back to {}: {}", peer_connect_addr, e);
                         }
                     }
                 }
@@ -72,8 +170,18 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .iter()
                     .map(|x| x.key().clone())
                     .collect::<Vec<_>>();
-                let message = NodeList(nodes);
-                println!("👐 sending node list to peer");
+                let message = NodeList {
+                    node_id: *crate::NODE_ID,
+                    nodes,
+                };
+                debug!("👐 sending node list to peer");
+                message.send_async(&mut socket).await.unwrap();
+            }
+            Challenge(nonce) => {
+                let message = ChallengeResponse {
+                    public_key: crate::peer::node_key().public_key(),
+                    signature: Signature::sign_output(&nonce, crate::peer::node_key()),
+                };
                 message.send_async(&mut socket).await.unwrap();
             }
             AskDifference(height) => {
@@ -88,35 +196,159 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 let message = BlockHeight(height);
                 message.send_async(&mut socket).await.unwrap();
             }
-            FetchUTXOs(key) => {
-                println!("received request to fetch UTXOs");
+            GetMempool => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-                let utxos = blockchain
-                    .utxos()
+                let transactions = blockchain
+                    .mempool()
                     .iter()
-                    .filter(|(_, (_, txout))| txout.pubkey == key)
+                    .map(|(_, tx)| tx.clone())
+                    .collect();
+                let message = Mempool(transactions);
+                message.send_async(&mut socket).await.unwrap();
+            }
+            Ping => {
+                if Pong.send_async(&mut socket).await.is_err() {
+                    return;
+                }
+            }
+            FetchSupplyInfo => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = SupplyInfo {
+                    issued: blockchain.total_issued(),
+                    remaining: blockchain.remaining_supply(),
+                };
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchStakeParams => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let consensus = &blockchain.params().consensus;
+                let message = StakeParams {
+                    min_stake: consensus.stake_minimum_amount,
+                    lock_period: consensus.stake_lock_period,
+                };
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchFeeEstimate => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let (min, median, max) = blockchain.fee_estimate();
+                let message = FeeEstimate { min, median, max };
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchTransaction(hash) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = TransactionFound(blockchain.find_transaction(&hash));
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchConfirmations(hash) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = Confirmations(blockchain.confirmations(&hash));
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchStake(key) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = Stake(blockchain.get_effective_stake(&key));
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchBalance(key) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let (spendable, active_stake, unlocked_stake) =
+                    blockchain.balance_for(&key, blockchain.block_height());
+                let message = Balance {
+                    spendable,
+                    active_stake,
+                    unlocked_stake,
+                };
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchSlashingHistory => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = SlashingHistory(blockchain.slashing_history().to_vec());
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchValidatorStats => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = ValidatorStats(blockchain.validator_stats());
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchSnapshot => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = Snapshot(blockchain.export_snapshot());
+                message.send_async(&mut socket).await.unwrap();
+            }
+            FetchUTXOs { key, offset, limit } => {
+                debug!("received request to fetch UTXOs");
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let limit = limit.min(MAX_UTXO_PAGE) as usize;
+                let mut page = blockchain.iter_utxos_for(&key).skip(offset as usize);
+                let utxos = page
+                    .by_ref()
+                    .take(limit)
                     .map(|(_, (marked, txout))| (txout.clone(), *marked))
                     .collect::<Vec<_>>();
-                let message = UTXOs(utxos);
+                let has_more = page.next().is_some();
+                let message = UTXOs { utxos, has_more };
                 message.send_async(&mut socket).await.unwrap();
             }
             NewBlock(block) => {
+                let id = block.id();
+                if crate::seen::is_seen(&id) {
+                    // already processed and relayed this one
+                    continue;
+                }
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-                println!("█ Received new block");
-                if blockchain.add_block(block).is_err() {
-                    println!("New block rejected");
+                info!("█ Received new block");
+                if blockchain.add_block(block.clone()).is_err() {
+                    // not marked seen: a block rejected for now, e.g. dated
+                    // ahead of our clock, is taken again when relayed
+                    crate::metrics::block_rejected();
+                    warn!("New block rejected");
+                } else if !crate::seen::mark_seen(id) {
+                    // another connection delivered it first and relayed it
+                    continue;
                 } else {
-                    // Rebuild UTXOs after accepting a new block
-                    blockchain.rebuild_utxos();
-                    println!("Block accepted, UTXOs rebuilt");
+                    info!("Block accepted");
+                    // a block off our tip is stashed, it may complete a
+                    // longer branch or come from a peer we fell behind
+                    if !blockchain.contains_block(&id)
+                        && !crate::util::adopt_orphan_branch(&mut blockchain, &id)
+                        && !blockchain.contains_block(&block.header.prev_block_hash)
+                        && let Some(peer) = origin.clone()
+                    {
+                        tokio::spawn(async move {
+                            if let Err(e) = crate::util::sync_with_peer(peer.clone()).await {
+                                warn!("failed to sync with {}: {}", peer, e);
+                            }
+                        });
+                    }
+                    drop(blockchain);
+                    relay_block(&block, origin.as_deref()).await;
                 }
             }
             NewTransaction(tx) => {
+                if !admit_transaction(&mut tx_bucket, &mut dropped_in_a_row) {
+                    if dropped_in_a_row >= MAX_DROPPED_TRANSACTIONS {
+                        warn!("peer keeps exceeding the transaction rate, closing connection");
+                        return;
+                    }
+                    continue;
+                }
+                if !crate::seen::mark_seen(tx.hash()) {
+                    continue;
+                }
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-                println!("received transaction from friend");
-                if blockchain.add_to_mempool(tx).is_err() {
-                    println!("transaction rejected, closing connection");
-                    return;
+                debug!("received transaction from friend");
+                match blockchain.add_to_mempool(tx.clone()) {
+                    Ok(MempoolOutcome::AlreadyPresent) => {
+                        debug!("transaction already known, not relaying");
+                    }
+                    Ok(_) => {
+                        drop(blockchain);
+                        relay_transaction(&tx, origin.as_deref()).await;
+                    }
+                    Err(_) => {
+                        warn!("transaction rejected, closing connection");
+                        return;
+                    }
                 }
             }
             ValidateTemplate(block_template) => {
@@ -125,83 +357,102 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     == blockchain
                         .blocks()
                         .last()
-                        .map(|last_block| last_block.hash())
+                        .map(|last_block| last_block.id())
                         .unwrap_or(Hash::zero());
                 let message = TemplateValidity(status);
                 message.send_async(&mut socket).await.unwrap();
             }
             // 🚨🚨🚨🚨🚨 Verification du block ou ça ????
             SubmitTemplate(block) => {
-                println!("received allegedly validated block");
+                info!("received allegedly validated block");
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
                 if let Err(e) = blockchain.add_block(block.clone()) {
-                    println!("block rejected: {e}, closing connection");
+                    crate::metrics::block_rejected();
+                    warn!("block rejected: {e}, closing connection");
                     continue;
                 }
-                blockchain.rebuild_utxos();
-                println!("block looks good, broadcasting");
-                // send block to all friend nodes
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                for node in nodes {
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = Message::NewBlock(block.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            println!("failed to send block to {}", node);
-                        }
-                    }
-                }
+                drop(blockchain);
+                info!("block looks good, broadcasting");
+                crate::seen::mark_seen(block.id());
+                relay_block(&block, None).await;
             }
             SubmitTransaction(tx) => {
-                println!("submit tx");
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
-                    println!("transaction rejected, closing connection: {e}");
-                    return;
+                debug!("submit tx");
+                if !admit_transaction(&mut tx_bucket, &mut dropped_in_a_row) {
+                    if dropped_in_a_row >= MAX_DROPPED_TRANSACTIONS {
+                        warn!("client keeps exceeding the transaction rate, closing connection");
+                        return;
+                    }
+                    continue;
                 }
-                println!("added transaction to mempool");
-                // send transaction to all friend nodes
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                for node in nodes {
-                    println!("sending to friend: {node}");
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = Message::NewTransaction(tx.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            println!("failed to send transaction to {}", node);
-                        }
+                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                match blockchain.add_to_mempool(tx.clone()) {
+                    Ok(MempoolOutcome::AlreadyPresent) => {
+                        debug!("transaction already in mempool, not relaying");
+                    }
+                    Ok(_) => {
+                        info!("added transaction to mempool");
+                        drop(blockchain);
+                        crate::seen::mark_seen(tx.hash());
+                        relay_transaction(&tx, None).await;
+                    }
+                    Err(e) => {
+                        warn!("transaction rejected, closing connection: {e}");
+                        return;
                     }
                 }
-                println!("transaction sent to friends");
             }
 
             SlashValidator {
                 validator,
                 reason,
-                evidence: _,
+                evidence,
             } => {
-                use poslib::types::SlashingReason;
+                // slashing only happens through blocks, so every node
+                // agrees on stakes; the report just feeds our next block
+                let evidence: DoubleSignEvidence =
+                    match DoubleSignEvidence::load(evidence.as_slice()) {
+                        Ok(evidence) => evidence,
+                        Err(_) => {
+                            debug!(
+                                "ignoring {} report against {} without evidence",
+                                reason, validator
+                            );
+                            continue;
+                        }
+                    };
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-
-                let slashing_reason = if reason.contains("double") {
-                    SlashingReason::DoubleSigning
-                } else {
-                    SlashingReason::Downtime
-                };
-
-                match blockchain.slash_validator(&validator, slashing_reason) {
-                    Ok(penalty) => {
-                        println!("🔪 Validator slashed! Penalty: {} coins", penalty);
-                    }
-                    Err(e) => {
-                        println!("Failed to slash validator: {}", e);
-                    }
+                match blockchain.report_evidence(evidence) {
+                    Ok(()) => info!("queued double-sign evidence against {}", validator),
+                    Err(e) => warn!("rejected double-sign report against {}: {}", validator, e),
                 }
             }
         }
     }
 }
+
+/// Send a transaction to all friend nodes except the one it came from
+async fn relay_transaction(tx: &Transaction, origin: Option<&str>) {
+    let message = Message::NewTransaction(tx.clone());
+    let delivered = crate::util::broadcast(&message, origin).await;
+    debug!("transaction sent to {} friends", delivered);
+}
+
+/// Check an incoming transaction against the connection's rate limit,
+/// counting consecutive drops so abusive peers can be disconnected
+fn admit_transaction(bucket: &mut TokenBucket, dropped_in_a_row: &mut u32) -> bool {
+    if bucket.try_take() {
+        *dropped_in_a_row = 0;
+        return true;
+    }
+    *dropped_in_a_row += 1;
+    warn!("transaction rate limit exceeded, dropping transaction");
+    false
+}
+
+/// Send a block to all friend nodes except the one it came from
+async fn relay_block(block: &Block, origin: Option<&str>) {
+    let message = Message::NewBlock(block.clone());
+    let delivered = crate::util::broadcast(&message, origin).await;
+    debug!("block sent to {} friends", delivered);
+}
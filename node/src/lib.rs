@@ -5,14 +5,21 @@
 //! - Validators (which are nodes with additional proposer capabilities)
 //! - Other node types
 
+pub mod genesis;
 pub mod handler;
+pub mod metrics;
+pub mod peer;
+pub mod rate_limit;
+pub mod rpc;
+pub mod seen;
 pub mod util;
 
 use dashmap::DashMap;
+use peer::Peer;
 use poslib::types::Blockchain;
 use static_init::dynamic;
-use tokio::net::TcpStream;
 use tokio::sync::RwLock;
+use uuid::Uuid;
 
 // ============================================================================
 // Shared State
@@ -21,9 +28,13 @@ use tokio::sync::RwLock;
 /// Global blockchain state - thread-safe read/write access
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
-/// Connected peer nodes
+/// Connected peer nodes, keyed by their listening address
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static NODES: DashMap<String, Peer> = DashMap::new();
+/// Random id for this node, sent in DiscoverNodes so peers (and we) can
+/// recognise the same node behind different addresses
+#[dynamic]
+pub static NODE_ID: Uuid = Uuid::new_v4();
 
 pub const NODES_SELF_ADDRESS: &str = "127.0.0.1";
 // ============================================================================
@@ -1,13 +1,26 @@
 use anyhow::Result;
 use argh::FromArgs;
 use dashmap::DashMap;
+use genesis::GenesisConfig;
+use peer::Peer;
 use poslib::types::Blockchain;
+use poslib::util::Saveable;
 use static_init::dynamic;
-use std::path::Path;
-use tokio::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
+mod genesis;
 mod handler;
+mod metrics;
+mod peer;
+mod rate_limit;
+mod rpc;
+mod seen;
 mod util;
 
 #[derive(FromArgs)]
@@ -16,25 +29,95 @@ struct Args {
     #[argh(option, default = "9000")]
     /// port number
     port: u16,
-    #[argh(option, default = "String::from(\"./blockchain.cbor\")")]
-    /// blockchain file location
+    #[argh(option, default = "String::from(\"./\")")]
+    /// directory holding the blockchain file, mempool and validator keys
+    data_dir: String,
+    #[argh(option, default = "String::from(\"blockchain.cbor\")")]
+    /// blockchain file location, relative to the data directory
     blockchain_file: String,
+    #[argh(option)]
+    /// TOML file describing the genesis validators, used when starting a
+    /// new network (defaults to the built-in Alice and Bob); its chain
+    /// parameters also apply when syncing an existing one
+    genesis_config: Option<String>,
     #[argh(option, default = "String::new()")]
     /// addresses of initial nodes (comma-separated, e.g. "127.0.0.1:9001, 127.0.0.1:9002")
     nodes: String,
+    #[argh(option, default = "IpAddr::V4(Ipv4Addr::UNSPECIFIED)")]
+    /// address to listen on for peers, RPC and metrics, e.g. 127.0.0.1 to
+    /// accept local connections only (defaults to all interfaces)
+    bind: IpAddr,
+    #[argh(option)]
+    /// port for the read-only JSON-RPC HTTP endpoint (disabled if unset)
+    rpc_port: Option<u16>,
+    #[argh(option)]
+    /// port for the Prometheus /metrics HTTP endpoint (disabled if unset)
+    metrics_port: Option<u16>,
+    #[argh(option)]
+    /// write the UTXO set of the blockchain file to this path and exit
+    dump_utxos: Option<String>,
+    #[argh(option)]
+    /// height to take the UTXO dump at (defaults to the tip)
+    dump_height: Option<u64>,
+    #[argh(switch)]
+    /// print a summary of every block in the blockchain file and exit
+    dump_chain: bool,
+    #[argh(switch)]
+    /// print the entry count and size of the blocks, UTXO set, mempool
+    /// and orphan pool of the blockchain file and exit
+    stats: bool,
+    #[argh(switch)]
+    /// on first start, import a peer's chain snapshot instead of replaying
+    /// every block (the peer's UTXO set is trusted, not verified)
+    trust_snapshot: bool,
+    #[argh(switch)]
+    /// index every confirmed transaction for lookups and explorer RPC
+    /// queries, at the cost of memory growing with the chain
+    archive: bool,
+    #[argh(switch)]
+    /// save the blockchain file zstd-compressed (either format loads)
+    compress: bool,
+    #[argh(option, default = "poslib::protocol::MAX_MEMPOOL_TRANSACTION_AGE")]
+    /// seconds a transaction may wait in the mempool before it is dropped
+    mempool_max_age: u64,
+    #[argh(option, default = "rate_limit::DEFAULT_TX_RATE_LIMIT")]
+    /// transactions per second accepted from a single connection
+    tx_rate_limit: u32,
+    #[argh(option, default = "peer::DEFAULT_CONNECT_TIMEOUT")]
+    /// seconds to wait for a peer to accept a connection
+    connect_timeout: u64,
+    #[argh(option, default = "peer::DEFAULT_CONNECT_RETRIES")]
+    /// attempts to connect to each peer given with --nodes
+    connect_retries: u32,
+    #[argh(option, default = "peer::DEFAULT_RETRY_DELAY")]
+    /// seconds before retrying a failed connection, doubled every attempt
+    retry_delay: u64,
 }
 
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
 // Node pool
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static NODES: DashMap<String, Peer> = DashMap::new();
+// Random id for this node, used to detect self-connections and duplicates
+#[dynamic]
+pub static NODE_ID: Uuid = Uuid::new_v4();
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Log level is taken from RUST_LOG, defaulting to info
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
     // Parse command line arguments
     let args: Args = argh::from_env();
     let port = args.port;
+    let data_dir = PathBuf::from(args.data_dir);
     let blockchain_file = args.blockchain_file;
+    rate_limit::set_tx_rate_limit(args.tx_rate_limit);
+    util::set_compress_chain(args.compress);
+    peer::set_connect_policy(args.connect_timeout, args.connect_retries, args.retry_delay);
     // Parse comma-separated nodes
     let nodes: Vec<String> = args
         .nodes
@@ -43,42 +126,132 @@ async fn main() -> Result<()> {
         .filter(|s| !s.is_empty())
         .collect();
 
-    if Path::new(&blockchain_file).exists() {
-        println!("Loading blockchain from file: {}", blockchain_file);
-        util::load_blockchain(&blockchain_file).await?;
+    if args.dump_chain {
+        let blockchain = Blockchain::load_from_file(data_dir.join(&blockchain_file))?;
+        println!(
+            "{:>6}  {:<64}  {:<66}  {:<25}  {:>4}  {:>12}",
+            "height", "hash", "validator", "timestamp", "txs", "fees"
+        );
+        for (height, (block, fees)) in blockchain
+            .blocks()
+            .zip(blockchain.fees_per_block())
+            .enumerate()
+        {
+            println!(
+                "{:>6}  {:<64}  {:<66}  {:<25}  {:>4}  {:>12}",
+                height,
+                block.id(),
+                block.header.validator,
+                block.header.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+                block.transactions.len(),
+                fees
+            );
+        }
+        return Ok(());
+    }
+
+    if args.stats {
+        util::load_blockchain(&data_dir, &blockchain_file).await?;
+        let size = BLOCKCHAIN.read().await.state_size();
+        println!("{:<8}  {:>10}  {:>14}", "", "entries", "bytes");
+        for (name, estimate) in [
+            ("blocks", size.blocks),
+            ("utxos", size.utxos),
+            ("mempool", size.mempool),
+            ("orphans", size.orphans),
+        ] {
+            println!(
+                "{:<8}  {:>10}  {:>14}",
+                name, estimate.count, estimate.bytes
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(dump_file) = args.dump_utxos {
+        let blockchain = Blockchain::load_from_file(data_dir.join(&blockchain_file))?;
+        let height = args.dump_height.unwrap_or(blockchain.block_height());
+        let snapshot = blockchain.utxo_snapshot(height);
+        snapshot.save_to_file(&dump_file)?;
+        info!(
+            "dumped {} UTXOs worth {} satoshis at height {} to {}",
+            snapshot.utxos.len(),
+            snapshot.total_value,
+            snapshot.height,
+            dump_file
+        );
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&data_dir)?;
+    peer::load_identity(&data_dir)?;
+    if data_dir.join(&blockchain_file).exists() {
+        info!(
+            "Loading blockchain from file: {}",
+            data_dir.join(&blockchain_file).display()
+        );
+        util::load_blockchain(&data_dir, &blockchain_file).await?;
     } else {
-        println!("No existing blockchain found 😫, checking with other node .. ");
+        info!("No existing blockchain found 😫, checking with other node .. ");
+        let genesis_config = match &args.genesis_config {
+            Some(path) => Some(GenesisConfig::load(Path::new(path))?),
+            None => None,
+        };
+        util::connect_to_peers(nodes.clone(), port).await;
         if NODES.is_empty() {
-            println!("no connected nodes available, starting as a seed node 🤴");
-            let genesis_block = util::create_genesis_block();
+            info!("no connected nodes available, starting as a seed node 🤴");
+            let genesis_config = genesis_config.unwrap_or_default();
+            let genesis_block = util::create_genesis_block(&data_dir, &genesis_config);
             let mut blockchain = BLOCKCHAIN.write().await;
+            // the parameters are saved with the chain from here on
+            *blockchain = Blockchain::with_params(genesis_config.chain_params());
             blockchain
                 .add_block(genesis_block)
                 .expect("Failed to add genesis block");
         } else {
+            // the downloaded chain is checked against our parameters, so
+            // they have to match the seed node's
+            if let Some(genesis_config) = genesis_config {
+                *BLOCKCHAIN.write().await = Blockchain::with_params(genesis_config.chain_params());
+            }
             let (longest_name, longest_count) = util::find_longest_chain_node().await?;
-            // request the blockchain from the node with the lon-gest blockchain
-            util::download_blockchain(&longest_name, longest_count).await?;
-            println!("blockchain downloaded from {}", longest_name);
-            {
-                // recalculate utxos
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
+            if args.trust_snapshot {
+                util::download_snapshot(&longest_name).await?;
+            } else {
+                // request the blockchain from the node with the lon-gest blockchain
+                util::download_blockchain(&longest_name, longest_count).await?;
             }
+            info!("blockchain downloaded from {}", longest_name);
         }
     }
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Listening on {}", addr);
+    {
+        let mut blockchain = BLOCKCHAIN.write().await;
+        blockchain.set_max_mempool_transaction_age(args.mempool_max_age);
+        blockchain.set_archive(args.archive);
+    }
+    let addr = SocketAddr::new(args.bind, port);
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", addr);
     // start a task to periodically cleanup the mempool
     // normally, you would want to keep and join the handle
     tokio::spawn(util::cleanup());
 
     // and a task to periodically save the blockchain
-    tokio::spawn(util::save(blockchain_file.clone()));
-    tokio::spawn(util::populate_connections(nodes, port));
-    loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+    tokio::spawn(util::save(data_dir.clone(), blockchain_file.clone()));
+    tokio::spawn(util::announce(port));
+    tokio::spawn(util::populate_connections(nodes.clone(), port));
+    tokio::spawn(util::maintain_peers(nodes, port));
+    if let Some(rpc_port) = args.rpc_port {
+        tokio::spawn(rpc::serve(SocketAddr::new(args.bind, rpc_port)));
+    }
+    if let Some(metrics_port) = args.metrics_port {
+        tokio::spawn(metrics::serve(SocketAddr::new(args.bind, metrics_port)));
     }
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    util::serve_until(listener, ctrl_c, &data_dir, &blockchain_file).await?;
+    let height = BLOCKCHAIN.read().await.block_height();
+    info!("state saved at height {}, goodbye", height);
+    Ok(())
 }
@@ -0,0 +1,184 @@
+//! Prometheus metrics endpoint over HTTP
+//!
+//! Hand-rolled like the RPC endpoint: any request on the metrics port gets
+//! the current values in the Prometheus text format, then the connection
+//! is closed. Gauges are read from the shared state at scrape time; only
+//! events the chain doesn't remember are counted here.
+
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Blocks from peers that failed validation since startup
+static BLOCKS_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Count a block that failed validation
+pub fn block_rejected() {
+    BLOCKS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics listening on {}", addr);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_connection(socket).await {
+                warn!("metrics request failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_metrics_connection(mut socket: TcpStream) -> Result<()> {
+    // only the request line matters, the path is not checked
+    let mut request_line = String::new();
+    BufReader::new(&mut socket)
+        .read_line(&mut request_line)
+        .await?;
+    let body = render().await;
+    let http = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    socket.write_all(http.as_bytes()).await?;
+    Ok(())
+}
+
+/// Current metrics in the Prometheus text exposition format
+pub async fn render() -> String {
+    let (height, mempool_size, orphans, slashings, size) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (
+            blockchain.block_height(),
+            blockchain.mempool().len(),
+            blockchain.orphan_count(),
+            blockchain.slashing_history().len(),
+            blockchain.state_size(),
+        )
+    };
+    let metrics: [(&str, &str, &str, u64); 11] = [
+        (
+            "chain_height",
+            "gauge",
+            "Number of blocks in the chain",
+            height,
+        ),
+        (
+            "peer_count",
+            "gauge",
+            "Connected peer nodes",
+            crate::NODES.len() as u64,
+        ),
+        (
+            "mempool_size",
+            "gauge",
+            "Transactions waiting in the mempool",
+            mempool_size as u64,
+        ),
+        (
+            "orphan_blocks",
+            "gauge",
+            "Blocks waiting for their parent",
+            orphans as u64,
+        ),
+        (
+            "blocks_rejected_total",
+            "counter",
+            "Blocks that failed validation since startup",
+            BLOCKS_REJECTED.load(Ordering::Relaxed),
+        ),
+        (
+            "slashing_events_total",
+            "counter",
+            "Slashing events recorded on the chain",
+            slashings as u64,
+        ),
+        (
+            "utxo_count",
+            "gauge",
+            "Unspent outputs in the UTXO set",
+            size.utxos.count as u64,
+        ),
+        (
+            "blocks_bytes",
+            "gauge",
+            "Encoded size of the blocks",
+            size.blocks.bytes,
+        ),
+        (
+            "utxos_bytes",
+            "gauge",
+            "Encoded size of the UTXO set",
+            size.utxos.bytes,
+        ),
+        (
+            "mempool_bytes",
+            "gauge",
+            "Encoded size of the mempool",
+            size.mempool.bytes,
+        ),
+        (
+            "orphans_bytes",
+            "gauge",
+            "Encoded size of the orphan pool",
+            size.orphans.bytes,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `value` of the sample line of `name`
+    fn sample(exposition: &str, name: &str) -> Option<u64> {
+        exposition
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+    }
+
+    #[tokio::test]
+    async fn render_follows_the_exposition_format() {
+        let exposition = render().await;
+        assert!(exposition.ends_with('\n'));
+        let lines: Vec<&str> = exposition.lines().collect();
+        assert_eq!(lines.len() % 3, 0);
+        for metric in lines.chunks(3) {
+            let name = metric[0]
+                .strip_prefix("# HELP ")
+                .and_then(|help| help.split_once(' '))
+                .map(|(name, _)| name)
+                .expect("HELP line first");
+            let kind = metric[1]
+                .strip_prefix(&format!("# TYPE {name} "))
+                .expect("TYPE line second");
+            assert!(matches!(kind, "gauge" | "counter"), "{name} is a {kind}");
+            assert_eq!(kind == "counter", name.ends_with("_total"), "{name}");
+            let (sample_name, value) = metric[2].split_once(' ').unwrap();
+            assert_eq!(sample_name, name);
+            value.parse::<u64>().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_blocks_are_counted() {
+        let before = sample(&render().await, "blocks_rejected_total").unwrap();
+        block_rejected();
+        let after = sample(&render().await, "blocks_rejected_total").unwrap();
+        assert!(after > before);
+    }
+}
@@ -0,0 +1,399 @@
+//! Connected peer bookkeeping
+//!
+//! Every `NODES` entry keeps the address the peer *listens* on (its
+//! announced address), never the ephemeral source port of an inbound
+//! socket, so a dead connection can always be dialed again. Peers are only
+//! added once they have signed a challenge with their node key.
+//!
+//! The node key is kept in the data directory, so a node answers for the
+//! same key across restarts. Each address is pinned to the first key it
+//! answered for (trust on first use) in `known_peers.toml`, which can also
+//! be written by hand beforehand to only accept the listed keys.
+
+use anyhow::{Context, Result, anyhow, bail};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use poslib::crypto::{PrivateKey, PublicKey};
+use poslib::network::Message;
+use poslib::sha256::Hash;
+use poslib::util::{Saveable, write_atomically};
+use static_init::dynamic;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// File in the data directory holding the node key
+pub const NODE_KEY_FILE: &str = "node_key.cbor";
+/// File in the data directory mapping peer addresses to their node keys
+pub const KNOWN_PEERS_FILE: &str = "known_peers.toml";
+
+/// Seconds a dial may take, handshake included, by default
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+/// Attempts `dial_with_retry` makes by default
+pub const DEFAULT_CONNECT_RETRIES: u32 = 5;
+/// Seconds before the first retry by default, doubled after every attempt
+pub const DEFAULT_RETRY_DELAY: u64 = 1;
+/// Longest wait between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+static CONNECT_TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_CONNECT_TIMEOUT);
+static CONNECT_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_CONNECT_RETRIES);
+static RETRY_DELAY: AtomicU64 = AtomicU64::new(DEFAULT_RETRY_DELAY);
+
+/// Key this node signs handshake challenges with
+static NODE_KEY: OnceLock<PrivateKey> = OnceLock::new();
+/// Node key each peer address is pinned to
+#[dynamic]
+static KNOWN_PEERS: DashMap<String, PublicKey> = DashMap::new();
+/// Where `KNOWN_PEERS` is saved, once `load_identity` has run
+static KNOWN_PEERS_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Load the node key from `data_dir`, generating and saving one on first
+/// start, along with the keys pinned to peer addresses
+pub fn load_identity(data_dir: &Path) -> Result<()> {
+    let key = load_or_create_key(&data_dir.join(NODE_KEY_FILE))?;
+    if NODE_KEY.set(key).is_err() {
+        bail!("the node key is already in use");
+    }
+
+    let peers_file = data_dir.join(KNOWN_PEERS_FILE);
+    if peers_file.exists() {
+        let text = std::fs::read_to_string(&peers_file)?;
+        let pinned: BTreeMap<String, String> =
+            toml::from_str(&text).with_context(|| format!("parsing {}", peers_file.display()))?;
+        for (addr, key) in pinned {
+            let key = key
+                .parse()
+                .map_err(|_| anyhow!("bad key for {} in {}", addr, peers_file.display()))?;
+            KNOWN_PEERS.insert(addr, key);
+        }
+    }
+    let _ = KNOWN_PEERS_PATH.set(peers_file);
+    Ok(())
+}
+
+fn load_or_create_key(key_file: &Path) -> Result<PrivateKey> {
+    if key_file.exists() {
+        return PrivateKey::load_from_file(key_file)
+            .with_context(|| format!("reading node key {}", key_file.display()));
+    }
+    let key = PrivateKey::new_key();
+    key.save_to_file(key_file)
+        .with_context(|| format!("saving node key {}", key_file.display()))?;
+    info!("generated node key {}", key_file.display());
+    Ok(key)
+}
+
+/// Key this node signs handshake challenges with. Without `load_identity`
+/// it is a fresh key, only valid until the process exits.
+pub fn node_key() -> &'static PrivateKey {
+    NODE_KEY.get_or_init(PrivateKey::new_key)
+}
+
+/// Pin `addr` to `key` if it has no key yet, and fail if it is pinned to
+/// another one
+fn pin(addr: &str, key: &PublicKey) -> Result<()> {
+    match KNOWN_PEERS.entry(addr.to_string()) {
+        Entry::Occupied(pinned) if pinned.get() == key => return Ok(()),
+        Entry::Occupied(pinned) => bail!(
+            "{} answered for key {}, but is pinned to {} in {} (remove the entry if the node was reinstalled)",
+            addr,
+            key,
+            pinned.get(),
+            KNOWN_PEERS_FILE
+        ),
+        Entry::Vacant(entry) => {
+            entry.insert(key.clone());
+        }
+    }
+    if let Err(e) = save_known_peers() {
+        warn!("failed to save {}: {}", KNOWN_PEERS_FILE, e);
+    }
+    Ok(())
+}
+
+fn save_known_peers() -> Result<()> {
+    let Some(path) = KNOWN_PEERS_PATH.get() else {
+        return Ok(());
+    };
+    let pinned: BTreeMap<String, String> = KNOWN_PEERS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().to_string()))
+        .collect();
+    let text = toml::to_string(&pinned)?;
+    write_atomically(path, |file| file.write_all(text.as_bytes()))?;
+    Ok(())
+}
+
+/// Set the dial timeout and retry backoff, for dials started afterwards
+pub fn set_connect_policy(timeout_secs: u64, retries: u32, retry_delay_secs: u64) {
+    CONNECT_TIMEOUT.store(timeout_secs.max(1), Ordering::Relaxed);
+    CONNECT_RETRIES.store(retries.max(1), Ordering::Relaxed);
+    RETRY_DELAY.store(retry_delay_secs, Ordering::Relaxed);
+}
+
+/// Our outbound connection to a peer. It is cloned out of `NODES` before
+/// talking to the peer, so no map guard is held across an await; the lock
+/// keeps a request and its answer together.
+pub type PeerStream = Arc<Mutex<TcpStream>>;
+
+pub struct Peer {
+    /// Address the peer accepts connections on, e.g. "127.0.0.1:9001"
+    pub listen_addr: String,
+    /// Our outbound connection to that address
+    pub stream: PeerStream,
+    /// Last time the peer proved it was alive (connect or Pong)
+    pub last_seen: Instant,
+    /// The peer's node id, once it has been exchanged in DiscoverNodes
+    pub node_id: Option<Uuid>,
+    /// The peer's node key, once it has signed our challenge
+    pub public_key: Option<PublicKey>,
+}
+
+impl Peer {
+    pub fn new(listen_addr: String, stream: TcpStream) -> Self {
+        Peer {
+            listen_addr,
+            stream: Arc::new(Mutex::new(stream)),
+            last_seen: Instant::now(),
+            node_id: None,
+            public_key: None,
+        }
+    }
+
+    /// Dial the peer's listening address and exchange versions
+    pub async fn connect(listen_addr: &str) -> io::Result<Self> {
+        let stream = dial(listen_addr).await?;
+        Ok(Peer::new(listen_addr.to_string(), stream))
+    }
+
+    /// Dial the peer and remember its node id
+    pub async fn connect_with_id(listen_addr: &str, node_id: Uuid) -> io::Result<Self> {
+        let mut peer = Peer::connect(listen_addr).await?;
+        peer.node_id = Some(node_id);
+        Ok(peer)
+    }
+
+    /// Have the peer sign a fresh nonce with its node key, and remember
+    /// the key if the signature checks out and it is the one pinned to the
+    /// peer's address
+    pub async fn verify_identity(&mut self) -> Result<()> {
+        let nonce = Hash::hash(&Uuid::new_v4());
+        let mut stream = self.stream.lock().await;
+        Message::Challenge(nonce).send_async(&mut *stream).await?;
+        match Message::receive_async(&mut *stream).await? {
+            Message::ChallengeResponse {
+                public_key,
+                signature,
+            } if signature.verify(&nonce, &public_key) => {
+                pin(&self.listen_addr, &public_key)?;
+                self.public_key = Some(public_key);
+                Ok(())
+            }
+            Message::ChallengeResponse { .. } => Err(anyhow!(
+                "{} failed the handshake challenge",
+                self.listen_addr
+            )),
+            _ => Err(anyhow!("unexpected message from {}", self.listen_addr)),
+        }
+    }
+}
+
+/// The connection to the peer listening on `addr`, if it is in `NODES`
+pub fn stream_of(addr: &str) -> Option<PeerStream> {
+    crate::NODES.get(addr).map(|peer| peer.stream.clone())
+}
+
+/// Record that the peer listening on `addr` just proved it is alive
+pub fn touch(addr: &str) {
+    if let Some(mut peer) = crate::NODES.get_mut(addr) {
+        peer.last_seen = Instant::now();
+    }
+}
+
+/// Replace `stream` with a fresh connection to the peer listening on `addr`
+pub async fn redial(addr: &str, stream: &mut TcpStream) -> io::Result<()> {
+    *stream = dial(addr).await?;
+    touch(addr);
+    Ok(())
+}
+
+/// Connect to `addr` and exchange versions, so every stream to a peer
+/// starts with the handshake its handler expects. Fails with `TimedOut`
+/// if both together take longer than the connect timeout.
+pub async fn dial(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(CONNECT_TIMEOUT.load(Ordering::Relaxed));
+    dial_within(addr, timeout).await
+}
+
+/// `dial` with the connect timeout given
+async fn dial_within(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<TcpStream> {
+    let connect = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let genesis_hash = crate::BLOCKCHAIN.read().await.genesis_hash();
+        Message::handshake_async(&mut stream, genesis_hash).await?;
+        Ok::<_, io::Error>(stream)
+    };
+    tokio::time::timeout(timeout, connect)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+}
+
+/// `dial` until it succeeds or the retries run out, waiting twice as long
+/// after every failed attempt
+pub async fn dial_with_retry(addr: impl ToSocketAddrs + Copy) -> io::Result<TcpStream> {
+    let retries = CONNECT_RETRIES.load(Ordering::Relaxed);
+    let delay = Duration::from_secs(RETRY_DELAY.load(Ordering::Relaxed));
+    with_backoff(retries, delay, || dial(addr)).await
+}
+
+/// Make up to `retries` attempts with `connect`, waiting `delay` after
+/// the first failure and twice as long after every next one
+async fn with_backoff<F: Future<Output = io::Result<TcpStream>>>(
+    retries: u32,
+    mut delay: Duration,
+    mut connect: impl FnMut() -> F,
+) -> io::Result<TcpStream> {
+    let mut attempt = 1;
+    loop {
+        match connect().await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(e) => {
+                debug!(
+                    "connection failed: {}, retrying in {:?} ({} attempts left)",
+                    e,
+                    delay,
+                    retries - attempt
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poslib::crypto::Signature;
+
+    #[test]
+    fn node_key_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("node-key-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join(NODE_KEY_FILE);
+        let first = load_or_create_key(&key_file).unwrap();
+        let second = load_or_create_key(&key_file).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+    }
+
+    /// A peer at `addr` whose node answers our challenge with `answer`
+    async fn challenged_peer(
+        addr: &str,
+        answer: impl FnOnce(Hash) -> Message + Send + 'static,
+    ) -> Peer {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            if let Ok(Message::Challenge(nonce)) = Message::receive_async(&mut stream).await {
+                answer(nonce).send_async(&mut stream).await.unwrap();
+            }
+        });
+        Peer::new(addr.to_string(), TcpStream::connect(local).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn wrong_challenge_signature_is_refused() {
+        let key = PrivateKey::new_key();
+        let impostor = PrivateKey::new_key();
+        let mut peer = challenged_peer("192.0.2.3:9000", move |nonce| Message::ChallengeResponse {
+            public_key: key.public_key(),
+            signature: Signature::sign_output(&nonce, &impostor),
+        })
+        .await;
+        assert!(peer.verify_identity().await.is_err());
+        assert!(peer.public_key.is_none());
+        assert!(!KNOWN_PEERS.contains_key("192.0.2.3:9000"));
+
+        // a stale nonce signed with the right key doesn't pass either
+        let key = PrivateKey::new_key();
+        let mut peer = challenged_peer("192.0.2.3:9001", move |_| Message::ChallengeResponse {
+            public_key: key.public_key(),
+            signature: Signature::sign_output(&Hash::zero(), &key),
+        })
+        .await;
+        assert!(peer.verify_identity().await.is_err());
+        assert!(!KNOWN_PEERS.contains_key("192.0.2.3:9001"));
+    }
+
+    #[tokio::test]
+    async fn signed_challenge_pins_the_key() {
+        let key = PrivateKey::new_key();
+        let public_key = key.public_key();
+        let mut peer = challenged_peer("192.0.2.3:9002", move |nonce| Message::ChallengeResponse {
+            public_key: key.public_key(),
+            signature: Signature::sign_output(&nonce, &key),
+        })
+        .await;
+        peer.verify_identity().await.unwrap();
+        assert_eq!(peer.public_key.as_ref(), Some(&public_key));
+        assert_eq!(
+            KNOWN_PEERS.get("192.0.2.3:9002").map(|key| key.clone()),
+            Some(public_key)
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        // the kernel accepts the connection, but nobody answers the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let started = Instant::now();
+        let error = dial_within(listener.local_addr().unwrap(), Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn retries_back_off_until_they_run_out() {
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let attempts = std::cell::RefCell::new(vec![]);
+        let error = with_backoff(3, Duration::from_millis(50), || {
+            attempts.borrow_mut().push(Instant::now());
+            dial_within(closed, Duration::from_secs(1))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let attempts = attempts.into_inner();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(50));
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn address_is_pinned_to_its_first_key() {
+        let (first, second) = (PrivateKey::new_key(), PrivateKey::new_key());
+        pin("192.0.2.1:9000", &first.public_key()).unwrap();
+        pin("192.0.2.1:9000", &first.public_key()).unwrap();
+        assert!(pin("192.0.2.1:9000", &second.public_key()).is_err());
+        pin("192.0.2.2:9000", &second.public_key()).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+//! Per-connection limit on incoming transactions
+//!
+//! Every accepted transaction is written to the mempool and relayed to all
+//! peers, so a single connection could otherwise flood both. Each
+//! connection gets its own token bucket; transactions arriving with the
+//! bucket empty are dropped.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+/// Transactions per second accepted from one connection by default
+pub const DEFAULT_TX_RATE_LIMIT: u32 = 20;
+/// Dropped transactions in a row after which we hang up on the peer
+pub const MAX_DROPPED_TRANSACTIONS: u32 = 100;
+
+/// Transactions per second accepted from one connection
+static TX_RATE_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_TX_RATE_LIMIT);
+
+/// Set the per-connection transaction rate, for connections opened afterwards
+pub fn set_tx_rate_limit(rate: u32) {
+    TX_RATE_LIMIT.store(rate.max(1), Ordering::Relaxed);
+}
+
+pub fn tx_rate_limit() -> u32 {
+    TX_RATE_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Token bucket refilled at `rate` tokens per second, holding at most one
+/// second's worth so short bursts are allowed
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    /// `try_take` with the clock reading `now`
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_one_second() {
+        let mut bucket = TokenBucket::new(4);
+        let start = bucket.last_refill;
+        let took = |bucket: &mut TokenBucket, at: Instant| {
+            (0..10).take_while(|_| bucket.try_take_at(at)).count()
+        };
+
+        // a full second's worth, then empty
+        assert_eq!(took(&mut bucket, start), 4);
+        assert!(!bucket.try_take_at(start));
+        // 0.3s refills 1.2 tokens, one of them whole
+        assert_eq!(took(&mut bucket, start + Duration::from_millis(300)), 1);
+        // a long pause doesn't allow more than the burst
+        assert_eq!(took(&mut bucket, start + Duration::from_secs(60)), 4);
+    }
+}
@@ -0,0 +1,201 @@
+//! Read-only JSON-RPC endpoint over HTTP
+//!
+//! Hand-rolled on top of tokio to avoid pulling in a web framework:
+//! one JSON-RPC 2.0 request per connection, answered and closed.
+//! Only queries are exposed - nothing here can modify the chain or mempool.
+//!
+//! Methods (positional params):
+//! - `get_block_height` -> number of blocks
+//! - `get_block_by_height` [height] -> block
+//! - `get_utxos_for_pubkey` [public key PEM] -> list of [output, marked]
+//! - `get_mempool_size` -> number of pending transactions
+//! - `get_transaction` [hash hex] -> height, confirmations and transaction
+//!   (archive nodes only, see `--archive`)
+
+use anyhow::{Result, bail};
+use poslib::crypto::PublicKey;
+use poslib::sha256::Hash;
+use poslib::util::Saveable;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Largest request body we accept, in bytes
+const MAX_RPC_BODY: usize = 64 * 1024;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("RPC listening on {}", addr);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_rpc_connection(socket).await {
+                warn!("rpc request failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_rpc_connection(mut socket: TcpStream) -> Result<()> {
+    let body = read_http_body(&mut socket).await?;
+    let response = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => handle_request(&request).await,
+        Err(_) => error_response(Value::Null, PARSE_ERROR, "Parse error"),
+    };
+    let body = response.to_string();
+    let http = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    socket.write_all(http.as_bytes()).await?;
+    Ok(())
+}
+
+/// Skip the request line and headers, then read `Content-Length` bytes
+async fn read_http_body(socket: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(socket);
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("connection closed before end of headers");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse()?;
+        }
+    }
+    if content_length > MAX_RPC_BODY {
+        bail!("request body of {} bytes is too large", content_length);
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+async fn handle_request(request: &Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return error_response(id, INVALID_REQUEST, "Invalid request");
+    };
+    let param = request.get("params").and_then(|params| params.get(0));
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let result = match method {
+        "get_block_height" => Ok(json!(blockchain.block_height())),
+        "get_block_by_height" => match param.and_then(Value::as_u64) {
+            Some(height) => blockchain
+                .blocks()
+                .nth(height as usize)
+                .map(|block| serde_json::to_value(block).unwrap_or(Value::Null))
+                .ok_or((INVALID_PARAMS, "Block not found")),
+            None => Err((INVALID_PARAMS, "Expected [height]")),
+        },
+        "get_utxos_for_pubkey" => match param
+            .and_then(Value::as_str)
+            .and_then(|pem| PublicKey::load(pem.as_bytes()).ok())
+        {
+            Some(key) => {
+                let utxos = blockchain
+                    .utxos()
+                    .values()
+                    .filter(|(_, txout)| txout.pubkey == key)
+                    .map(|(marked, txout)| (txout, *marked))
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_value(utxos).unwrap_or(Value::Null))
+            }
+            None => Err((INVALID_PARAMS, "Expected [public key PEM]")),
+        },
+        "get_mempool_size" => Ok(json!(blockchain.mempool().len())),
+        "get_transaction" if !blockchain.is_archive() => {
+            Err((METHOD_NOT_FOUND, "Only available on archive nodes"))
+        }
+        "get_transaction" => match param
+            .and_then(Value::as_str)
+            .and_then(|hash| Hash::from_hex(hash).ok())
+        {
+            Some(hash) => blockchain
+                .find_transaction(&hash)
+                .map(|(height, transaction)| {
+                    json!({
+                        "height": height,
+                        "confirmations": blockchain.confirmations(&hash),
+                        "transaction": serde_json::to_value(transaction).unwrap_or(Value::Null),
+                    })
+                })
+                .ok_or((INVALID_PARAMS, "Transaction not found")),
+            None => Err((INVALID_PARAMS, "Expected [transaction hash]")),
+        },
+        _ => Err((METHOD_NOT_FOUND, "Method not found")),
+    };
+
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err((code, message)) => error_response(id, code, message),
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn call(method: &str, params: Value) -> Value {
+        handle_request(&json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 7 }))
+            .await
+    }
+
+    fn error_code(response: &Value) -> Option<i64> {
+        response["error"]["code"].as_i64()
+    }
+
+    #[tokio::test]
+    async fn known_method_answers_with_the_request_id() {
+        let response = call("get_block_height", json!([])).await;
+        assert!(response["result"].is_u64());
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["jsonrpc"], "2.0");
+    }
+
+    #[tokio::test]
+    async fn unknown_method_is_not_found() {
+        let response = call("submit_block", json!([])).await;
+        assert_eq!(error_code(&response), Some(METHOD_NOT_FOUND));
+        assert_eq!(response["id"], 7);
+    }
+
+    #[tokio::test]
+    async fn malformed_params_are_invalid() {
+        for (method, params) in [
+            ("get_block_by_height", json!(["one"])),
+            ("get_block_by_height", json!([])),
+            ("get_utxos_for_pubkey", json!([42])),
+            ("get_utxos_for_pubkey", json!(["not a key"])),
+        ] {
+            let response = call(method, params).await;
+            assert_eq!(error_code(&response), Some(INVALID_PARAMS), "{method}");
+        }
+        let response = handle_request(&json!({ "jsonrpc": "2.0", "id": 7 })).await;
+        assert_eq!(error_code(&response), Some(INVALID_REQUEST));
+    }
+}
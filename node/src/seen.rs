@@ -0,0 +1,80 @@
+//! Bounded cache of recently seen transaction and block hashes
+//!
+//! Gossip relays everything it accepts to every peer, so without this a
+//! transaction bounces between nodes until the mempool dedup catches it.
+//! Once a hash is in here we neither process nor relay it again. Blocks
+//! are only marked once accepted or kept as orphans, so one rejected for a
+//! temporary reason is processed again when it comes back.
+
+use poslib::sha256::Hash;
+use static_init::dynamic;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// How many hashes we remember before evicting the oldest
+pub const SEEN_CACHE_CAPACITY: usize = 10_000;
+
+/// Recently relayed transaction/block hashes
+#[dynamic]
+pub static SEEN: Mutex<SeenCache> = Mutex::new(SeenCache::new(SEEN_CACHE_CAPACITY));
+
+pub struct SeenCache {
+    capacity: usize,
+    order: VecDeque<Hash>,
+    hashes: HashSet<Hash>,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            hashes: HashSet::with_capacity(capacity),
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Record a hash. Returns `true` if it was not seen before.
+    pub fn insert(&mut self, hash: Hash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// Whether the global cache holds a hash, without marking it
+pub fn is_seen(hash: &Hash) -> bool {
+    SEEN.lock().unwrap().contains(hash)
+}
+
+/// Mark a hash as seen in the global cache. Returns `true` if it is new.
+pub fn mark_seen(hash: Hash) -> bool {
+    SEEN.lock().unwrap().insert(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_hash_is_forgotten_first() {
+        let mut cache = SeenCache::new(2);
+        let hashes: Vec<Hash> = (0u8..3).map(|i| Hash::hash(&i)).collect();
+        assert!(cache.insert(hashes[0]));
+        assert!(!cache.insert(hashes[0]));
+        assert!(cache.insert(hashes[1]));
+        assert!(cache.insert(hashes[2]));
+        assert!(!cache.contains(&hashes[0]));
+        assert!(cache.contains(&hashes[1]) && cache.contains(&hashes[2]));
+    }
+}
@@ -1,48 +1,60 @@
-use anyhow::{Context, Result};
+use crate::genesis::GenesisConfig;
+use crate::peer::Peer;
+use anyhow::{Context, Result, anyhow};
 use chrono::Utc;
+use dashmap::DashMap;
 use poslib::crypto::{PrivateKey, PublicKey, Signature};
-use poslib::network::Message;
+use poslib::network::{MAX_CHAIN_SEGMENT, Message};
 use poslib::sha256::Hash;
 use poslib::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
-use poslib::util::{MerkleRoot, Saveable};
-use tokio::net::TcpStream;
+use poslib::util::{MerkleRoot, Saveable, write_atomically};
+use static_init::dynamic;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
 use tokio::time;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-pub fn create_genesis_block() -> Block {
+/// Keys of the pre-defined validators are looked up under `data_dir`
+pub fn create_genesis_block(data_dir: &Path, config: &GenesisConfig) -> Block {
     let mut outputs = Vec::new();
 
-    // Try to load pre-defined validators
-    let validators = vec!["validator/alice.pub.pem", "validator/bob.pub.pem"];
-    let validator_count = validators.len() as u64;
-    for path in validators {
-        if let Ok(pubkey) = PublicKey::load_from_file(path) {
-            outputs.push(TransactionOutput {
-                unique_id: Uuid::new_v4(),
-                value: poslib::TOTAL_SUPPLY_CAP / validator_count,
-                pubkey: pubkey.clone(),
-                is_stake: false, // Regular spendable coins
-                locked_until: 0,
-            });
-            println!(
-                "  - Allocated {} spendable coins",
-                poslib::TOTAL_SUPPLY_CAP / validator_count
+    for validator in &config.validators {
+        let path = data_dir.join(&validator.public_key);
+        let Ok(pubkey) = PublicKey::load_from_file(&path) else {
+            warn!(
+                "skipping genesis validator {}: key not found",
+                path.display()
             );
+            continue;
+        };
+        outputs.push(TransactionOutput {
+            unique_id: Uuid::new_v4(),
+            value: validator.allocation,
+            pubkey: pubkey.clone(),
+            is_stake: false, // Regular spendable coins
+            locked_until: 0,
+            delegate_to: None,
+        });
+        info!("Allocated {} spendable coins", validator.allocation);
 
-            println!("Allocating genesis stake to {}", path);
-
-            outputs.push(TransactionOutput {
-                unique_id: Uuid::new_v4(),
-                value: poslib::STAKE_MINIMUM_AMOUNT,
-                pubkey: pubkey.clone(),
-                is_stake: true,
-                locked_until: 100, // Locked for  the first 100 blocks
-            });
-            println!(
-                "  - Allocated {} staked coins (locked until block 100)",
-                poslib::STAKE_MINIMUM_AMOUNT
-            );
-        }
+        info!("Allocating genesis stake to {}", validator.public_key);
+
+        outputs.push(TransactionOutput {
+            unique_id: Uuid::new_v4(),
+            value: validator.stake,
+            pubkey: pubkey.clone(),
+            is_stake: true,
+            locked_until: validator.locked_until,
+            delegate_to: None,
+        });
+        info!(
+            "Allocated {} staked coins (locked until block {})",
+            validator.stake, validator.locked_until
+        );
     }
 
     let transactions = vec![Transaction::new(vec![], outputs)];
@@ -52,98 +64,323 @@ pub fn create_genesis_block() -> Block {
         Utc::now(),
         Hash::zero(),
         merkle_root,
-        PublicKey::load_from_file("validator/alice.pub.pem")
+        PublicKey::load_from_file(data_dir.join(&config.signer_public_key))
             .expect("Failed to load genesis validator public key"),
     );
 
     let signature = Signature::sign_output(
         &header.hash(),
-        &PrivateKey::load_from_file("validator/alice.priv.cbor")
+        &PrivateKey::load_from_file(data_dir.join(&config.signer_private_key))
             .expect("Failed to load genesis validator private key"),
     );
     Block::new(header, transactions, signature)
 }
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
-    println!("blockchain file exists, loading...");
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
-    println!("blockchain loaded");
+/// Sidecar file the mempool is saved to, next to the blockchain file
+pub fn mempool_file(blockchain_file: &Path) -> PathBuf {
+    blockchain_file.with_extension("mempool.cbor")
+}
+
+pub async fn load_blockchain(data_dir: &Path, blockchain_file: &str) -> Result<()> {
+    info!("blockchain file exists, loading...");
+    let blockchain_file = data_dir.join(blockchain_file);
+    let new_blockchain = Blockchain::load_from_file(&blockchain_file)?;
+    debug!("blockchain loaded, validating...");
+    new_blockchain
+        .validate_full_chain()
+        .context("blockchain file failed validation")?;
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
-    println!("rebuilding utxos...");
+    debug!("rebuilding utxos...");
     blockchain.rebuild_utxos();
-    println!("utxos rebuilt");
-    println!("initialization complete");
+    debug!("utxos rebuilt");
+    let mempool_file = mempool_file(&blockchain_file);
+    if mempool_file.exists() {
+        match File::open(&mempool_file).map(|file| blockchain.load_mempool(file)) {
+            Ok(Ok(count)) => info!("restored {} mempool transactions", count),
+            Ok(Err(e)) | Err(e) => warn!("could not restore mempool: {}", e),
+        }
+    }
+    info!("initialization complete");
     Ok(())
 }
 
+/// Node id each address we dialed answered DiscoverNodes with, so a seed
+/// connected under another address isn't mistaken for a lost one
+#[dynamic]
+static DIALED_IDS: DashMap<String, Uuid> = DashMap::new();
+
+/// Whether the node at `addr` is a peer, under that address or, if it
+/// told us its node id before, under any other
+fn is_connected(addr: &str) -> bool {
+    crate::NODES.contains_key(addr)
+        || DIALED_IDS
+            .get(addr)
+            .is_some_and(|node_id| is_known_node(*node_id))
+}
+
+/// Connect to `nodes` and the peers they know, then ask them for the
+/// transactions we missed
 pub async fn populate_connections(nodes: Vec<String>, port: u16) -> Result<()> {
-    println!("trying to connect to other nodes...");
+    connect_to_peers(nodes, port).await;
+    sync_mempool().await;
+    Ok(())
+}
+
+/// Connect to `nodes` and the peers they list. Called before syncing, so a
+/// node started with `--nodes` downloads the chain instead of creating a
+/// genesis block of its own.
+pub async fn connect_to_peers(nodes: Vec<String>, port: u16) {
+    info!("trying to connect to other nodes...");
     'node_loop: for node in nodes {
-        println!("connecting to {}", node);
+        debug!("connecting to {}", node);
+        let addr = match resolve_peer(&node).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("skipping peer {}: {}", node, e);
+                continue 'node_loop;
+            }
+        };
         // Skip connecting to ourselves
-        if node.contains(&format!("127.0.0.1:{}", port))
-            || node.contains(&format!("localhost:{}", port))
-        {
-            println!("  - skipping self (127.0.0.1:{})", port);
+        if is_self_addr(addr, port) {
+            debug!("skipping self ({})", addr);
+            continue 'node_loop;
+        }
+        if is_connected(&node) {
+            debug!("already connected to {}", node);
             continue 'node_loop;
         }
-        // Try to connect with retry
-        let mut retries = 5;
-        let stream = loop {
-            match TcpStream::connect(&node).await {
-                Ok(s) => break s,
-                Err(e) => {
-                    retries -= 1;
-                    if retries == 0 {
-                        println!("  - failed to connect to {} after 3 attempts: {}", node, e);
-                        continue 'node_loop;
+        let stream = match crate::peer::dial_with_retry(addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to connect to {}: {}", node, e);
+                continue 'node_loop;
+            }
+        };
+
+        match discover(stream, &node, port).await {
+            Ok((peer, child_nodes)) => {
+                debug!("received NodeList from {}", node);
+                if let Some(node_id) = peer.node_id {
+                    DIALED_IDS.insert(node.clone(), node_id);
+                }
+                add_peer(peer);
+                for child_node in child_nodes {
+                    if crate::NODES.contains_key(&child_node) {
+                        continue;
+                    }
+                    let addr = match resolve_peer(&child_node).await {
+                        Ok(addr) if !is_self_addr(addr, port) => addr,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            warn!("skipping peer {}: {}", child_node, e);
+                            continue;
+                        }
+                    };
+                    info!("adding node {}", child_node);
+                    let stream = match crate::peer::dial(addr).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("failed to connect to {}: {}", child_node, e);
+                            continue;
+                        }
+                    };
+                    match discover(stream, &child_node, port).await {
+                        Ok((peer, _)) => {
+                            add_peer(peer);
+                        }
+                        Err(e) => warn!("discovery with {} failed: {}", child_node, e),
                     }
-                    println!(
-                        "  - connection failed, retrying... ({} attempts left)",
-                        retries
-                    );
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                 }
             }
+            Err(e) => {
+                warn!("discovery with {} failed: {}", node, e);
+            }
+        }
+    }
+}
+
+/// Introduce ourselves again to every peer we are connected to. Peers we
+/// dialed before we were listening couldn't connect back to us then, so
+/// they would never relay their blocks to us.
+pub async fn announce(port: u16) {
+    let message = Message::DiscoverNodes {
+        port,
+        node_id: *crate::NODE_ID,
+    };
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+    for node in nodes {
+        let Some(stream) = crate::peer::stream_of(&node) else {
+            continue;
         };
+        let mut stream = stream.lock().await;
+        let answered = match message.send_async(&mut *stream).await {
+            Ok(()) => Message::receive_async(&mut *stream).await.is_ok(),
+            Err(_) => false,
+        };
+        if !answered {
+            warn!("failed to announce ourselves to {}", node);
+        }
+    }
+}
+
+/// Parse a peer entry of the form `host:port`, `1.2.3.4:port` or
+/// `[::1]:port`, resolving hostnames, into the first address it names
+pub async fn resolve_peer(entry: &str) -> Result<SocketAddr> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        if addr.port() == 0 {
+            return Err(anyhow!("port 0 in peer address {}", entry));
+        }
+        return Ok(addr);
+    }
+    // anything else must be a hostname with a port
+    let (host, port) = entry
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("peer address {} has no port", entry))?;
+    if host.is_empty() || host.contains(':') {
+        return Err(anyhow!("invalid peer address {}", entry));
+    }
+    let port: u16 = port
+        .parse()
+        .ok()
+        .filter(|&port| port != 0)
+        .ok_or_else(|| anyhow!("invalid port in peer address {}", entry))?;
+    lookup_host((host, port))
+        .await
+        .with_context(|| format!("could not resolve {}", host))?
+        .next()
+        .ok_or_else(|| anyhow!("{} resolved to no addresses", host))
+}
+
+/// Whether `addr` is this node listening on `port`
+fn is_self_addr(addr: SocketAddr, port: u16) -> bool {
+    addr.port() == port && (addr.ip().is_loopback() || addr.ip().is_unspecified())
+}
+
+/// Introduce ourselves over `stream` and learn the node id and peers of
+/// the node listening on `addr`
+async fn discover(mut stream: TcpStream, addr: &str, port: u16) -> Result<(Peer, Vec<String>)> {
+    let message = Message::DiscoverNodes {
+        port,
+        node_id: *crate::NODE_ID,
+    };
+    message.send_async(&mut stream).await?;
+    debug!("sent DiscoverNodes to {}", addr);
+    match Message::receive_async(&mut stream).await? {
+        Message::NodeList { node_id, nodes } => {
+            let mut peer = Peer::new(addr.to_string(), stream);
+            peer.node_id = Some(node_id);
+            peer.verify_identity().await?;
+            Ok((peer, nodes))
+        }
+        _ => Err(anyhow::anyhow!("unexpected message from {}", addr)),
+    }
+}
+
+/// Whether a peer with this node id is already connected
+pub fn is_known_node(node_id: Uuid) -> bool {
+    crate::NODES
+        .iter()
+        .any(|peer| peer.node_id == Some(node_id))
+}
 
-        let mut stream = stream;
-        let message = Message::DiscoverNodes(port);
-        if let Err(e) = message.send_async(&mut stream).await {
-            println!("  - failed to send DiscoverNodes to {}: {}", node, e);
+/// Keep a discovered peer unless it is ourselves, a node we already
+/// know under another address, or hasn't proven its node key. Returns
+/// whether it was added.
+fn add_peer(peer: Peer) -> bool {
+    let (Some(node_id), Some(_)) = (peer.node_id, &peer.public_key) else {
+        return false;
+    };
+    if node_id == *crate::NODE_ID {
+        debug!("skipping self ({})", peer.listen_addr);
+        return false;
+    }
+    if is_known_node(node_id) {
+        debug!(
+            "skipping {}, already connected to that node",
+            peer.listen_addr
+        );
+        return false;
+    }
+    crate::NODES.insert(peer.listen_addr.clone(), peer);
+    true
+}
+
+/// Send `message` to every peer except `except`, returning how many got
+/// it. A peer whose stream fails is redialed and sent the message again;
+/// it is only dropped when that fails too.
+pub async fn broadcast(message: &Message, except: Option<&str>) -> usize {
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .filter(|node| Some(node.as_str()) != except)
+        .collect::<Vec<_>>();
+    let mut delivered = 0;
+    for node in nodes {
+        let Some(stream) = crate::peer::stream_of(&node) else {
+            continue;
+        };
+        let mut stream = stream.lock().await;
+        if message.send_async(&mut *stream).await.is_ok() {
+            delivered += 1;
+            continue;
+        }
+        debug!("send to {} failed, redialing", node);
+        if crate::peer::redial(&node, &mut stream).await.is_ok()
+            && message.send_async(&mut *stream).await.is_ok()
+        {
+            delivered += 1;
             continue;
         }
-        println!("sent DiscoverNodes to {}", node);
+        drop(stream);
+        crate::NODES.remove(&node);
+        warn!("dropped unreachable peer {}", node);
+    }
+    delivered
+}
 
-        let message = match Message::receive_async(&mut stream).await {
-            Ok(m) => m,
-            Err(e) => {
-                println!("  - failed to receive response from {}: {}", node, e);
+/// Ask every peer for its pending transactions so we don't miss the
+/// ones broadcast before we joined. Each goes through `add_to_mempool`.
+pub async fn sync_mempool() {
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+    for node in nodes {
+        let Some(stream) = crate::peer::stream_of(&node) else {
+            continue;
+        };
+        let mut stream = stream.lock().await;
+        if Message::GetMempool.send_async(&mut *stream).await.is_err() {
+            warn!("failed to ask {} for its mempool", node);
+            continue;
+        }
+        let transactions = match Message::receive_async(&mut *stream).await {
+            Ok(Message::Mempool(transactions)) => transactions,
+            _ => {
+                warn!("unexpected mempool response from {}", node);
                 continue;
             }
         };
-        match message {
-            Message::NodeList(child_nodes) => {
-                println!("received NodeList from {}", node);
-                for child_node in child_nodes {
-                    println!("adding node {}", child_node);
-
-                    let new_stream = TcpStream::connect(&child_node).await?;
-                    crate::NODES.insert(child_node, new_stream);
-                }
-            }
-            _ => {
-                println!("unexpected message from {}", node);
+        drop(stream);
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        let mut added = 0;
+        for tx in transactions {
+            crate::seen::mark_seen(tx.hash());
+            if blockchain.add_to_mempool(tx).is_ok() {
+                added += 1;
             }
         }
-        crate::NODES.insert(node.clone(), stream);
+        info!("synced {} mempool transactions from {}", added, node);
     }
-    Ok(())
 }
 
 pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!("finding nodes with the highest blockchainlength...");
+    info!("finding nodes with the highest blockchainlength...");
     let mut longest_name = String::new();
     let mut longest_count = 0;
     let all_nodes = crate::NODES
@@ -151,17 +388,18 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
         .map(|x| x.key().clone())
         .collect::<Vec<_>>();
     for node in all_nodes {
-        println!("asking {} for blockchain length", node);
-        let mut stream = crate::NODES.get_mut(&node).context("no node")?;
+        debug!("asking {} for blockchain length", node);
+        let stream = crate::peer::stream_of(&node).context("no node")?;
+        let mut stream = stream.lock().await;
         let message = Message::AskDifference(0);
         message.send_async(&mut *stream).await.unwrap();
-        println!("sent AskDifference to {}", node);
+        debug!("sent AskDifference to {}", node);
         let message = Message::receive_async(&mut *stream).await?;
         match message {
             Message::Difference(count) => {
-                println!("received Difference from {}", node);
+                debug!("received Difference from {}", node);
                 if count > longest_count {
-                    println!(
+                    info!(
                         "new longest blockchain: \
 {} blocks from {node}",
                         count
@@ -171,48 +409,677 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
                 }
             }
             e => {
-                println!("unexpected message from {}: {:?}", node, e);
+                warn!("unexpected message from {}: {:?}", node, e);
             }
         }
     }
     Ok((longest_name, longest_count as u32))
 }
 
-// TODO :: immplement a better to download the blockchains (with one message to feetch the whole blockchain ) rnd (using multiple connections and parallel downloads)
+/// Replace our chain with a snapshot from `node` instead of downloading
+/// and replaying every block. The UTXO set is trusted as sent.
+pub async fn download_snapshot(node: &str) -> Result<()> {
+    let stream = crate::peer::stream_of(node).context("no node")?;
+    let mut stream = stream.lock().await;
+    Message::FetchSnapshot.send_async(&mut *stream).await?;
+    let snapshot = match Message::receive_async(&mut *stream).await? {
+        Message::Snapshot(snapshot) => snapshot,
+        e => return Err(anyhow!("unexpected message from {}: {:?}", node, e)),
+    };
+    drop(stream);
+    let height = snapshot.height;
+    crate::BLOCKCHAIN.write().await.import_snapshot(snapshot)?;
+    info!("imported snapshot at height {} from {}", height, node);
+    Ok(())
+}
+
+/// Fetch the first `count` blocks, spreading `MAX_CHAIN_SEGMENT` sized
+/// chunks over every known peer. Chunks are fetched concurrently and
+/// applied in height order once they have all arrived. With a single
+/// peer this is the sequential download from `node`.
 pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
-    let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
+    let mut peers: Vec<String> = crate::NODES.iter().map(|x| x.key().clone()).collect();
+    if peers.len() < 2 {
+        return download_from_peer(node, count).await;
+    }
+    // the node with the longest chain takes the first chunk
+    peers.retain(|peer| peer != node);
+    peers.insert(0, node.to_string());
+
+    let count = count as u64;
+    let tasks: Vec<_> = (0..count)
+        .step_by(MAX_CHAIN_SEGMENT as usize)
+        .enumerate()
+        .map(|(i, from)| {
+            let to = (from + MAX_CHAIN_SEGMENT).min(count);
+            let peers = peers.clone();
+            tokio::spawn(async move { fetch_segment(&peers, i, from, to).await })
+        })
+        .collect();
+    info!(
+        "downloading {} blocks in {} chunks from {} peers",
+        count,
+        tasks.len(),
+        peers.len()
+    );
+    let mut segments = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        segments.push(task.await??);
+    }
+
+    // a peer whose segment doesn't move our tip is dropped and the rest
+    // of its segment fetched from the others
+    for (from, (mut node, mut blocks)) in
+        (0..count).step_by(MAX_CHAIN_SEGMENT as usize).zip(segments)
+    {
+        let to = (from + MAX_CHAIN_SEGMENT).min(count);
+        loop {
+            let applied = apply_segment(&mut *crate::BLOCKCHAIN.write().await, blocks, to);
+            let Err(e) = applied else {
+                break;
+            };
+            warn!("dropping {} from the download: {}", node, e);
+            peers.retain(|peer| *peer != node);
+            let height = crate::BLOCKCHAIN.read().await.block_height();
+            (node, blocks) = fetch_segment(&peers, 0, height, to).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Add a segment ending at height `to` to the chain. Fails if the blocks
+/// are invalid or don't bring the tip up to `to`, e.g. when they were
+/// stashed as orphans because they don't extend it.
+fn apply_segment(blockchain: &mut Blockchain, blocks: Vec<Block>, to: u64) -> Result<()> {
+    for block in blocks {
+        blockchain.add_block(block)?;
+    }
+    if blockchain.block_height() < to {
+        return Err(anyhow!(
+            "its blocks left our tip at height {} instead of {}",
+            blockchain.block_height(),
+            to
+        ));
+    }
+    Ok(())
+}
+
+/// Fetch blocks `from..to` over a fresh connection, starting with peer
+/// `first` and moving on to the next one if a peer fails or comes back
+/// with fewer blocks than asked for. Returns the peer that served them.
+async fn fetch_segment(
+    peers: &[String],
+    first: usize,
+    from: u64,
+    to: u64,
+) -> Result<(String, Vec<Block>)> {
+    for attempt in 0..peers.len() {
+        let node = &peers[(first + attempt) % peers.len()];
+        match request_segment(node, from, to).await {
+            Ok(blocks) if blocks.len() as u64 == to - from => return Ok((node.clone(), blocks)),
+            Ok(blocks) => warn!(
+                "{} sent {} of the blocks {}..{}",
+                node,
+                blocks.len(),
+                from,
+                to
+            ),
+            Err(e) => warn!(
+                "failed to fetch blocks {}..{} from {}: {}",
+                from, to, node, e
+            ),
+        }
+    }
+    Err(anyhow!("no peer could serve blocks {}..{}", from, to))
+}
+
+async fn request_segment(node: &str, from: u64, to: u64) -> Result<Vec<Block>> {
+    let mut stream = crate::peer::dial(node).await?;
+    Message::FetchChain { from, to }
+        .send_async(&mut stream)
+        .await?;
+    match Message::receive_async(&mut stream).await? {
+        Message::ChainSegment(blocks) => Ok(blocks),
+        _ => Err(anyhow!("unexpected message")),
+    }
+}
+
+/// Switch to the branch ending at the orphan `tip` if it connects to our
+/// chain and is longer. Returns whether the tip moved.
+pub fn adopt_orphan_branch(blockchain: &mut Blockchain, tip: &Hash) -> bool {
+    let Some(branch) = blockchain.orphan_branch(tip) else {
+        return false;
+    };
+    let old_tip = blockchain.blocks().last().map(Block::id);
+    match blockchain.consider_fork(branch) {
+        Ok(returned) if blockchain.blocks().last().map(Block::id) != old_tip => {
+            info!(
+                "switched to a longer branch, height {}, {} transactions back in the mempool",
+                blockchain.block_height(),
+                returned.len()
+            );
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            warn!("rejected the branch ending at {}: {}", tip, e);
+            false
+        }
+    }
+}
+
+/// Catch up with `node` after it sent a block whose ancestors we don't
+/// have: fetch the last `MAX_CHAIN_SEGMENT` blocks of its chain and
+/// extend ours with them, or switch to them if they branch off it and
+/// are longer
+pub async fn sync_with_peer(node: String) -> Result<()> {
+    let mut stream = crate::peer::dial(&node).await?;
+    Message::FetchBlockHeight.send_async(&mut stream).await?;
+    let height = match Message::receive_async(&mut stream).await? {
+        Message::BlockHeight(height) => height,
+        e => return Err(anyhow!("unexpected message from {}: {:?}", node, e)),
+    };
+    let blocks = request_segment(&node, height.saturating_sub(MAX_CHAIN_SEGMENT), height).await?;
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    let branch: Vec<Block> = blocks
+        .into_iter()
+        .skip_while(|block| blockchain.contains_block(&block.id()))
+        .collect();
+    let Some(first) = branch.first() else {
+        return Ok(());
+    };
+    if Some(first.header.prev_block_hash) == blockchain.blocks().last().map(Block::id) {
+        for block in branch {
+            blockchain.add_block(block)?;
+        }
+        info!(
+            "caught up with {}, height {}",
+            node,
+            blockchain.block_height()
+        );
+    } else {
+        let returned = blockchain.consider_fork(branch)?;
+        info!(
+            "synced with {}, height {}, {} transactions back in the mempool",
+            node,
+            blockchain.block_height(),
+            returned.len()
+        );
+    }
+    Ok(())
+}
+
+/// Sequential download of the first `count` blocks from `node`
+async fn download_from_peer(node: &str, count: u32) -> Result<()> {
+    let stream =
+        crate::peer::stream_of(node).with_context(|| format!("{} is not connected", node))?;
+    let mut stream = stream.lock().await;
+    let count = count as u64;
+    let mut next = 0;
+    while next < count {
+        let to = (next + MAX_CHAIN_SEGMENT).min(count);
+        let message = Message::FetchChain { from: next, to };
         message.send_async(&mut *stream).await?;
-        let message = Message::receive_async(&mut *stream).await?;
+        let blocks = match Message::receive_async(&mut *stream).await {
+            Ok(Message::ChainSegment(blocks)) if !blocks.is_empty() => blocks,
+            _ => {
+                // older peers drop the connection on messages they don't know
+                info!(
+                    "{} can't serve chain segments, fetching block by block",
+                    node
+                );
+                crate::peer::redial(node, &mut stream).await?;
+                return download_blocks(&mut stream, node, next, count).await;
+            }
+        };
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        let to = blockchain.block_height() + blocks.len() as u64;
+        apply_segment(&mut blockchain, blocks, to)
+            .with_context(|| format!("bad chain segment from {}", node))?;
+        next = blockchain.block_height();
+    }
+    Ok(())
+}
+
+/// One FetchBlock round trip per block in `from..to`
+async fn download_blocks(stream: &mut TcpStream, node: &str, from: u64, to: u64) -> Result<()> {
+    for i in from as usize..to as usize {
+        let message = Message::FetchBlock(i);
+        message.send_async(stream).await?;
+        let message = Message::receive_async(stream).await?;
         match message {
             Message::NewBlock(block) => {
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
                 blockchain.add_block(block)?;
             }
+            // the peer's chain is shorter than it claimed, or pruned
+            Message::BlockNotFound(height) => {
+                info!("{} has no block at height {}, stopping", node, height);
+                break;
+            }
             _ => {
-                println!("unexpected message from {}", node);
+                warn!("unexpected message from {}", node);
             }
         }
     }
     Ok(())
 }
 
+/// How often every peer is pinged
+const PING_INTERVAL_SECS: u64 = 30;
+/// How long a peer has to answer a Ping
+const PING_TIMEOUT_SECS: u64 = 5;
+
+/// Send a Ping and wait for the Pong
+async fn ping(stream: &mut TcpStream) -> bool {
+    if Message::Ping.send_async(stream).await.is_err() {
+        return false;
+    }
+    let reply = time::timeout(
+        time::Duration::from_secs(PING_TIMEOUT_SECS),
+        Message::receive_async(stream),
+    )
+    .await;
+    matches!(reply, Ok(Ok(Message::Pong)))
+}
+
+/// Periodically ping every peer. A peer that doesn't answer gets one
+/// redial attempt before it is evicted from NODES. Seed nodes we are no
+/// longer connected to are dialed again. The first round comes one
+/// interval in, `populate_connections` dials the seeds at startup.
+pub async fn maintain_peers(seeds: Vec<String>, port: u16) {
+    let period = time::Duration::from_secs(PING_INTERVAL_SECS);
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        ping_peers().await;
+        info!("{} healthy peers", healthy_peer_count());
+
+        let missing = seeds
+            .iter()
+            .filter(|seed| !is_connected(seed))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            tokio::spawn(populate_connections(missing, port));
+        }
+    }
+}
+
+/// One round of `maintain_peers`: ping every peer, redialing the ones
+/// that don't answer once, and evict those that still don't
+async fn ping_peers() {
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+    for node in nodes {
+        let Some(stream) = crate::peer::stream_of(&node) else {
+            continue;
+        };
+        let mut stream = stream.lock().await;
+        let alive = ping(&mut stream).await
+            || (crate::peer::redial(&node, &mut stream).await.is_ok() && ping(&mut stream).await);
+        drop(stream);
+        if alive {
+            crate::peer::touch(&node);
+            continue;
+        }
+        crate::NODES.remove(&node);
+        warn!("evicted unresponsive peer {}", node);
+    }
+}
+
+/// Number of peers that answered within the last two ping intervals
+pub fn healthy_peer_count() -> usize {
+    let window = time::Duration::from_secs(2 * PING_INTERVAL_SECS);
+    crate::NODES
+        .iter()
+        .filter(|peer| peer.last_seen.elapsed() <= window)
+        .count()
+}
+
 pub async fn cleanup() {
     let mut interval = time::interval(time::Duration::from_secs(30));
     loop {
         interval.tick().await;
-        println!("cleaning the mempool from old transactions");
+        debug!("cleaning the mempool from old transactions");
         let mut blockchain = crate::BLOCKCHAIN.write().await;
         blockchain.clean_mempool();
     }
 }
-pub async fn save(name: String) {
+pub async fn save(data_dir: PathBuf, name: String) {
     let mut interval = time::interval(time::Duration::from_secs(15));
     loop {
         interval.tick().await;
-        println!("saving blockchain to drive...");
-        let blockchain = crate::BLOCKCHAIN.read().await;
-        blockchain.save_to_file(name.clone()).unwrap();
+        debug!("saving blockchain to drive...");
+        if let Err(e) = flush(&data_dir, &name).await {
+            error!("failed to save state: {}", e);
+        }
+    }
+}
+
+/// Whether `flush` writes the blockchain file zstd-compressed
+static COMPRESS_CHAIN: AtomicBool = AtomicBool::new(false);
+
+/// Compress the blockchain file from the next save on. Either format
+/// loads, so this can change between runs.
+pub fn set_compress_chain(compress: bool) {
+    COMPRESS_CHAIN.store(compress, Ordering::Relaxed);
+}
+
+/// Write the blockchain and its mempool sidecar to disk. The read lock is
+/// held across both writes so they describe the same state.
+pub async fn flush(data_dir: &Path, name: &str) -> Result<()> {
+    let name = data_dir.join(name);
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let saved = if COMPRESS_CHAIN.load(Ordering::Relaxed) {
+        blockchain.save_to_file_compressed(&name)
+    } else {
+        blockchain.save_to_file(&name)
+    };
+    saved.with_context(|| format!("failed to save blockchain to {}", name.display()))?;
+    write_atomically(mempool_file(&name), |file| blockchain.save_mempool(file))
+        .context("failed to save mempool")?;
+    Ok(())
+}
+
+/// Hand every connection on `listener` to the handler until `shutdown`
+/// completes, then stop listening and flush the state to disk
+pub async fn serve_until(
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+    data_dir: &Path,
+    name: &str,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, _) = accepted?;
+                tokio::spawn(crate::handler::handle_connection(socket));
+            }
+            _ = &mut shutdown => {
+                info!("shutting down, saving state...");
+                break;
+            }
+        }
+    }
+    // stop accepting before the final flush so nothing lands after it
+    drop(listener);
+    flush(data_dir, name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poslib::network::PROTOCOL_VERSION;
+    use poslib::types::fixtures::*;
+    use std::collections::HashSet;
+    use tokio::task::JoinHandle;
+
+    /// Held by the tests that change `BLOCKCHAIN` or `NODES`, which the
+    /// whole test binary shares
+    #[dynamic]
+    static GLOBALS: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+
+    /// A peer on a free port that takes connections one at a time,
+    /// answers the version handshake for whatever genesis we have, then
+    /// each message with `answer` until that returns `None`
+    async fn fake_peer<F>(mut answer: F) -> (String, JoinHandle<()>)
+    where
+        F: FnMut(Message) -> Option<Message> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                while let Ok(message) = Message::receive_async(&mut stream).await {
+                    let reply = match message {
+                        Message::Version { genesis_hash, .. } => Message::Version {
+                            protocol_version: PROTOCOL_VERSION,
+                            genesis_hash,
+                        },
+                        message => match answer(message) {
+                            Some(reply) => reply,
+                            None => break,
+                        },
+                    };
+                    if reply.send_async(&mut stream).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        (addr, task)
+    }
+
+    /// Put the peer at `addr` in `NODES`, as if discovery had run
+    async fn add_connected(addr: &str) {
+        let stream = crate::peer::dial(addr).await.unwrap();
+        crate::NODES.insert(addr.to_string(), Peer::new(addr.to_string(), stream));
+    }
+
+    #[tokio::test]
+    async fn peer_addresses_are_resolved() {
+        let v4 = resolve_peer("127.0.0.1:9000").await.unwrap();
+        assert_eq!(v4, SocketAddr::from(([127, 0, 0, 1], 9000)));
+        let v6 = resolve_peer(" [::1]:9000 ").await.unwrap();
+        assert_eq!(v6, "[::1]:9000".parse().unwrap());
+        let host = resolve_peer("localhost:9000").await.unwrap();
+        assert!(host.ip().is_loopback());
+        assert_eq!(host.port(), 9000);
+    }
+
+    #[tokio::test]
+    async fn malformed_peer_addresses_are_rejected() {
+        for entry in [
+            "127.0.0.1:0",
+            "[::1]:0",
+            "localhost:0",
+            "localhost",
+            "::1",
+            ":9000",
+            "localhost:port",
+        ] {
+            assert!(resolve_peer(entry).await.is_err(), "{entry} was accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_peer_is_evicted_in_one_round() {
+        let _globals = GLOBALS.lock().await;
+        crate::NODES.clear();
+        let (live, _task) = fake_peer(|message| match message {
+            Message::Ping => Some(Message::Pong),
+            _ => None,
+        })
+        .await;
+        let (dead, task) = fake_peer(|_| None).await;
+        add_connected(&live).await;
+        add_connected(&dead).await;
+        // the stream and the listener go away, so the redial fails too
+        task.abort();
+        let _ = task.await;
+
+        ping_peers().await;
+        assert!(crate::NODES.contains_key(&live));
+        assert!(!crate::NODES.contains_key(&dead));
+        assert_eq!(healthy_peer_count(), 1);
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn same_node_under_two_addresses_is_kept_once() {
+        let _globals = GLOBALS.lock().await;
+        crate::NODES.clear();
+        /// Answers discovery as `node_id`, signing challenges with `key`
+        fn node(node_id: Uuid, key: PrivateKey) -> impl FnMut(Message) -> Option<Message> {
+            move |message| match message {
+                Message::DiscoverNodes { .. } => Some(Message::NodeList {
+                    node_id,
+                    nodes: vec![],
+                }),
+                Message::Challenge(nonce) => Some(Message::ChallengeResponse {
+                    public_key: key.public_key(),
+                    signature: Signature::sign_output(&nonce, &key),
+                }),
+                _ => None,
+            }
+        }
+        let (node_id, key) = (Uuid::new_v4(), PrivateKey::new_key());
+        let (first, _first) = fake_peer(node(node_id, key.clone())).await;
+        let (second, _second) = fake_peer(node(node_id, key)).await;
+        // and one that turns out to be ourselves
+        let (us, _us) = fake_peer(node(*crate::NODE_ID, PrivateKey::new_key())).await;
+
+        connect_to_peers(vec![first.clone(), second.clone(), us.clone()], 0).await;
+        assert_eq!(crate::NODES.len(), 1);
+        assert!(crate::NODES.contains_key(&first));
+        // not dialed again later either
+        assert!(is_connected(&second));
+        assert!(!is_connected(&us));
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn long_chain_downloads_in_segments() {
+        let _globals = GLOBALS.lock().await;
+        crate::NODES.clear();
+        // one full segment and a partial one
+        let height = MAX_CHAIN_SEGMENT + 100;
+        let mut source = TestChain::new(&[1000 * COIN]);
+        for _ in 1..height {
+            source.extend(vec![]);
+        }
+        let blocks: Vec<Block> = source.chain.blocks().cloned().collect();
+        let requested = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let (peer, _task) = fake_peer({
+            let requested = requested.clone();
+            move |message| match message {
+                Message::FetchChain { from, to } => {
+                    requested.lock().unwrap().push((from, to));
+                    Some(Message::ChainSegment(
+                        blocks[from as usize..to as usize].to_vec(),
+                    ))
+                }
+                _ => None,
+            }
+        })
+        .await;
+        *crate::BLOCKCHAIN.write().await = Blockchain::with_params(source.chain.params().clone());
+        add_connected(&peer).await;
+
+        download_blockchain(&peer, height as u32).await.unwrap();
+        let synced = crate::BLOCKCHAIN.read().await;
+        assert_eq!(synced.block_height(), height);
+        assert_eq!(
+            synced.blocks().last().map(Block::id),
+            source.chain.blocks().last().map(Block::id)
+        );
+        assert_eq!(
+            *requested.lock().unwrap(),
+            [(0, MAX_CHAIN_SEGMENT), (MAX_CHAIN_SEGMENT, height)]
+        );
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn new_peer_gets_the_pending_transactions() {
+        let _globals = GLOBALS.lock().await;
+        crate::NODES.clear();
+        let test = TestChain::new(&[1000 * COIN; 3]);
+        let mut node_a = test.chain.clone();
+        for index in 0..3 {
+            let owner = &test.validators[index];
+            let payment = spend(
+                owner,
+                &[test.allocation_of(index)],
+                &owner.public_key(),
+                COIN,
+                1000,
+            );
+            node_a.add_to_mempool(payment).unwrap();
+        }
+
+        // node A answers GetMempool through its handler
+        *crate::BLOCKCHAIN.write().await = node_a.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            crate::handler::handle_connection(socket).await;
+        });
+        let mut stream = crate::peer::dial(addr).await.unwrap();
+        Message::GetMempool.send_async(&mut stream).await.unwrap();
+        let Message::Mempool(pending) = Message::receive_async(&mut stream).await.unwrap() else {
+            panic!("expected the mempool");
+        };
+        assert_eq!(pending.len(), 3);
+
+        // node B has the same blocks and asks its peers once connected
+        *crate::BLOCKCHAIN.write().await = test.chain.clone();
+        let (peer, _task) = fake_peer(move |message| match message {
+            Message::GetMempool => Some(Message::Mempool(pending.clone())),
+            _ => None,
+        })
+        .await;
+        add_connected(&peer).await;
+        sync_mempool().await;
+        let node_b = crate::BLOCKCHAIN.read().await;
+        let hashes = |chain: &Blockchain| -> HashSet<Hash> {
+            chain.mempool().iter().map(|(_, tx)| tx.hash()).collect()
+        };
+        assert_eq!(hashes(&node_b), hashes(&node_a));
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_the_state() {
+        let _globals = GLOBALS.lock().await;
+        let mut test = TestChain::new(&[1000 * COIN]);
+        for _ in 0..5 {
+            test.extend(vec![]);
+        }
+        let owner = &test.validators[0];
+        let payment = spend(
+            owner,
+            &[test.allocation_of(0)],
+            &owner.public_key(),
+            COIN,
+            1000,
+        );
+        test.chain.add_to_mempool(payment.clone()).unwrap();
+        *crate::BLOCKCHAIN.write().await = test.chain.clone();
+
+        let dir = std::env::temp_dir().join(format!("shutdown-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let dir = dir.clone();
+            async move {
+                let shutdown = async {
+                    let _ = stopped.await;
+                };
+                serve_until(listener, shutdown, &dir, "blockchain.cbor").await
+            }
+        });
+        crate::peer::dial(addr).await.unwrap();
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let refused = TcpStream::connect(addr).await;
+        let mut saved = Blockchain::load_from_file(dir.join("blockchain.cbor")).unwrap();
+        saved.rebuild_utxos();
+        let mempool = File::open(mempool_file(&dir.join("blockchain.cbor"))).unwrap();
+        let restored = saved.load_mempool(mempool).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(refused.is_err());
+        assert_eq!(saved.block_height(), test.chain.block_height());
+        assert_eq!(restored, 1);
+        assert_eq!(saved.mempool()[0].1.hash(), payment.hash());
     }
 }
@@ -1,30 +1,78 @@
 //! Command-line interface definition for the validator
 
-use clap::{Parser, arg, command};
+use clap::Parser;
+use std::net::{IpAddr, Ipv4Addr};
 
 #[derive(Parser)]
 #[command(
-    author, 
-    version, 
-    about = "Proof of Stake Validator - Full Node + Block Proposer", 
+    author,
+    version,
+    about = "Proof of Stake Validator - Full Node + Block Proposer",
     long_about = None
 )]
 pub struct Cli {
     /// Port to listen on for incoming connections
     #[arg(long, default_value = "9001")]
     pub port: u16,
-    
+
+    /// Address to listen on, e.g. 127.0.0.1 to accept local connections only
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub bind: IpAddr,
+
     /// Path to the validator's private key file
     #[arg(short, long)]
     pub private_key_file: String,
-    
+
+    /// Path to the public key our stake is registered under; startup fails
+    /// if the private key doesn't belong to it
+    #[arg(long)]
+    pub public_key_file: Option<String>,
+
     /// Path to the local blockchain file
     #[arg(short, long, default_value = "validator_blockchain.cbor")]
     pub blockchain_file: String,
-    
+
     /// Addresses of peer nodes to connect to (comma-separated, e.g. "127.0.0.1:9001,127.0.0.1:9002")
     #[arg(short, long, default_value = "")]
     pub nodes: String,
+
+    /// Among equal-fee transactions, include those from higher-stake senders first
+    #[arg(long)]
+    pub prioritize_stake: bool,
+
+    /// Maximum transactions to include per block (clamped to the consensus limit)
+    #[arg(long)]
+    pub max_block_transactions: Option<usize>,
+
+    /// Seconds between two slot checks. Raised to the consensus minimum
+    /// block interval if set lower
+    #[arg(long, default_value = "10")]
+    pub block_interval: u64,
+
+    /// Seconds a transaction may wait in the mempool before it is dropped
+    #[arg(long, default_value_t = poslib::protocol::MAX_MEMPOOL_TRANSACTION_AGE)]
+    pub mempool_max_age: u64,
+
+    /// Save the blockchain file zstd-compressed (either format loads)
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Seconds to wait for a peer to accept a connection
+    #[arg(long, default_value_t = node_lib::peer::DEFAULT_CONNECT_TIMEOUT)]
+    pub connect_timeout: u64,
+
+    /// Attempts to connect to each peer given with --nodes
+    #[arg(long, default_value_t = node_lib::peer::DEFAULT_CONNECT_RETRIES)]
+    pub connect_retries: u32,
+
+    /// Seconds before retrying a failed connection, doubled every attempt
+    #[arg(long, default_value_t = node_lib::peer::DEFAULT_RETRY_DELAY)]
+    pub retry_delay: u64,
+
+    /// Print the blocks this validator produced and the rewards it earned
+    /// according to the blockchain file, then exit
+    #[arg(long)]
+    pub stats: bool,
 }
 
 impl Cli {
@@ -12,21 +12,23 @@ mod cli;
 mod proposer;
 
 use anyhow::{Result, anyhow};
+use chrono::Utc;
 use clap::Parser;
+use node_lib::genesis::GenesisConfig;
 use node_lib::{BLOCKCHAIN, NODES, handler, util};
-use poslib::crypto::PrivateKey;
+use poslib::crypto::{PrivateKey, PublicKey};
 use poslib::types::Blockchain;
 use poslib::util::Saveable;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio::net::TcpListener;
 use tokio::time::{Duration, interval};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 use crate::cli::Cli;
 use crate::proposer::BlockProposer;
 
-/// Slot duration for validator checks (seconds)
-const SLOT_DURATION_SECS: u64 = 10;
-
 fn print_banner() {
     println!("═══════════════════════════════════════════════");
     println!("    Proof of Stake Validator v0.3.0           ");
@@ -38,6 +40,12 @@ fn print_banner() {
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    // Log level is taken from RUST_LOG, defaulting to info
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
     print_banner();
 
@@ -50,58 +58,97 @@ async fn main() -> Result<()> {
         )
     })?;
 
+    if let Some(public_key_file) = &cli.public_key_file {
+        let expected = PublicKey::load_from_file(public_key_file)
+            .map_err(|e| anyhow!("Error reading public key from '{}': {}", public_key_file, e))?;
+        private_key.ensure_matches(&expected).map_err(|_| {
+            anyhow!(
+                "Private key '{}' does not belong to public key '{}': this validator would never be selected",
+                cli.private_key_file,
+                public_key_file
+            )
+        })?;
+    }
+
     let public_key = private_key.public_key();
-    println!("🔑 Validator public key: {:?}", public_key);
+    info!("🔑 Validator public key: {}", public_key);
+
+    if cli.stats {
+        let blockchain = Blockchain::load_from_file(&cli.blockchain_file).map_err(|e| {
+            anyhow!(
+                "Error reading blockchain from '{}': {}",
+                cli.blockchain_file,
+                e
+            )
+        })?;
+        let (blocks, rewards) = blockchain
+            .validator_stats()
+            .get(&public_key)
+            .copied()
+            .unwrap_or_default();
+        println!("Blocks produced: {}", blocks);
+        println!("Rewards earned: {} satoshis", rewards);
+        println!("Chain height: {}", blockchain.block_height());
+        return Ok(());
+    }
 
     // =========================================================================
     // REUSE NODE INITIALIZATION (from node_lib)
     // =========================================================================
 
     let nodes = cli.get_nodes();
-
-    // Connect to peer nodes
-    println!("📡 Connected to {} peer nodes", NODES.len());
+    util::set_compress_chain(cli.compress);
+    node_lib::peer::set_connect_policy(cli.connect_timeout, cli.connect_retries, cli.retry_delay);
+    node_lib::peer::load_identity(Path::new("."))?;
 
     // Load or initialize blockchain
     if Path::new(&cli.blockchain_file).exists() {
-        println!("📂 Loading blockchain from: {}", cli.blockchain_file);
-        util::load_blockchain(&cli.blockchain_file).await?;
+        info!("📂 Loading blockchain from: {}", cli.blockchain_file);
+        util::load_blockchain(Path::new("."), &cli.blockchain_file).await?;
     } else {
-        println!("📂 No blockchain found, syncing from network...");
+        info!("📂 No blockchain found, syncing from network...");
         if nodes.is_empty() {
-            println!("🌱 No peers provided, creating genesis block as seed validator");
-            let genesis_block = util::create_genesis_block();
+            info!("🌱 No peers provided, creating genesis block as seed validator");
+            let genesis_block =
+                util::create_genesis_block(Path::new("."), &GenesisConfig::default());
             let mut blockchain = BLOCKCHAIN.write().await;
             blockchain
                 .add_block(genesis_block)
                 .expect("Failed to add genesis block");
-            // Rebuild UTXOs after creating genesis block
-            blockchain.rebuild_utxos();
         } else {
+            // Connect to peer nodes first, there is nothing to sync from otherwise
+            util::connect_to_peers(nodes.clone(), cli.port).await;
+            info!("📡 Connected to {} peer nodes", NODES.len());
+            if NODES.is_empty() {
+                return Err(anyhow!("none of the peers {:?} could be reached", nodes));
+            }
             let (longest_name, longest_count) = util::find_longest_chain_node().await?;
             util::download_blockchain(&longest_name, longest_count).await?;
-            println!("✅ Downloaded blockchain from {}", longest_name);
-
-            let mut blockchain = BLOCKCHAIN.write().await;
-            blockchain.rebuild_utxos();
+            info!("✅ Downloaded blockchain from {}", longest_name);
         }
     }
 
+    BLOCKCHAIN
+        .write()
+        .await
+        .set_max_mempool_transaction_age(cli.mempool_max_age);
+
     // Display validator status
     {
         let blockchain = BLOCKCHAIN.read().await;
         let stakes = blockchain.calculate_stakes();
         let our_stake = stakes.get(&public_key).cloned().unwrap_or(0);
-        let min_stake = Blockchain::get_min_stake_amount();
+        let min_stake = blockchain.get_min_stake_amount();
 
-        println!("\n💰 Stake status:");
-        println!("   Our stake: {}", our_stake);
-        println!("   Minimum required: {}", min_stake);
+        info!(
+            "💰 Stake status: our stake {}, minimum required {}",
+            our_stake, min_stake
+        );
 
         if our_stake < min_stake {
-            println!("⚠️  WARNING: Insufficient stake! You cannot propose blocks.");
+            warn!("⚠️  WARNING: Insufficient stake! You cannot propose blocks.");
         } else {
-            println!("✅ Sufficient stake to be a validator");
+            info!("✅ Sufficient stake to be a validator");
         }
     }
 
@@ -110,16 +157,18 @@ async fn main() -> Result<()> {
     // =========================================================================
 
     // Start listening for connections (node functionality)
-    let addr = format!("0.0.0.0:{}", cli.port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("\n🌐 Node listening on {}", addr);
+    let addr = SocketAddr::new(cli.bind, cli.port);
+    let listener = TcpListener::bind(addr).await?;
+    info!("🌐 Node listening on {}", addr);
 
     // Start background tasks (reusing node code)
     tokio::spawn(util::cleanup());
-    tokio::spawn(util::save(cli.blockchain_file.clone()));
+    tokio::spawn(util::save(PathBuf::from("."), cli.blockchain_file.clone()));
     // DEV : async func so listener port is passed correctly
     // In Eth, the validator connects to other nodes rather than other nodes connecting to it --> with a trusted boot node logicic 🫡
-    tokio::spawn(util::populate_connections(nodes, cli.port));
+    tokio::spawn(util::announce(cli.port));
+    tokio::spawn(util::populate_connections(nodes.clone(), cli.port));
+    tokio::spawn(util::maintain_peers(nodes, cli.port));
     // Spawn connection handler (node functionality)
     let listener_handle = tokio::spawn(async move {
         loop {
@@ -133,15 +182,35 @@ async fn main() -> Result<()> {
     // VALIDATOR-SPECIFIC: BLOCK PROPOSAL LOOP
     // =========================================================================
 
-    let proposer = BlockProposer::new(private_key);
+    let proposer = BlockProposer::new(
+        private_key,
+        cli.prioritize_stake,
+        cli.max_block_transactions,
+    );
+
+    // Proposing faster than the chain accepts would only get our blocks rejected
+    let min_interval = BLOCKCHAIN
+        .read()
+        .await
+        .params()
+        .consensus
+        .min_block_interval;
+    let slot_duration = if cli.block_interval < min_interval {
+        warn!(
+            "⚠️  Block interval {}s is below the {}s consensus minimum, using {}s",
+            cli.block_interval, min_interval, min_interval
+        );
+        min_interval
+    } else {
+        cli.block_interval
+    };
 
-    println!(
-        "\n🚀 Validator started. Checking for slot every {}s",
-        SLOT_DURATION_SECS
+    info!(
+        "🚀 Validator started. Checking for slot every {}s",
+        slot_duration
     );
-    println!("═══════════════════════════════════════════════\n");
 
-    let mut slot_timer = interval(Duration::from_secs(SLOT_DURATION_SECS));
+    let mut slot_timer = interval(Duration::from_secs(slot_duration));
 
     loop {
         tokio::select! {
@@ -149,26 +218,30 @@ async fn main() -> Result<()> {
                 // Check if it's our turn (LOCAL calculation - trustless)
                 let is_our_turn = {
                     let blockchain = BLOCKCHAIN.read().await;
-                    proposer.is_our_turn(&blockchain)
+                    proposer.is_our_turn(&blockchain, Utc::now())
                 };
 
                 if is_our_turn {
-                    println!("\n🔔 IT'S OUR TURN TO PROPOSE A BLOCK!");
+                    info!("🔔 IT'S OUR TURN TO PROPOSE A BLOCK!");
 
-                    if let Err(e) = proposer.propose_block().await {
-                        eprintln!("❌ Block proposal failed: {}", e);
+                    if let Err(e) = proposer.verify_validator_eligibility().await {
+                        error!("❌ Refusing to propose: {}", e);
+                    } else if let Err(e) = proposer.propose_block().await {
+                        error!("❌ Block proposal failed: {}", e);
                     }
                 }else {
-                    println!("⏳ Not our turn to propose a block this slot.");
+                    debug!("⏳ Not our turn to propose a block this slot.");
                 }
             }
             _ = tokio::signal::ctrl_c() => {
-                println!("\n👋 Shutting down validator...");
+                info!("👋 Shutting down validator...");
                 break;
             }
         }
     }
 
     listener_handle.abort();
+    util::flush(Path::new("."), &cli.blockchain_file).await?;
+    info!("💾 State saved");
     Ok(())
 }
@@ -8,68 +8,155 @@
 //! All other functionality is inherited from node_lib.
 
 use anyhow::{Result, anyhow};
-use chrono::Utc;
-use node_lib::{BLOCKCHAIN, NODES};
+use chrono::{DateTime, Utc};
+use node_lib::{BLOCKCHAIN, NODES, util};
 use poslib::crypto::{PrivateKey, PublicKey, Signature};
 use poslib::network::Message;
 use poslib::sha256::Hash;
 use poslib::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
 use poslib::util::MerkleRoot;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 pub struct BlockProposer {
     private_key: PrivateKey,
     public_key: PublicKey,
     blocks_proposed: AtomicU64,
+    /// Set while `propose_block` runs, so overlapping calls back off
+    proposing: AtomicBool,
+    /// Break fee ties by the sender's stake weight when picking transactions
+    prioritize_stake: bool,
+    /// How many transactions we are willing to include, capped by consensus
+    soft_transaction_cap: Option<usize>,
+}
+
+/// Holds `BlockProposer::proposing` set until dropped
+struct ProposingGuard<'a>(&'a AtomicBool);
+
+impl<'a> ProposingGuard<'a> {
+    fn acquire(flag: &'a AtomicBool) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| ProposingGuard(flag))
+    }
+}
+
+impl Drop for ProposingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
 }
 
 impl BlockProposer {
-    pub fn new(private_key: PrivateKey) -> Self {
+    pub fn new(
+        private_key: PrivateKey,
+        prioritize_stake: bool,
+        soft_transaction_cap: Option<usize>,
+    ) -> Self {
         let public_key = private_key.public_key();
         Self {
             private_key,
             public_key,
             blocks_proposed: AtomicU64::new(0),
+            proposing: AtomicBool::new(false),
+            prioritize_stake,
+            soft_transaction_cap,
         }
     }
 
-    /// Check if it's our turn to propose a blocks
-    pub fn is_our_turn(&self, blockchain: &Blockchain) -> bool {
-        let last_block_hash = blockchain
-            .blocks()
-            .last()
-            .map(|b| b.hash())
-            .unwrap_or(Hash::zero());
+    /// Number of mempool transactions to put in our block:
+    /// our own limit, never above the consensus maximum
+    fn transaction_cap(&self, blockchain: &Blockchain) -> usize {
+        let hard_cap = blockchain.params().consensus.block_transaction_cap;
+        self.soft_transaction_cap
+            .map_or(hard_cap, |soft_cap| soft_cap.min(hard_cap))
+    }
 
-        match blockchain.get_next_validator(&last_block_hash) {
+    /// Check if it's our turn to propose a block at `now`, including slots
+    /// passed to us because the expected validator missed them
+    pub fn is_our_turn(&self, blockchain: &Blockchain, now: DateTime<Utc>) -> bool {
+        match blockchain.slot_validator(now) {
             Some(expected_validator) => expected_validator == self.public_key,
             None => false,
         }
     }
 
+    /// Make sure we hold at least the minimum stake before proposing.
+    ///
+    /// The effective stake (after slashing) is asked from a peer, so a
+    /// validator with a stale local view doesn't submit blocks the network
+    /// would reject. Without a reachable peer we fall back to our own chain.
+    pub async fn verify_validator_eligibility(&self) -> Result<()> {
+        let (local_stake, min_stake) = {
+            let blockchain = BLOCKCHAIN.read().await;
+            (
+                blockchain.get_effective_stake(&self.public_key),
+                blockchain.get_min_stake_amount(),
+            )
+        };
+        let stake = self.fetch_stake_from_peer().await.unwrap_or(local_stake);
+
+        if stake < min_stake {
+            return Err(anyhow!(
+                "Insufficient stake: effective stake {} is below the minimum of {}",
+                stake,
+                min_stake
+            ));
+        }
+        Ok(())
+    }
+
+    /// Ask the first peer that answers for our effective stake
+    async fn fetch_stake_from_peer(&self) -> Option<u64> {
+        let nodes: Vec<String> = NODES.iter().map(|x| x.key().clone()).collect();
+        let message = Message::FetchStake(self.public_key.clone());
+
+        for node in &nodes {
+            let Some(stream) = node_lib::peer::stream_of(node) else {
+                continue;
+            };
+            let mut stream = stream.lock().await;
+            if message.send_async(&mut *stream).await.is_err() {
+                continue;
+            }
+            match Message::receive_async(&mut *stream).await {
+                Ok(Message::Stake(stake)) => return Some(stake),
+                Ok(other) => warn!("unexpected message from {}: {:?}", node, other),
+                Err(e) => warn!("⚠️  Failed to fetch stake from {}: {}", node, e),
+            }
+        }
+        None
+    }
+
     /// Propose a new block
     ///
     /// This builds the block locally, signs it, adds it to our chain,
     /// and broadcasts it to peers.
+    ///
+    /// The write lock is held from the turn check until our block is
+    /// appended, so a peer's `NewBlock` can't advance the tip in between.
+    /// If the tip moved before we got the lock, the slot is skipped.
+    /// A call made while another one is still running (build or broadcast)
+    /// returns right away instead of proposing a second block.
     pub async fn propose_block(&self) -> Result<()> {
-        // Build block from our local state
-        let block = self.build_block().await?;
-
-        // Add to our own blockchain first (this validates it)
-        {
-            let mut blockchain = BLOCKCHAIN.write().await;
-            blockchain
-                .add_block(block.clone())
-                .map_err(|e| anyhow!("Our own block was rejected: {:?}", e))?;
-            blockchain.rebuild_utxos();
-        }
+        let Some(_guard) = ProposingGuard::acquire(&self.proposing) else {
+            info!("⏭️  Already proposing a block, skipping this tick");
+            return Ok(());
+        };
+        let Some(block) = self.build_and_add(&BLOCKCHAIN, Utc::now).await? else {
+            return Ok(());
+        };
 
-        // Broadcast to all peers
+        // Broadcast to all peers, remembering it so their echoes are ignored
+        node_lib::seen::mark_seen(block.id());
         self.broadcast_block(block).await?;
 
         let count = self.blocks_proposed.fetch_add(1, Ordering::SeqCst) + 1;
-        println!(
+        info!(
             "🎉 Block proposed and broadcast! (Total proposed: {})",
             count
         );
@@ -77,71 +164,71 @@ impl BlockProposer {
         Ok(())
     }
 
+    /// Build our block and add it to `blockchain` under a single write
+    /// lock, `None` if the slot isn't ours at `now()` once we hold it
+    async fn build_and_add(
+        &self,
+        blockchain: &RwLock<Blockchain>,
+        now: impl FnOnce() -> DateTime<Utc>,
+    ) -> Result<Option<Block>> {
+        let mut blockchain = blockchain.write().await;
+
+        // Re-check under the lock: a peer block may have landed since the slot tick.
+        // The block carries this timestamp, so it lands in the round we checked
+        let now = now();
+        if !self.is_our_turn(&blockchain, now) {
+            info!("⏭️  Tip moved before we could propose, skipping this slot");
+            return Ok(None);
+        }
+
+        // Build block from our local state
+        let block = self.build_block(&blockchain, now)?;
+
+        // Add to our own blockchain first (this validates it)
+        blockchain
+            .add_block(block.clone())
+            .map_err(|e| anyhow!("Our own block was rejected: {:?}", e))?;
+        Ok(Some(block))
+    }
+
     /// Build a new block from local state
     ///
     /// The block is built entirely from our local blockchain state.
     /// We don't ask any node for a template - we build it ourselves.
-    async fn build_block(&self) -> Result<Block> {
-        let blockchain = BLOCKCHAIN.read().await;
-
-        // Double-check we're still the expected validator
-        if !self.is_our_turn(&blockchain) {
-            return Err(anyhow!("No longer our turn to propose"));
+    /// The caller must hold the blockchain lock for the whole build + add.
+    fn build_block(&self, blockchain: &Blockchain, timestamp: DateTime<Utc>) -> Result<Block> {
+        // Get transactions from mempool, already ordered highest fee first
+        let mut candidates: Vec<&Transaction> =
+            blockchain.mempool().iter().map(|(_, tx)| tx).collect();
+        if self.prioritize_stake {
+            sort_by_fee_then_stake(blockchain, &mut candidates);
         }
-
-        // Get transactions from mempool
-        let mempool_txs: Vec<Transaction> = blockchain
-            .mempool()
-            .iter()
-            .take(poslib::BLOCK_TRANSACTION_CAP)
-            .map(|(_, tx)| tx.clone())
+        let mempool_txs: Vec<Transaction> = candidates
+            .into_iter()
+            .take(self.transaction_cap(blockchain))
+            .cloned()
             .collect();
 
         // Calculate fees from transactions
-        let mut validator_fees = 0u64;
-        let mut valid_transactions = Vec::new();
-
-        for tx in mempool_txs {
-            let mut input_sum = 0u64;
-            let mut output_sum = 0u64;
-            let mut is_valid = true;
-
-            for input in &tx.inputs {
-                if let Some((_, output)) =
-                    blockchain.utxos().get(&input.prev_transaction_output_hash)
-                {
-                    input_sum += output.value;
-                } else {
-                    is_valid = false;
-                    break;
-                }
-            }
-
-            if !is_valid {
-                continue;
-            }
-
-            for output in &tx.outputs {
-                output_sum += output.value;
-            }
-
-            if input_sum >= output_sum {
-                validator_fees += input_sum - output_sum;
-                valid_transactions.push(tx);
-            }
-        }
-
-        // Create coinbase transaction (our reward)
-        let coinbase = Transaction {
-            inputs: vec![],
-            outputs: vec![TransactionOutput {
+        let (valid_transactions, validator_fees) =
+            payable_transactions(mempool_txs, blockchain.utxos());
+
+        // Create coinbase transaction (our reward): block subsidy + fees
+        let reward = blockchain.calculate_block_reward();
+        let coinbase_value = reward
+            .checked_add(validator_fees)
+            .ok_or_else(|| anyhow!("Coinbase value overflows"))?;
+        let coinbase = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
                 pubkey: self.public_key.clone(),
                 unique_id: Uuid::new_v4(),
-                value: validator_fees,
+                value: coinbase_value,
                 is_stake: false,
                 locked_until: 0,
+                delegate_to: None,
             }],
-        };
+        );
 
         // Build transaction list with coinbase first
         let mut transactions = vec![coinbase];
@@ -154,20 +241,27 @@ impl BlockProposer {
         let prev_hash = blockchain
             .blocks()
             .last()
-            .map(|b| b.hash())
+            .map(|b| b.id())
             .unwrap_or(Hash::zero());
 
-        let header = BlockHeader::new(Utc::now(), prev_hash, merkle_root, self.public_key.clone());
+        let mut header =
+            BlockHeader::new(timestamp, prev_hash, merkle_root, self.public_key.clone());
+        // double signs we saw get slashed by our block
+        header.evidence = blockchain.pending_evidence();
+        header.set_randomness(&blockchain.randomness(), &self.private_key);
 
         // Sign the block
         let signature = Signature::sign_output(&header.hash(), &self.private_key);
 
         let block = Block::new(header, transactions, signature);
 
-        println!("📦 Built block:");
-        println!("   - Transactions: {}", block.transactions.len());
-        println!("   - Reward: {}", validator_fees);
-        println!("   - Prev hash: {}", prev_hash);
+        info!(
+            transactions = block.transactions.len(),
+            subsidy = reward,
+            fees = validator_fees,
+            prev_hash = %prev_hash,
+            "📦 Built block"
+        );
 
         Ok(block)
     }
@@ -175,27 +269,281 @@ impl BlockProposer {
     /// Broadcast a block to all connected peers
     async fn broadcast_block(&self, block: Block) -> Result<()> {
         let message = Message::NewBlock(block);
+        let peers = NODES.len();
+        let delivered = util::broadcast(&message, None).await;
 
-        let nodes: Vec<String> = NODES.iter().map(|x| x.key().clone()).collect();
+        info!("📡 Block broadcast to {}/{} peers", delivered, peers);
+
+        Ok(())
+    }
+}
 
-        let mut success_count = 0;
+/// The transactions of `candidates` whose fee can be paid from `utxos`,
+/// with the fees they pay together. Anything spending unknown UTXOs,
+/// paying out more than it spends or whose fee would overflow the total
+/// is skipped.
+fn payable_transactions(
+    candidates: Vec<Transaction>,
+    utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+) -> (Vec<Transaction>, u64) {
+    let mut fees = 0u64;
+    let mut payable = Vec::new();
+    for tx in candidates {
+        if let Ok(fee) = tx.fee(utxos)
+            && let Some(total) = fees.checked_add(fee)
+        {
+            fees = total;
+            payable.push(tx);
+        }
+    }
+    (payable, fees)
+}
 
-        for node in &nodes {
-            if let Some(mut stream) = NODES.get_mut(node) {
-                if message.send_async(&mut *stream).await.is_ok() {
-                    success_count += 1;
-                } else {
-                    eprintln!("⚠️  Failed to send block to {}", node);
-                }
+/// Order transactions by fee (highest first), breaking ties by the stake
+/// weight of the sender, then by hash. The sender's weight is the largest
+/// active stake among the owners of the transaction's input UTXOs.
+fn sort_by_fee_then_stake(blockchain: &Blockchain, txs: &mut [&Transaction]) {
+    let stakes = blockchain.calculate_stakes();
+    let utxos = blockchain.utxos();
+    let weight = |tx: &Transaction| {
+        let mut sender_stake = 0u64;
+        for input in &tx.inputs {
+            if let Some((_, output)) = utxos.get(&input.prev_transaction_output_hash) {
+                sender_stake = sender_stake.max(stakes.get(&output.pubkey).copied().unwrap_or(0));
             }
         }
+        (
+            Reverse(tx.fee(utxos).unwrap_or(0)),
+            Reverse(sender_stake),
+            tx.hash().as_bytes(),
+        )
+    };
+    txs.sort_by_cached_key(|tx| weight(tx));
+}
 
-        println!(
-            "📡 Block broadcast to {}/{} peers",
-            success_count,
-            nodes.len()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poslib::ChainParams;
+    use poslib::error::{BlockRejectReason, EthError, TxRejectReason};
+    use poslib::types::TransactionInput;
+    use poslib::types::fixtures::*;
+
+    /// A proposer for the validator `test` expects in its next slot
+    fn next_proposer(test: &TestChain) -> BlockProposer {
+        let producer = test.chain.slot_validator(test.next_timestamp()).unwrap();
+        BlockProposer::new(test.key_of(&producer).clone(), false, None)
+    }
+
+    #[tokio::test]
+    async fn peer_block_cannot_land_between_build_and_add() {
+        let test = TestChain::new(&[1000 * COIN, 1000 * COIN]);
+        let proposer = next_proposer(&test);
+        let tip = test.chain.blocks().last().unwrap().id();
+        // a block for the same slot, arriving while we build ours
+        let peer_block = test.next_block(vec![]);
+        let blockchain = RwLock::new(test.chain.clone());
+
+        let (ours, peer) = tokio::join!(
+            proposer.build_and_add(&blockchain, || {
+                assert!(blockchain.try_read().is_err(), "lock released early");
+                test.next_timestamp()
+            }),
+            async { blockchain.write().await.add_block(peer_block.clone()) },
         );
+        let ours = ours.unwrap().expect("our slot");
+
+        // the peer block only got in after ours and no longer extends the tip
+        peer.unwrap();
+        let blockchain = blockchain.read().await;
+        assert_eq!(ours.header.prev_block_hash, tip);
+        assert_eq!(blockchain.blocks().last().map(Block::id), Some(ours.id()));
+        assert!(!blockchain.contains_block(&peer_block.id()));
+    }
 
-        Ok(())
+    /// Build the next block of `test` with `payment` in the mempool and
+    /// check its coinbase claims exactly `subsidy` plus the payment's fee
+    fn assert_coinbase_claims(test: &mut TestChain, payment: Transaction, subsidy: u64) {
+        let fee = payment.fee(test.chain.utxos()).unwrap();
+        test.chain.add_to_mempool(payment).unwrap();
+        let proposer = next_proposer(test);
+        let block = proposer
+            .build_block(&test.chain, test.next_timestamp())
+            .unwrap();
+        let reward = test.chain.calculate_block_reward();
+        assert_eq!(reward, subsidy);
+        assert_eq!(block.transactions[0].outputs[0].value, subsidy + fee);
+        block
+            .verify_coinbase_transaction(test.chain.utxos(), reward)
+            .unwrap();
+
+        let mut greedy = block.clone();
+        greedy.transactions[0].outputs[0].value += 1;
+        assert!(matches!(
+            greedy.verify_coinbase_transaction(test.chain.utxos(), reward),
+            Err(EthError::InvalidTransaction {
+                reason: TxRejectReason::CoinbaseMismatch { .. }
+            })
+        ));
+        test.chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn coinbase_claims_subsidy_and_fees() {
+        let mut params = ChainParams::default();
+        params.economics.halving_interval = 3;
+        let mut test = TestChain::with_params(&[1000 * COIN], params);
+        let owner = test.validators[0].clone();
+        let initial_reward = test.chain.params().economics.initial_reward * COIN;
+
+        let payment = spend(
+            &owner,
+            &[test.allocation_of(0)],
+            &owner.public_key(),
+            COIN,
+            1000,
+        );
+        let change = &payment.outputs[1];
+        let change = (change.hash(), change.value);
+        assert_coinbase_claims(&mut test, payment, initial_reward);
+
+        // the block at height 3 is the first of the second halving interval
+        test.extend(vec![]);
+        let payment = spend(&owner, &[change], &owner.public_key(), COIN, 2000);
+        assert_coinbase_claims(&mut test, payment, initial_reward / 2);
+    }
+
+    #[test]
+    fn fees_that_overflow_or_underflow_are_skipped() {
+        let owner = keys(0, 1).remove(0);
+        let coin = |value| output(value, &owner.public_key());
+        let (rich, small, short) = (coin(u64::MAX), coin(10), coin(1));
+        let utxos: HashMap<Hash, (bool, TransactionOutput)> = [&rich, &small, &short]
+            .into_iter()
+            .map(|utxo| (utxo.hash(), (false, utxo.clone())))
+            .collect();
+        let paying = |spent: &TransactionOutput, value| {
+            Transaction::new(
+                vec![TransactionInput::new(
+                    spent.hash(),
+                    TransactionInput::SEQUENCE_FINAL,
+                    &owner,
+                )],
+                vec![coin(value)],
+            )
+        };
+        let whale = paying(&rich, 0);
+        // fee of 5 on top of u64::MAX
+        let overflowing = paying(&small, 5);
+        // pays out more than it spends
+        let underflowing = paying(&short, 2);
+
+        let (payable, fees) =
+            payable_transactions(vec![whale.clone(), overflowing, underflowing], &utxos);
+        assert_eq!(fees, u64::MAX);
+        assert_eq!(
+            payable.iter().map(Transaction::hash).collect::<Vec<_>>(),
+            vec![whale.hash()]
+        );
+    }
+
+    #[test]
+    fn equal_fees_are_ordered_by_sender_stake() {
+        let test = TestChain::new(&[2000 * COIN, 1000 * COIN]);
+        let payment = |index: usize, fee: u64| {
+            let owner = &test.validators[index];
+            spend(
+                owner,
+                &[test.allocation_of(index)],
+                &owner.public_key(),
+                COIN,
+                fee,
+            )
+        };
+        let low_stake = payment(1, 1000);
+        let high_stake = payment(0, 1000);
+        let high_fee = payment(1, 5000);
+
+        let mut txs = vec![&low_stake, &high_stake, &high_fee];
+        sort_by_fee_then_stake(&test.chain, &mut txs);
+        let order: Vec<Hash> = txs.into_iter().map(Transaction::hash).collect();
+        assert_eq!(
+            order,
+            vec![high_fee.hash(), high_stake.hash(), low_stake.hash()]
+        );
+    }
+
+    #[test]
+    fn soft_cap_limits_the_block_and_consensus_caps_the_soft_cap() {
+        let mut params = ChainParams::default();
+        params.consensus.block_transaction_cap = 2;
+        let mut test = TestChain::with_params(&[1000 * COIN; 3], params);
+        let payments: Vec<Transaction> = (0..3)
+            .map(|index| {
+                let owner = &test.validators[index];
+                spend(
+                    owner,
+                    &[test.allocation_of(index)],
+                    &owner.public_key(),
+                    COIN,
+                    1000,
+                )
+            })
+            .collect();
+        for payment in &payments {
+            test.chain.add_to_mempool(payment.clone()).unwrap();
+        }
+        let producer = test.chain.slot_validator(test.next_timestamp()).unwrap();
+        let key = test.key_of(&producer).clone();
+        let timestamp = test.next_timestamp();
+
+        let frugal = BlockProposer::new(key.clone(), false, Some(1));
+        let block = frugal.build_block(&test.chain, timestamp).unwrap();
+        assert_eq!(block.transactions.len(), 1 + 1);
+
+        let greedy = BlockProposer::new(key, false, Some(10));
+        let block = greedy.build_block(&test.chain, timestamp).unwrap();
+        assert_eq!(block.transactions.len(), 1 + 2);
+
+        // a block past the consensus cap is refused whoever built it
+        let oversized = test.block_at(timestamp, payments);
+        assert!(matches!(
+            test.chain.add_block(oversized),
+            Err(EthError::InvalidBlock {
+                reason: BlockRejectReason::TooManyTransactions { count: 4, cap: 3 }
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn overlapping_proposal_is_skipped() {
+        let proposer = BlockProposer::new(keys(0, 1).remove(0), false, None);
+        proposer.proposing.store(true, Ordering::Release);
+        proposer.propose_block().await.unwrap();
+        // the skipped call leaves the running one's flag alone
+        assert!(proposer.proposing.load(Ordering::Acquire));
+        assert_eq!(proposer.blocks_proposed.load(Ordering::SeqCst), 0);
+
+        // not our turn on the empty global chain, so this returns early
+        proposer.proposing.store(false, Ordering::Release);
+        proposer.propose_block().await.unwrap();
+        assert!(!proposer.proposing.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn proposing_guard_is_released_on_error() {
+        let flag = AtomicBool::new(false);
+        let attempt = |fail: bool| -> Result<()> {
+            let _guard = ProposingGuard::acquire(&flag).expect("not proposing yet");
+            assert!(ProposingGuard::acquire(&flag).is_none());
+            if fail {
+                return Err(anyhow!("our own block was rejected"));
+            }
+            Ok(())
+        };
+        attempt(true).unwrap_err();
+        assert!(!flag.load(Ordering::Acquire));
+        attempt(false).unwrap();
+        assert!(!flag.load(Ordering::Acquire));
     }
 }
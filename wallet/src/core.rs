@@ -1,22 +1,32 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use crossbeam_skiplist::SkipMap;
-use poslib::STAKE_MINIMUM_AMOUNT;
 use poslib::crypto::{PrivateKey, PublicKey};
-use poslib::network::Message;
-use poslib::types::{Transaction, TransactionOutput};
-use poslib::util::Saveable;
+use poslib::network::{MAX_UTXO_PAGE, Message};
+use poslib::sha256::Hash;
+use poslib::types::{SlashingRecord, Transaction, TransactionInput, TransactionOutput};
+use poslib::util::{Saveable, sum_amounts};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpStream;
 
+use crate::error::WalletError;
+use tracing::debug;
+
 use kanal::AsyncSender;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Key {
     public: PathBuf,
-    private: PathBuf,
+    /// Left out for a watch-only key, whose balance is shown but whose
+    /// coins can't be spent from this wallet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<PathBuf>,
 }
 #[derive(Clone)]
 struct LoadedKey {
@@ -52,47 +62,246 @@ pub struct FeeConfig {
     pub fee_type: FeeType,
     pub value: f64,
 }
+/// Where change (and our own stake outputs) are sent
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum ChangePolicy {
+    /// Always the first key in `my_keys`
+    FirstKey,
+    /// The key that contributed the most input value
+    #[default]
+    SourceKey,
+}
+/// Which UTXOs pay for a transaction, see `UtxoStore::select`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum CoinSelection {
+    /// Biggest coins first, for as few inputs as possible
+    #[default]
+    LargestFirst,
+    /// Smallest coins first, consolidating dust at the cost of more inputs
+    SmallestFirst,
+    /// Coins adding up to exactly the amount, so no change output links
+    /// the payment back to us. Largest-first when there is no exact match.
+    BranchAndBound,
+}
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub my_keys: Vec<Key>,
     pub contacts: Vec<Recipient>,
     pub default_node: String,
     pub fee_config: FeeConfig,
+    #[serde(default)]
+    pub change_policy: ChangePolicy,
+    #[serde(default)]
+    pub coin_selection: CoinSelection,
+}
+
+/// Whether a history entry paid someone else or locked our own coins
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Direction {
+    Sent,
+    Staked,
+}
+/// One payment made by this wallet, as stored in the history sidecar
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub amount: u64,
+    pub counterparty: PublicKey,
+}
+
+/// Transaction history sits next to the wallet config
+pub fn history_file(config_path: &Path) -> PathBuf {
+    config_path.with_extension("history.cbor")
 }
 
 #[derive(Clone)]
 struct UtxoStore {
     my_keys: Vec<LoadedKey>,
+    /// Keys we only track the UTXOs of, from the config or `watch`
+    watched: Arc<Mutex<Vec<PublicKey>>>,
     utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
 }
 impl UtxoStore {
     fn new() -> Self {
         UtxoStore {
             my_keys: vec![],
+            watched: Arc::new(Mutex::new(vec![])),
             utxos: Arc::new(SkipMap::new()),
         }
     }
     fn add_key(&mut self, key: LoadedKey) {
         self.my_keys.push(key);
     }
+    /// Returns false if the key was already known
+    fn add_watched(&self, key: PublicKey) -> bool {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.contains(&key) || self.my_keys.iter().any(|k| k.public == key) {
+            return false;
+        }
+        watched.push(key);
+        true
+    }
+    /// Our spendable UTXOs matching `filter`, picked by `strategy` to cover
+    /// `target`. Marked, worthless and watch-only UTXOs are never picked.
+    fn select(
+        &self,
+        target: u64,
+        strategy: CoinSelection,
+        filter: impl Fn(&TransactionOutput) -> bool,
+    ) -> Result<Vec<(PublicKey, TransactionOutput)>> {
+        let mut candidates: Vec<(PublicKey, TransactionOutput)> = self
+            .utxos
+            .iter()
+            .filter(|entry| self.my_keys.iter().any(|key| key.public == *entry.key()))
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(marked, utxo)| !marked && utxo.value > 0 && filter(utxo))
+                    .map(|(_, utxo)| (entry.key().clone(), utxo.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        // ties broken by hash so the same UTXO set always gives the same pick
+        match strategy {
+            CoinSelection::SmallestFirst => {
+                candidates.sort_by_cached_key(|(_, utxo)| (utxo.value, utxo.hash().as_bytes()))
+            }
+            CoinSelection::LargestFirst | CoinSelection::BranchAndBound => candidates
+                .sort_by_cached_key(|(_, utxo)| (Reverse(utxo.value), utxo.hash().as_bytes())),
+        }
+        if let CoinSelection::BranchAndBound = strategy {
+            let values: Vec<u64> = candidates.iter().map(|(_, utxo)| utxo.value).collect();
+            if let Some(picked) = exact_match(&values, target) {
+                return Ok(picked.into_iter().map(|i| candidates[i].clone()).collect());
+            }
+        }
+        let mut selected = vec![];
+        let mut sum = 0u64;
+        for candidate in candidates {
+            if sum >= target {
+                break;
+            }
+            sum = sum
+                .checked_add(candidate.1.value)
+                .ok_or_else(|| anyhow::anyhow!("UTXO total overflows"))?;
+            selected.push(candidate);
+        }
+        if sum < target {
+            return Err(WalletError::InsufficientFunds {
+                needed: target,
+                available: sum,
+            }
+            .into());
+        }
+        Ok(selected)
+    }
+    /// Signing keys first, then watch-only ones
+    fn all_keys(&self) -> Vec<PublicKey> {
+        self.my_keys
+            .iter()
+            .map(|key| key.public.clone())
+            .chain(self.watched.lock().unwrap().iter().cloned())
+            .collect()
+    }
+}
+/// Search steps `exact_match` may take before giving up
+const BRANCH_AND_BOUND_TRIES: u32 = 100_000;
+
+/// Indexes of `values` (sorted largest first) adding up to exactly
+/// `target`, found by a depth-first search that skips branches which
+/// overshoot or can no longer reach the target
+fn exact_match(values: &[u64], target: u64) -> Option<Vec<usize>> {
+    fn search(
+        values: &[u64],
+        remaining: &[u64],
+        index: usize,
+        sum: u64,
+        target: u64,
+        picked: &mut Vec<usize>,
+        tries: &mut u32,
+    ) -> bool {
+        if sum == target {
+            return true;
+        }
+        if index == values.len() || sum.saturating_add(remaining[index]) < target || *tries == 0 {
+            return false;
+        }
+        *tries -= 1;
+        if let Some(with) = sum.checked_add(values[index])
+            && with <= target
+        {
+            picked.push(index);
+            if search(values, remaining, index + 1, with, target, picked, tries) {
+                return true;
+            }
+            picked.pop();
+        }
+        search(values, remaining, index + 1, sum, target, picked, tries)
+    }
+    // remaining[i] is the most the values from i on can still add
+    let mut remaining = vec![0u64; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1].saturating_add(values[i]);
+    }
+    let mut picked = vec![];
+    let mut tries = BRANCH_AND_BOUND_TRIES;
+    search(values, &remaining, 0, 0, target, &mut picked, &mut tries).then_some(picked)
 }
+
+/// How long `queue_transaction` waits for room in the send queue by default
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `connect` waits for the node by default
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct Core {
     pub config: Config,
     utxos: UtxoStore,
     pub tx_sender: AsyncSender<Transaction>,
+    /// How long to wait for the node to accept a connection and handshake
+    pub connect_timeout: Duration,
+    /// How long `queue_transaction` waits for room in the send queue
+    queue_timeout: Duration,
+    history_path: PathBuf,
+    /// Errors from the background sender, waiting to be shown in the REPL
+    send_failures: Arc<Mutex<Vec<String>>>,
 }
 impl Core {
     // ...
-    fn new(config: Config, utxos: UtxoStore) -> Self {
+    fn new(config: Config, utxos: UtxoStore, history_path: PathBuf) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
         Core {
             config,
             utxos,
             tx_sender: tx_sender.clone_async(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            queue_timeout: QUEUE_TIMEOUT,
+            history_path,
+            send_failures: Arc::new(Mutex::new(vec![])),
+        }
+    }
+    /// Hand a transaction to the background sender. Fails with `QueueFull`
+    /// if the queue has no room within `queue_timeout`, which happens when
+    /// the sender is stuck on an unreachable node.
+    pub async fn queue_transaction(&self, transaction: Transaction) -> Result<()> {
+        match tokio::time::timeout(self.queue_timeout, self.tx_sender.send(transaction)).await {
+            Ok(sent) => Ok(sent?),
+            Err(_) => Err(WalletError::QueueFull.into()),
         }
     }
-    pub fn load(config_path: PathBuf) -> Result<Self> {
+    /// Remember a failure of the background sender for the REPL to show
+    pub fn report_send_failure(&self, failure: String) {
+        self.send_failures.lock().unwrap().push(failure);
+    }
+    /// Failures reported since the last call
+    pub fn take_send_failures(&self) -> Vec<String> {
+        std::mem::take(&mut *self.send_failures.lock().unwrap())
+    }
+    /// `passphrase` unlocks key files written by `PrivateKey::save_encrypted`;
+    /// plaintext key files load without it
+    pub fn load(config_path: PathBuf, passphrase: Option<&str>) -> Result<Self> {
         let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
         if !config.my_keys.is_empty() {
             println!("Loaded wallet config from {}", config_path.display());
@@ -106,214 +315,487 @@ impl Core {
         // Load keys from config
         for key in &config.my_keys {
             let public = PublicKey::load_from_file(&key.public)?;
-            let private = PrivateKey::load_from_file(&key.private)?;
+            let Some(private_path) = &key.private else {
+                utxos.add_watched(public);
+                continue;
+            };
+            let private = match passphrase {
+                Some(passphrase) => PrivateKey::load_encrypted(private_path, passphrase)?,
+                None if PrivateKey::is_encrypted_file(private_path)? => {
+                    return Err(anyhow::anyhow!(
+                        "Private key {} is encrypted, run with --ask-passphrase",
+                        private_path.display()
+                    ));
+                }
+                None => PrivateKey::load_from_file(private_path)?,
+            };
+            private.ensure_matches(&public).map_err(|_| {
+                anyhow::anyhow!(
+                    "Private key {} does not match public key {}",
+                    private_path.display(),
+                    key.public.display()
+                )
+            })?;
             utxos.add_key(LoadedKey { public, private });
         }
-        Ok(Core::new(config, utxos))
+        Ok(Core::new(config, utxos, history_file(&config_path)))
+    }
+    /// Open a connection to the configured node. The wallet has no chain,
+    /// so only the protocol version is checked.
+    /// Fails with `NodeUnreachable` after `connect_timeout`.
+    async fn connect(&self) -> Result<TcpStream> {
+        let unreachable = || WalletError::NodeUnreachable(self.config.default_node.clone());
+        let connect = async {
+            let mut stream = TcpStream::connect(&self.config.default_node)
+                .await
+                .map_err(|_| unreachable())?;
+            Message::handshake_async(&mut stream, Hash::zero()).await?;
+            Ok::<_, anyhow::Error>(stream)
+        };
+        tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| unreachable())?
+    }
+    /// Look up a contact by name and load its key
+    pub fn find_contact(&self, name: &str) -> Result<LoadedRecipient> {
+        self.config
+            .contacts
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| WalletError::RecipientNotFound(name.to_string()))?
+            .load()
+    }
+    /// A contact name, or failing that an address made by
+    /// `PublicKey::to_address`
+    pub fn resolve_recipient(&self, name_or_address: &str) -> Result<LoadedRecipient> {
+        match self.find_contact(name_or_address) {
+            Ok(recipient) => Ok(recipient),
+            Err(e) => match PublicKey::from_address(name_or_address) {
+                Ok(key) => Ok(LoadedRecipient {
+                    name: name_or_address.to_string(),
+                    key,
+                }),
+                Err(_) => Err(e),
+            },
+        }
+    }
+    /// Addresses of our keys, to share with senders
+    pub fn addresses(&self) -> Vec<String> {
+        self.utxos
+            .my_keys
+            .iter()
+            .map(|key| key.public.to_address())
+            .collect()
+    }
+    /// Track the UTXOs of the public key in `path` without being able to
+    /// spend them, until the wallet exits
+    pub fn watch(&self, path: &Path) -> Result<PublicKey> {
+        let key = PublicKey::load_from_file(path)?;
+        if !self.utxos.add_watched(key.clone()) {
+            return Err(anyhow::anyhow!(
+                "{} is already in the wallet",
+                path.display()
+            ));
+        }
+        Ok(key)
     }
     pub async fn fetch_utxos(&self) -> Result<()> {
-        let mut stream = TcpStream::connect(&self.config.default_node).await?;
-        for key in &self.utxos.my_keys {
-            let message = Message::FetchUTXOs(key.public.clone());
-            message.send_async(&mut stream).await?;
-            if let Message::UTXOs(utxos) = Message::receive_async(&mut stream).await? {
-                // Replace the entire UTXO set for this key
-                self.utxos.utxos.insert(
-                    key.public.clone(),
-                    utxos
-                        .into_iter()
-                        .map(|(output, marked)| (marked, output))
-                        .collect(),
-                );
-            } else {
-                return Err(anyhow::anyhow!("Unexpected response from node"));
+        let mut stream = self.connect().await?;
+        for key in self.utxos.all_keys() {
+            // Page through the key's UTXOs until the node has no more
+            let mut all_utxos = Vec::new();
+            loop {
+                let message = Message::FetchUTXOs {
+                    key: key.clone(),
+                    offset: all_utxos.len() as u64,
+                    limit: MAX_UTXO_PAGE,
+                };
+                message.send_async(&mut stream).await?;
+                if let Message::UTXOs { utxos, has_more } =
+                    Message::receive_async(&mut stream).await?
+                {
+                    let received = utxos.len();
+                    all_utxos.extend(utxos.into_iter().map(|(output, marked)| (marked, output)));
+                    if !has_more || received == 0 {
+                        break;
+                    }
+                } else {
+                    return Err(WalletError::UnexpectedResponse.into());
+                }
             }
+            // Replace the entire UTXO set for this key
+            self.utxos.utxos.insert(key, all_utxos);
         }
         Ok(())
     }
+    /// Balance of all our keys, watch-only ones included, as computed by
+    /// the node, as (spendable, locked stake, unlocked stake). Doesn't touch
+    /// the local UTXO set, which `fetch_utxos` keeps for coin selection.
+    pub async fn fetch_balance(&self) -> Result<(u64, u64, u64)> {
+        let mut stream = self.connect().await?;
+        let mut total = (0u64, 0u64, 0u64);
+        for key in self.utxos.all_keys() {
+            Message::FetchBalance(key).send_async(&mut stream).await?;
+            if let Message::Balance {
+                spendable,
+                active_stake,
+                unlocked_stake,
+            } = Message::receive_async(&mut stream).await?
+            {
+                total.0 = total.0.saturating_add(spendable);
+                total.1 = total.1.saturating_add(active_stake);
+                total.2 = total.2.saturating_add(unlocked_stake);
+            } else {
+                return Err(WalletError::UnexpectedResponse.into());
+            }
+        }
+        Ok(total)
+    }
+    /// Checks `validate_standalone` first, so a malformed transaction
+    /// never reaches the node
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
-        let mut stream = TcpStream::connect(&self.config.default_node).await?;
+        transaction.validate_standalone()?;
+        let mut stream = self.connect().await?;
         let message = Message::SubmitTransaction(transaction);
         message.send_async(&mut stream).await?;
         Ok(())
     }
 
+    /// Append the payments made by a sent transaction to the history file.
+    /// Outputs to other keys are payments; stake outputs to our own keys are
+    /// stakes. Change and unstaked coins coming back to us are not recorded.
+    pub fn record_transaction(&self, transaction: &Transaction) -> Result<()> {
+        let timestamp = Utc::now();
+        let mut history = self.history()?;
+        for output in &transaction.outputs {
+            let ours = self.utxos.my_keys.iter().any(|k| k.public == output.pubkey);
+            let direction = match (ours, output.is_stake) {
+                (false, _) => Direction::Sent,
+                (true, true) => Direction::Staked,
+                (true, false) => continue,
+            };
+            history.push(HistoryEntry {
+                timestamp,
+                direction,
+                amount: output.value,
+                counterparty: output.pubkey.clone(),
+            });
+        }
+        let file = fs::File::create(&self.history_path)?;
+        ciborium::ser::into_writer(&history, file)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize history: {e}"))?;
+        Ok(())
+    }
+    /// Everything recorded so far, oldest first
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        if !self.history_path.exists() {
+            return Ok(vec![]);
+        }
+        let file = fs::File::open(&self.history_path)?;
+        ciborium::de::from_reader(file)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize history: {e}"))
+    }
+    /// Name to show for a history counterparty: a contact name, "self" for
+    /// our own keys, or "unknown"
+    pub fn counterparty_name(&self, key: &PublicKey) -> String {
+        if self.utxos.my_keys.iter().any(|k| k.public == *key) {
+            return "self".to_string();
+        }
+        self.config
+            .contacts
+            .iter()
+            .filter_map(|r| r.load().ok())
+            .find(|r| r.key == *key)
+            .map_or_else(|| key.to_address(), |r| r.name)
+    }
+    /// Value of one of our UTXOs, looked up by its hash
+    pub fn utxo_value(&self, hash: &Hash) -> Option<u64> {
+        self.utxos.utxos.iter().find_map(|entry| {
+            entry
+                .value()
+                .iter()
+                .find(|(_, utxo)| utxo.hash() == *hash)
+                .map(|(_, utxo)| utxo.value)
+        })
+    }
+    /// Write the history as CSV: timestamp,direction,amount,recipient
+    pub fn export_history(&self, path: &Path) -> Result<usize> {
+        let history = self.history()?;
+        let mut csv = String::from("timestamp,direction,amount,recipient\n");
+        for entry in &history {
+            let name = self
+                .counterparty_name(&entry.counterparty)
+                .replace('"', "\"\"");
+            csv.push_str(&format!(
+                "{},{:?},{},\"{}\"\n",
+                entry.timestamp.to_rfc3339(),
+                entry.direction,
+                entry.amount,
+                name
+            ));
+        }
+        fs::write(path, csv)?;
+        Ok(history.len())
+    }
+
     /// Fetch current block height from the node (source of truth)
     pub async fn fetch_block_height(&self) -> Result<u64> {
-        let mut stream = TcpStream::connect(&self.config.default_node).await?;
+        let mut stream = self.connect().await?;
         let message = Message::FetchBlockHeight;
         message.send_async(&mut stream).await?;
 
         if let Message::BlockHeight(height) = Message::receive_async(&mut stream).await? {
             Ok(height)
         } else {
-            Err(anyhow::anyhow!("Unexpected response from node"))
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// Fetch issued and remaining supply from the node
+    pub async fn fetch_supply_info(&self) -> Result<(u64, u64)> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchSupplyInfo;
+        message.send_async(&mut stream).await?;
+
+        if let Message::SupplyInfo { issued, remaining } =
+            Message::receive_async(&mut stream).await?
+        {
+            Ok((issued, remaining))
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
         }
     }
 
+    /// Ask the node for a confirmed transaction and its block height
+    pub async fn fetch_transaction(&self, hash: Hash) -> Result<Option<(u64, Transaction)>> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchTransaction(hash);
+        message.send_async(&mut stream).await?;
+
+        if let Message::TransactionFound(found) = Message::receive_async(&mut stream).await? {
+            Ok(found)
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// Ask the node how many blocks bury a confirmed transaction
+    pub async fn fetch_confirmations(&self, hash: Hash) -> Result<Option<u64>> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchConfirmations(hash);
+        message.send_async(&mut stream).await?;
+
+        if let Message::Confirmations(confirmations) = Message::receive_async(&mut stream).await? {
+            Ok(confirmations)
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// Fetch the lowest, median and highest fee in the node's mempool
+    pub async fn fetch_fee_estimate(&self) -> Result<(u64, u64, u64)> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchFeeEstimate;
+        message.send_async(&mut stream).await?;
+
+        if let Message::FeeEstimate { min, median, max } =
+            Message::receive_async(&mut stream).await?
+        {
+            Ok((min, median, max))
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// Fetch every slashing event recorded on the node's chain
+    pub async fn fetch_slashing_history(&self) -> Result<Vec<SlashingRecord>> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchSlashingHistory;
+        message.send_async(&mut stream).await?;
+
+        if let Message::SlashingHistory(records) = Message::receive_async(&mut stream).await? {
+            Ok(records)
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// Fetch the minimum stake and lock period enforced by the node
+    pub async fn fetch_stake_params(&self) -> Result<(u64, u64)> {
+        let mut stream = self.connect().await?;
+        let message = Message::FetchStakeParams;
+        message.send_async(&mut stream).await?;
+
+        if let Message::StakeParams {
+            min_stake,
+            lock_period,
+        } = Message::receive_async(&mut stream).await?
+        {
+            Ok((min_stake, lock_period))
+        } else {
+            Err(WalletError::UnexpectedResponse.into())
+        }
+    }
+
+    /// `valid_until` is the last block height the transaction may be
+    /// included at, `None` for no expiry. Both it and the memo are signed.
     pub async fn create_transaction(
         &self,
         recipient: &PublicKey,
         amount: u64,
+        valid_until: Option<u64>,
+        memo: Option<Vec<u8>>,
     ) -> Result<Transaction> {
+        self.ensure_keys()?;
         let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow::anyhow!("Amount plus fee overflows"))?;
 
         // Fetch current block height to check stake lock status
         let current_height = self.fetch_block_height().await?;
 
         // Debug: show UTXO state
-        println!("=== DEBUG UTXO State ===");
-        println!("Current block height: {}", current_height);
+        debug!(height = current_height, "UTXO state");
         for entry in self.utxos.utxos.iter() {
             let pubkey = entry.key();
             let utxos = entry.value();
-            println!("Key: {:?}", pubkey);
             for (i, (marked, utxo)) in utxos.iter().enumerate() {
-                println!(
-                    "  UTXO {}: value={}, marked={}, is_stake={}, locked_until={}",
-                    i, utxo.value, marked, utxo.is_stake, utxo.locked_until
+                debug!(
+                    key = %pubkey,
+                    "UTXO {}: value={}, marked={}, is_stake={}, locked_until={}, can_spend={}",
+                    i,
+                    utxo.value,
+                    marked,
+                    utxo.is_stake,
+                    utxo.locked_until,
+                    !marked && !utxo.is_locked(current_height)
                 );
-                let can_spend = !marked && !(utxo.is_stake && utxo.locked_until > current_height);
-                println!("    -> can_spend: {}", can_spend);
-            }
-        }
-        println!("========================");
-
-        for entry in self.utxos.utxos.iter() {
-            let pubkey = entry.key();
-            let utxos = entry.value();
-            for (marked, utxo) in utxos.iter() {
-                if *marked {
-                    continue; // Skip used UTXOs
-                }
-                // Skip zero-value UTXOs - they are useless and may cause validation errors
-                if utxo.value == 0 {
-                    continue;
-                }
-                // Skip locked staked UTXOs - they can't be spent until unlocked
-                if utxo.is_stake && utxo.locked_until > current_height {
-                    continue;
-                }
-                if input_sum >= total_amount {
-                    break;
-                }
-                inputs.push(poslib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: poslib::crypto::Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
-                });
-                input_sum += utxo.value;
-            }
-            if input_sum >= total_amount {
-                break;
             }
         }
-        println!("Total input_sum collected: {}", input_sum);
-        println!("Total amount needed: {}", total_amount);
 
-        if input_sum < total_amount {
-            return Err(anyhow::anyhow!(format!(
-                "Insufficient funds, total amount : {} (note: locked staked coins cannot be spent)",
-                total_amount
-            )));
-        }
+        let selected = self
+            .utxos
+            .select(total_amount, self.config.coin_selection, |utxo| {
+                !utxo.is_locked(current_height)
+            })?;
+        let (inputs, input_sum, contributions) =
+            self.sign_inputs(&selected, valid_until, memo.as_deref())?;
+        debug!("collected {} in inputs, {} needed", input_sum, total_amount);
         let mut outputs = vec![TransactionOutput {
             value: amount,
             unique_id: uuid::Uuid::new_v4(),
             pubkey: recipient.clone(),
             is_stake: false,
             locked_until: 0,
+            delegate_to: None,
         }];
         if input_sum > total_amount {
             outputs.push(TransactionOutput {
                 value: input_sum - total_amount,
                 unique_id: uuid::Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                pubkey: self.change_key(&contributions),
                 is_stake: false,
                 locked_until: 0,
+                delegate_to: None,
             });
         }
-        Ok(Transaction::new(inputs, outputs))
+        Ok(Transaction::new(inputs, outputs)
+            .with_valid_until(valid_until)
+            .with_memo(memo))
     }
 
-    pub async fn create_stake_transaction(&self, amount: u64) -> Result<Transaction> {
+    /// Pay several recipients in one transaction: inputs are selected once
+    /// to cover the sum of all amounts plus a single fee
+    pub async fn create_multi_output_transaction(
+        &self,
+        payments: &[(PublicKey, u64)],
+    ) -> Result<Transaction> {
+        self.ensure_keys()?;
+        if payments.is_empty() {
+            return Err(anyhow::anyhow!("No recipients given"));
+        }
+        let amount = payments
+            .iter()
+            .try_fold(0u64, |sum, (_, value)| sum.checked_add(*value))
+            .ok_or_else(|| anyhow::anyhow!("Total amount overflows"))?;
         let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow::anyhow!("Amount plus fee overflows"))?;
 
-        // Fetch current block height to check stake lock status
         let current_height = self.fetch_block_height().await?;
 
-        // We use the first key for staking for simplicity, or we could iterate
-        // For now, let's assume we stake from the first available funds found
-        for entry in self.utxos.utxos.iter() {
-            let pubkey = entry.key();
-            let utxos = entry.value();
-            for (marked, utxo) in utxos.iter() {
-                if *marked {
-                    continue;
-                }
-                // Skip UTXOs with no value
-                if utxo.value == 0 {
-                    continue;
-                }
-                // Skip locked staked UTXOs - they can't be spent until unlocked
-                if utxo.is_stake && utxo.locked_until > current_height {
-                    continue;
-                }
-                if input_sum >= total_amount {
-                    break;
-                }
-                inputs.push(poslib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: poslib::crypto::Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
-                });
-                input_sum += utxo.value;
-            }
-            if input_sum >= total_amount {
-                break;
-            }
-        }
-
-        if input_sum < total_amount {
-            return Err(anyhow::anyhow!("Insufficient funds"));
+        let selected = self
+            .utxos
+            .select(total_amount, self.config.coin_selection, |utxo| {
+                !utxo.is_locked(current_height)
+            })?;
+        let (inputs, input_sum, contributions) = self.sign_inputs(&selected, None, None)?;
+        let mut outputs: Vec<TransactionOutput> = payments
+            .iter()
+            .map(|(recipient, value)| TransactionOutput {
+                value: *value,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey: recipient.clone(),
+                is_stake: false,
+                locked_until: 0,
+                delegate_to: None,
+            })
+            .collect();
+        if input_sum > total_amount {
+            outputs.push(TransactionOutput {
+                value: input_sum - total_amount,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey: self.change_key(&contributions),
+                is_stake: false,
+                locked_until: 0,
+                delegate_to: None,
+            });
         }
+        Ok(Transaction::new(inputs, outputs))
+    }
 
-        // The output is sent back to ourselves (the first key), but marked as stake
-        let my_pubkey = self.utxos.my_keys[0].public.clone();
+    /// Lock `amount` as stake. With `delegate_to` the stake counts towards
+    /// that validator's weight while the coins stay ours.
+    pub async fn create_stake_transaction(
+        &self,
+        amount: u64,
+        delegate_to: Option<PublicKey>,
+    ) -> Result<Transaction> {
+        self.ensure_keys()?;
+        let fee = self.calculate_fee(amount);
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow::anyhow!("Amount plus fee overflows"))?;
 
-        // Fetch current block height from the node (source of truth)
+        // Fetch current block height to check stake lock status
         let current_height = self.fetch_block_height().await?;
-        // Calculate lock period: current block height + STAKE_LOCK_PERIOD
-        let lock_until = current_height + poslib::STAKE_LOCK_PERIOD;
+
+        // Stake from any spendable funds, picked by the coin selection strategy
+        let selected = self
+            .utxos
+            .select(total_amount, self.config.coin_selection, |utxo| {
+                !utxo.is_locked(current_height)
+            })?;
+        let (inputs, input_sum, contributions) = self.sign_inputs(&selected, None, None)?;
+
+        // The output is sent back to ourselves (per the change policy), but marked as stake
+        let my_pubkey = self.change_key(&contributions);
+
+        // Fetch the lock period from the node (source of truth)
+        let (_, lock_period) = self.fetch_stake_params().await?;
+        let lock_until = current_height
+            .checked_add(lock_period)
+            .ok_or_else(|| anyhow::anyhow!("Stake lock height overflows"))?;
 
         let mut outputs = vec![TransactionOutput {
             value: amount,
             unique_id: uuid::Uuid::new_v4(),
             pubkey: my_pubkey.clone(),
             is_stake: true,           // This is the key difference
-            locked_until: lock_until, // Stake is locked for STAKE_LOCK_PERIOD blocks
+            locked_until: lock_until, // Stake is locked for the node's lock period
+            delegate_to: delegate_to.clone(),
         }];
 
         // Change output (not staked)
@@ -324,6 +806,7 @@ impl Core {
                 pubkey: my_pubkey,
                 is_stake: false,
                 locked_until: 0,
+                delegate_to: None,
             });
         }
         Ok(Transaction::new(inputs, outputs))
@@ -332,65 +815,25 @@ impl Core {
     /// Create a transaction to unstake coins (convert staked UTXOs back to regular UTXOs)
     /// Note: The node will validate that the stake lock period has passed
     pub async fn create_unstake_transaction(&self, amount: u64) -> Result<Transaction> {
+        self.ensure_keys()?;
         let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow::anyhow!("Amount plus fee overflows"))?;
 
         // Fetch current height from node for display purposes only
         // The actual validation is done by the node in add_to_mempool
         let current_height = self.fetch_block_height().await?;
 
         // Find staked UTXOs that appear unlocked (lock period has passed)
-        for entry in self.utxos.utxos.iter() {
-            let pubkey = entry.key();
-            let utxos = entry.value();
-            for (marked, utxo) in utxos.iter() {
-                if *marked {
-                    continue; // Skip marked UTXOs
-                }
-                // Skip UTXOs with no value
-                if utxo.value == 0 {
-                    continue;
-                }
-                // Only use staked UTXOs that are unlocked
-                if !utxo.is_stake {
-                    continue;
-                }
-                // Check if lock period has passed (display check only, node validates)
-                if utxo.locked_until > current_height {
-                    continue; // Still locked
-                }
-                if input_sum >= total_amount {
-                    break;
-                }
-                inputs.push(poslib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: poslib::crypto::Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
-                });
-                input_sum += utxo.value;
-            }
-            if input_sum >= total_amount {
-                break;
-            }
-        }
-
-        if input_sum < total_amount {
-            return Err(anyhow::anyhow!(
-                "Insufficient unlocked staked funds. You may need to wait for the lock period to expire."
-            ));
-        }
+        let selected = self
+            .utxos
+            .select(total_amount, self.config.coin_selection, |utxo| {
+                utxo.is_stake && !utxo.is_locked(current_height)
+            })?;
+        let (inputs, input_sum, contributions) = self.sign_inputs(&selected, None, None)?;
 
-        let my_pubkey = self.utxos.my_keys[0].public.clone();
+        let my_pubkey = self.change_key(&contributions);
 
         // Output is NOT staked anymore
         let mut outputs = vec![TransactionOutput {
@@ -399,6 +842,7 @@ impl Core {
             pubkey: my_pubkey.clone(),
             is_stake: false, // No longer staked
             locked_until: 0,
+            delegate_to: None,
         }];
 
         // Change output (also not staked)
@@ -409,6 +853,7 @@ impl Core {
                 pubkey: my_pubkey,
                 is_stake: false,
                 locked_until: 0,
+                delegate_to: None,
             });
         }
         Ok(Transaction::new(inputs, outputs))
@@ -417,37 +862,119 @@ impl Core {
     // Get the amount of currently locked staked coins
     pub async fn get_active_stake_balance(&self) -> Result<u64> {
         let current_height = self.fetch_block_height().await?;
-        Ok(self
+        self.sum_utxos(|_, utxo| utxo.is_stake && utxo.locked_until > current_height)
+    }
+
+    // Get the amount of currently unlocked staked coins -> Not available for staking anymore
+    pub async fn get_unlocked_stake_balance(&self) -> Result<u64> {
+        let current_height = self.fetch_block_height().await?;
+        self.sum_utxos(|_, utxo| utxo.is_stake && utxo.locked_until <= current_height)
+    }
+
+    /// Each of our staked UTXOs as (value, unlock height, blocks until
+    /// unlocked), soonest unlock first. Unlocked stakes have 0 remaining.
+    pub async fn stake_positions(&self) -> Result<Vec<(u64, u64, u64)>> {
+        let current_height = self.fetch_block_height().await?;
+        let mut positions: Vec<(u64, u64, u64)> = self
             .utxos
             .utxos
             .iter()
-            .map(|entry| {
+            .flat_map(|entry| {
                 entry
                     .value()
                     .iter()
-                    .filter(|(_, utxo)| utxo.is_stake && utxo.locked_until > current_height)
-                    .map(|(_, utxo)| utxo.value)
-                    .sum::<u64>()
+                    .filter(|(_, utxo)| utxo.is_stake)
+                    .map(|(_, utxo)| {
+                        (
+                            utxo.value,
+                            utxo.locked_until,
+                            utxo.locked_until.saturating_sub(current_height),
+                        )
+                    })
+                    .collect::<Vec<_>>()
             })
-            .sum())
+            .collect();
+        positions.sort_by_key(|&(value, locked_until, _)| (locked_until, value));
+        Ok(positions)
     }
 
-    // Get the amount of currently unlocked staked coins -> Not available for staking anymore
-    pub async fn get_unlocked_stake_balance(&self) -> Result<u64> {
-        let current_height = self.fetch_block_height().await?;
-        Ok(self
+    /// Transactions need one of our keys for change and stake outputs
+    fn ensure_keys(&self) -> Result<()> {
+        if self.utxos.my_keys.is_empty() {
+            if !self.utxos.watched.lock().unwrap().is_empty() {
+                return Err(WalletError::WatchOnly.into());
+            }
+            return Err(WalletError::NoKeysConfigured.into());
+        }
+        Ok(())
+    }
+
+    /// Inputs spending `selected` in a transaction with this `valid_until`
+    /// and `memo`, with their total value and how much each key put in
+    fn sign_inputs(
+        &self,
+        selected: &[(PublicKey, TransactionOutput)],
+        valid_until: Option<u64>,
+        memo: Option<&[u8]>,
+    ) -> Result<(Vec<TransactionInput>, u64, HashMap<PublicKey, u64>)> {
+        let mut inputs = Vec::new();
+        let mut input_sum = 0u64;
+        let mut contributions: HashMap<PublicKey, u64> = HashMap::new();
+        for (pubkey, utxo) in selected {
+            let private_key = self.signing_key(pubkey).ok_or(WalletError::WatchOnly)?;
+            inputs.push(TransactionInput::signed_for(
+                utxo.hash(),
+                TransactionInput::SEQUENCE_FINAL,
+                valid_until,
+                memo,
+                private_key,
+            ));
+            input_sum = input_sum
+                .checked_add(utxo.value)
+                .ok_or_else(|| anyhow::anyhow!("UTXO total overflows"))?;
+            *contributions.entry(pubkey.clone()).or_insert(0) += utxo.value;
+        }
+        Ok((inputs, input_sum, contributions))
+    }
+
+    /// Private key for `pubkey`, `None` for watch-only keys
+    fn signing_key(&self, pubkey: &PublicKey) -> Option<&PrivateKey> {
+        self.utxos
+            .my_keys
+            .iter()
+            .find(|key| key.public == *pubkey)
+            .map(|key| &key.private)
+    }
+
+    /// Which of our keys receives change, given how much each key put in
+    fn change_key(&self, contributions: &HashMap<PublicKey, u64>) -> PublicKey {
+        let first_key = || self.utxos.my_keys[0].public.clone();
+        match self.config.change_policy {
+            ChangePolicy::FirstKey => first_key(),
+            ChangePolicy::SourceKey => contributions
+                .iter()
+                .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+                .map(|(pubkey, _)| pubkey.clone())
+                .unwrap_or_else(first_key),
+        }
+    }
+
+    /// Total value of our UTXOs matching `filter(marked, utxo)`
+    fn sum_utxos(&self, filter: impl Fn(bool, &TransactionOutput) -> bool) -> Result<u64> {
+        let values: Vec<u64> = self
             .utxos
             .utxos
             .iter()
-            .map(|entry| {
+            .flat_map(|entry| {
                 entry
                     .value()
                     .iter()
-                    .filter(|(_, utxo)| utxo.is_stake && utxo.locked_until <= current_height)
+                    .filter(|(marked, utxo)| filter(*marked, utxo))
                     .map(|(_, utxo)| utxo.value)
-                    .sum::<u64>()
+                    .collect::<Vec<_>>()
             })
-            .sum())
+            .collect();
+        Ok(sum_amounts(values)?)
     }
 
     fn calculate_fee(&self, amount: u64) -> u64 {
@@ -457,26 +984,452 @@ impl Core {
         }
     }
 
-    pub async fn get_balance(&self) -> Result<u64> {
-        let current_height = self.fetch_block_height().await?;
-        Ok(self
+    pub async fn get_min_stake_amount(&self) -> Result<u64> {
+        let (min_stake, _) = self.fetch_stake_params().await?;
+        Ok(min_stake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxo(value: u64, pubkey: &PublicKey) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: pubkey.clone(),
+            is_stake: false,
+            locked_until: 0,
+            delegate_to: None,
+        }
+    }
+
+    /// A store with one signing key owning `utxos` (marked, value)
+    fn store(utxos: &[(bool, u64)]) -> (UtxoStore, PublicKey) {
+        let private = PrivateKey::new_key();
+        let public = private.public_key();
+        let mut store = UtxoStore::new();
+        store.add_key(LoadedKey {
+            public: public.clone(),
+            private,
+        });
+        let utxos = utxos
+            .iter()
+            .map(|(marked, value)| (*marked, utxo(*value, &public)))
+            .collect();
+        store.utxos.insert(public.clone(), utxos);
+        (store, public)
+    }
+
+    fn values(selected: &[(PublicKey, TransactionOutput)]) -> Vec<u64> {
+        selected.iter().map(|(_, utxo)| utxo.value).collect()
+    }
+
+    /// A wallet over `store` using `node`, paying a fixed `fee`
+    fn wallet(store: UtxoStore, node: &str, fee: u64) -> Core {
+        let config = Config {
+            my_keys: vec![],
+            contacts: vec![],
+            default_node: node.to_string(),
+            fee_config: FeeConfig {
+                fee_type: FeeType::Fixed,
+                value: fee as f64,
+            },
+            change_policy: ChangePolicy::default(),
+            coin_selection: CoinSelection::default(),
+        };
+        let history = std::env::temp_dir().join(format!("history-{}.cbor", uuid::Uuid::new_v4()));
+        Core::new(config, store, history)
+    }
+
+    /// Address of a node at `height` with a stake lock period of
+    /// `lock_period`, answering what the wallet asks before building a
+    /// transaction. Every key has a spendable balance of 10.
+    async fn fake_node(height: u64, lock_period: u64) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if Message::handshake_async(&mut stream, Hash::zero())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    while let Ok(request) = Message::receive_async(&mut stream).await {
+                        let reply = match request {
+                            Message::FetchBlockHeight => Message::BlockHeight(height),
+                            Message::FetchStakeParams => Message::StakeParams {
+                                min_stake: 0,
+                                lock_period,
+                            },
+                            Message::FetchBalance(_) => Message::Balance {
+                                spendable: 10,
+                                active_stake: 0,
+                                unlocked_stake: 0,
+                            },
+                            other => panic!("unexpected request {other:?}"),
+                        };
+                        if reply.send_async(&mut stream).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn exact_match_finds_a_subset() {
+        let values = [50, 30, 20, 10];
+        let picked = exact_match(&values, 40).unwrap();
+        assert_eq!(picked.iter().map(|&i| values[i]).sum::<u64>(), 40);
+        assert_eq!(exact_match(&values, 110).unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(exact_match(&[50, 30], 40), None);
+        assert_eq!(exact_match(&[50, 30], 100), None);
+        // branches overflowing u64 are skipped, not wrapped
+        assert_eq!(
+            exact_match(&[u64::MAX - 1, 5, 1], u64::MAX).unwrap(),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn selection_follows_the_strategy() {
+        let (store, _) = store(&[(false, 10), (false, 50), (false, 30), (false, 20)]);
+        let pick = |strategy| store.select(40, strategy, |_| true).unwrap();
+        assert_eq!(values(&pick(CoinSelection::LargestFirst)), vec![50]);
+        assert_eq!(
+            values(&pick(CoinSelection::SmallestFirst)),
+            vec![10, 20, 30]
+        );
+        assert_eq!(values(&pick(CoinSelection::BranchAndBound)), vec![30, 10]);
+        // no exact match: largest first
+        let selected = store
+            .select(45, CoinSelection::BranchAndBound, |_| true)
+            .unwrap();
+        assert_eq!(values(&selected), vec![50]);
+    }
+
+    #[test]
+    fn unspendable_utxos_are_never_selected() {
+        let (store, _) = store(&[(true, 100), (false, 0), (false, 7), (false, 5)]);
+        let watched = PrivateKey::new_key().public_key();
+        store.add_watched(watched.clone());
+        store
             .utxos
+            .insert(watched.clone(), vec![(false, utxo(1000, &watched))]);
+
+        let error = store
+            .select(10, CoinSelection::LargestFirst, |utxo| utxo.value != 7)
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(
+            error,
+            WalletError::InsufficientFunds {
+                needed: 10,
+                available: 5
+            }
+        ));
+        let selected = store
+            .select(12, CoinSelection::LargestFirst, |_| true)
+            .unwrap();
+        assert_eq!(values(&selected), vec![7, 5]);
+    }
+
+    #[test]
+    fn overflowing_sums_are_errors() {
+        let (near_max, _) = store(&[(false, u64::MAX - 1), (false, 5)]);
+        assert!(
+            near_max
+                .select(u64::MAX, CoinSelection::LargestFirst, |_| true)
+                .is_err()
+        );
+
+        let (past_max, public) = store(&[(false, u64::MAX), (false, 1)]);
+        let wallet = wallet(past_max, "127.0.0.1:1", 1);
+        let selected: Vec<_> = wallet
             .utxos
+            .utxos
+            .get(&public)
+            .unwrap()
+            .value()
             .iter()
-            .map(|entry| {
-                entry
-                    .value()
-                    .iter()
-                    .filter(|(marked, utxo)| {
-                        // Skip marked UTXOs and locked staked UTXOs
-                        !marked && !(utxo.is_stake && utxo.locked_until > current_height)
-                    })
-                    .map(|(_, utxo)| utxo.value)
-                    .sum::<u64>()
-            })
-            .sum())
+            .map(|(_, utxo)| (public.clone(), utxo.clone()))
+            .collect();
+        assert!(wallet.sign_inputs(&selected, None, None).is_err());
+        assert!(wallet.sum_utxos(|_, _| true).is_err());
+    }
+
+    #[tokio::test]
+    async fn amount_past_the_funds_is_an_error() {
+        let node = fake_node(10, 100).await;
+        let (store, _) = store(&[(false, 100)]);
+        let wallet = wallet(store, &node, 1);
+        let recipient = PrivateKey::new_key().public_key();
+
+        // amount plus fee overflows before anything is asked of the node
+        assert!(
+            wallet
+                .create_transaction(&recipient, u64::MAX, None, None)
+                .await
+                .is_err()
+        );
+        // the change would be negative: refused, not wrapped
+        let error = wallet
+            .create_transaction(&recipient, 100, None, None)
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(
+            error,
+            WalletError::InsufficientFunds {
+                needed: 101,
+                available: 100
+            }
+        ));
+        // exactly enough leaves no change output
+        let transaction = wallet
+            .create_transaction(&recipient, 99, None, None)
+            .await
+            .unwrap();
+        assert_eq!(transaction.outputs.len(), 1);
+    }
+
+    #[test]
+    fn change_goes_where_the_policy_says() {
+        let (mut two_keys, first) = store(&[]);
+        let private = PrivateKey::new_key();
+        let second = private.public_key();
+        two_keys.add_key(LoadedKey {
+            public: second.clone(),
+            private,
+        });
+        let mut wallet = wallet(two_keys, "127.0.0.1:1", 1);
+        let contributions = HashMap::from([(first.clone(), 10), (second.clone(), 30)]);
+
+        wallet.config.change_policy = ChangePolicy::FirstKey;
+        assert_eq!(wallet.change_key(&contributions), first);
+        wallet.config.change_policy = ChangePolicy::SourceKey;
+        assert_eq!(wallet.change_key(&contributions), second);
+        assert_eq!(wallet.change_key(&HashMap::new()), first);
+        // a tie goes to the lower key, whatever the map order
+        let tie = HashMap::from([(first.clone(), 20), (second.clone(), 20)]);
+        assert_eq!(wallet.change_key(&tie), first.min(second));
+    }
+
+    #[tokio::test]
+    async fn multi_output_pays_everyone_with_one_fee() {
+        let node = fake_node(10, 100).await;
+        let (store, public) = store(&[(false, 60), (false, 50)]);
+        let wallet = wallet(store, &node, 5);
+        let (alice, bob) = (
+            PrivateKey::new_key().public_key(),
+            PrivateKey::new_key().public_key(),
+        );
+
+        let transaction = wallet
+            .create_multi_output_transaction(&[(alice.clone(), 40), (bob.clone(), 30)])
+            .await
+            .unwrap();
+        let paid: Vec<_> = transaction
+            .outputs
+            .iter()
+            .map(|output| (output.pubkey.clone(), output.value))
+            .collect();
+        // 110 in, 70 paid, a single fee of 5, the rest back to us
+        assert_eq!(paid, [(alice.clone(), 40), (bob.clone(), 30), (public, 35)]);
+        assert_eq!(transaction.inputs.len(), 2);
+
+        let error = wallet
+            .create_multi_output_transaction(&[(alice, 60), (bob, 50)])
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(
+            error,
+            WalletError::InsufficientFunds {
+                needed: 115,
+                available: 110
+            }
+        ));
+        assert!(wallet.create_multi_output_transaction(&[]).await.is_err());
     }
-    pub fn get_min_stake_amount(&self) -> u64 {
-        STAKE_MINIMUM_AMOUNT
+
+    #[test]
+    fn history_records_payments_and_exports_csv() {
+        let (store, public) = store(&[]);
+        let mut wallet = wallet(store, "127.0.0.1:1", 1);
+        let contact = PrivateKey::new_key().public_key();
+        let stranger = PrivateKey::new_key().public_key();
+        let key_file =
+            std::env::temp_dir().join(format!("contact-{}.pub.pem", uuid::Uuid::new_v4()));
+        contact.save_to_file(&key_file).unwrap();
+        wallet.config.contacts.push(Recipient {
+            name: "Bob \"the builder\", Jr".to_string(),
+            key: key_file.clone(),
+        });
+        assert!(wallet.history().unwrap().is_empty());
+
+        let stake = TransactionOutput {
+            is_stake: true,
+            locked_until: 100,
+            ..utxo(40, &public)
+        };
+        let payment = Transaction::new(vec![], vec![utxo(30, &contact), stake, utxo(29, &public)]);
+        wallet.record_transaction(&payment).unwrap();
+        wallet
+            .record_transaction(&Transaction::new(vec![], vec![utxo(5, &stranger)]))
+            .unwrap();
+        // the change back to us is left out
+        let recorded: Vec<_> = wallet
+            .history()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.direction, entry.amount, entry.counterparty))
+            .collect();
+        assert!(matches!(
+            recorded.as_slice(),
+            [
+                (Direction::Sent, 30, a),
+                (Direction::Staked, 40, b),
+                (Direction::Sent, 5, c),
+            ] if *a == contact && *b == public && *c == stranger
+        ));
+
+        let csv_file = std::env::temp_dir().join(format!("history-{}.csv", uuid::Uuid::new_v4()));
+        assert_eq!(wallet.export_history(&csv_file).unwrap(), 3);
+        let csv = fs::read_to_string(&csv_file).unwrap();
+        let rows: Vec<Vec<&str>> = csv
+            .lines()
+            .map(|line| line.splitn(4, ',').collect())
+            .collect();
+        assert_eq!(rows[0], ["timestamp", "direction", "amount", "recipient"]);
+        assert_eq!(
+            rows[1][1..],
+            ["Sent", "30", "\"Bob \"\"the builder\"\", Jr\""]
+        );
+        assert_eq!(rows[2][1..], ["Staked", "40", "\"self\""]);
+        assert_eq!(rows[3][3], format!("\"{}\"", stranger.to_address()));
+        assert!(DateTime::parse_from_rfc3339(rows[1][0]).is_ok());
+        let _ = fs::remove_file(key_file);
+        let _ = fs::remove_file(csv_file);
+    }
+
+    #[tokio::test]
+    async fn stake_positions_count_down_to_the_unlock() {
+        let node = fake_node(50, 100).await;
+        let (store, public) = store(&[]);
+        let stake = |value, locked_until| TransactionOutput {
+            is_stake: true,
+            locked_until,
+            ..utxo(value, &public)
+        };
+        // plain coins are not stake positions, marked stakes still are
+        let utxos = vec![
+            (false, utxo(7, &public)),
+            (false, stake(30, 80)),
+            (true, stake(20, 50)),
+            (false, stake(10, 12)),
+            (false, stake(40, 80)),
+        ];
+        store.utxos.insert(public, utxos);
+        let wallet = wallet(store, &node, 1);
+
+        // soonest unlock first; at or past the unlock height nothing remains
+        assert_eq!(
+            wallet.stake_positions().await.unwrap(),
+            vec![(10, 12, 0), (20, 50, 0), (30, 80, 30), (40, 80, 30)]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_keys_is_refused_before_asking_the_node() {
+        // nothing listens on the node address: the check must come first
+        let wallet = wallet(UtxoStore::new(), "127.0.0.1:1", 1);
+        let recipient = PrivateKey::new_key().public_key();
+        let error = wallet
+            .create_transaction(&recipient, 10, None, None)
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(error, WalletError::NoKeysConfigured));
+    }
+
+    #[tokio::test]
+    async fn full_queue_times_out() {
+        let mut wallet = wallet(UtxoStore::new(), "127.0.0.1:1", 1);
+        // a sender stuck on its first transaction never drains the queue
+        let (sender, _receiver) = kanal::bounded_async(1);
+        wallet.tx_sender = sender;
+        wallet.queue_timeout = Duration::from_millis(50);
+        let transaction = || Transaction::new(vec![], vec![]);
+
+        wallet.queue_transaction(transaction()).await.unwrap();
+        let error = wallet
+            .queue_transaction(transaction())
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(error, WalletError::QueueFull));
+    }
+
+    #[tokio::test]
+    async fn watched_coins_count_but_are_never_spent() {
+        let node = fake_node(10, 100).await;
+        let (store, _) = store(&[(false, 20)]);
+        let watched = PrivateKey::new_key().public_key();
+        store.add_watched(watched.clone());
+        let watched_utxo = utxo(1000, &watched);
+        store
+            .utxos
+            .insert(watched.clone(), vec![(false, watched_utxo.clone())]);
+        let watch_only = UtxoStore::new();
+        watch_only.add_watched(watched.clone());
+        watch_only
+            .utxos
+            .insert(watched.clone(), vec![(false, watched_utxo.clone())]);
+        let watch_only = wallet(watch_only, &node, 1);
+        let wallet = wallet(store, &node, 1);
+        let recipient = PrivateKey::new_key().public_key();
+
+        assert_eq!(wallet.sum_utxos(|_, _| true).unwrap(), 1020);
+        // the node is asked for the watched key too
+        assert_eq!(wallet.fetch_balance().await.unwrap(), (20, 0, 0));
+        let error = wallet
+            .create_transaction(&recipient, 100, None, None)
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(
+            error,
+            WalletError::InsufficientFunds {
+                needed: 101,
+                available: 20
+            }
+        ));
+        let error = wallet
+            .sign_inputs(&[(watched, watched_utxo)], None, None)
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(error, WalletError::WatchOnly));
+
+        let error = watch_only
+            .create_transaction(&recipient, 100, None, None)
+            .await
+            .unwrap_err()
+            .downcast::<WalletError>()
+            .unwrap();
+        assert!(matches!(error, WalletError::WatchOnly));
     }
 }
@@ -0,0 +1,24 @@
+use thiserror::Error;
+
+/// Failures of wallet operations that callers may want to tell apart.
+/// `Core` methods return them inside `anyhow::Error`, so match with
+/// `downcast_ref::<WalletError>()`.
+#[derive(Error, Debug)]
+pub enum WalletError {
+    #[error(
+        "Insufficient funds: {needed} satoshis needed, {available} available (note: locked staked coins and coins of watch-only keys cannot be spent)"
+    )]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("Unexpected response from node")]
+    UnexpectedResponse,
+    #[error("Node {0} is unreachable")]
+    NodeUnreachable(String),
+    #[error("Recipient {0} not found")]
+    RecipientNotFound(String),
+    #[error("Transaction queue full, the node may be unreachable")]
+    QueueFull,
+    #[error("No keys configured, add one under [[my_keys]] in the wallet config")]
+    NoKeysConfigured,
+    #[error("Only watch-only keys configured, their coins can't be spent without the private key")]
+    WatchOnly,
+}
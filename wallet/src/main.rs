@@ -1,13 +1,18 @@
 mod core;
+mod error;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use core::{Config, Core, FeeConfig, FeeType, Recipient};
-use kanal::bounded;
+use core::{ChangePolicy, CoinSelection, Config, Core, FeeConfig, FeeType, Recipient};
+use poslib::crypto::PrivateKey;
+use poslib::sha256::Hash;
 use poslib::types::Transaction;
+use poslib::util::Saveable;
 use std::io::{self, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::time::{self, Duration};
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -19,6 +24,14 @@ struct Cli {
 
     #[arg(short, long, value_name = "ADDRESS")]
     node: Option<String>,
+
+    /// Prompt for the passphrase of encrypted private key files
+    #[arg(long)]
+    ask_passphrase: bool,
+
+    /// Seconds to wait for the node to accept a connection
+    #[arg(long, value_name = "SECS", default_value_t = core::DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    connect_timeout: u64,
 }
 
 #[derive(Subcommand)]
@@ -27,6 +40,11 @@ enum Commands {
         #[arg(short, long, value_name = "FILE", default_value_os_t = PathBuf::from("wallet_config.toml"))]
         output: PathBuf,
     },
+    /// Encrypt a plaintext private key file in place with a passphrase
+    EncryptKey {
+        #[arg(value_name = "FILE")]
+        key: PathBuf,
+    },
 }
 
 fn generate_dummy_config(path: &PathBuf) -> Result<()> {
@@ -47,75 +65,209 @@ fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             fee_type: FeeType::Percent,
             value: 0.1,
         },
+        change_policy: ChangePolicy::SourceKey,
+        coin_selection: CoinSelection::LargestFirst,
     };
     let config_str = toml::to_string_pretty(&dummy_config)?;
     std::fs::write(path, config_str)?;
     println!("Dummy config generated at: {}", path.display());
     Ok(())
 }
+fn encrypt_key(path: &PathBuf) -> Result<()> {
+    if PrivateKey::is_encrypted_file(path)? {
+        println!("{} is already encrypted", path.display());
+        return Ok(());
+    }
+    let key = PrivateKey::load_from_file(path)?;
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err(anyhow::anyhow!("Passphrases do not match"));
+    }
+    key.save_encrypted(path, &passphrase)?;
+    println!("Encrypted {}", path.display());
+    Ok(())
+}
 async fn update_utxos(core: Arc<Core>) {
     let mut interval = time::interval(Duration::from_secs(20));
     loop {
         interval.tick().await;
         if let Err(e) = core.fetch_utxos().await {
-            eprintln!("Failed to update UTXOs: {}", e);
+            error!("Failed to update UTXOs: {}", e);
         }
     }
 }
 async fn handle_transactions(rx: kanal::AsyncReceiver<Transaction>, core: Arc<Core>) {
     while let Ok(transaction) = rx.recv().await {
-        if let Err(e) = core.send_transaction(transaction).await {
-            eprintln!("Failed to send transaction: {}", e);
+        match core.send_transaction(transaction.clone()).await {
+            Ok(()) => {
+                if let Err(e) = core.record_transaction(&transaction) {
+                    error!("Failed to record transaction in history: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("Failed to send transaction: {}", e);
+                core.report_send_failure(format!(
+                    "Failed to send transaction {}: {}",
+                    transaction.hash(),
+                    e
+                ));
+            }
+        }
+    }
+}
+/// Print the inputs, outputs, fee and change of a transaction built by
+/// `create_transaction`, where the first output pays the recipient and
+/// any second one is our change
+fn print_transaction_summary(core: &Core, transaction: &Transaction) {
+    let mut total_input = 0u64;
+    println!("Inputs:");
+    for input in &transaction.inputs {
+        let value = core
+            .utxo_value(&input.prev_transaction_output_hash)
+            .unwrap_or(0);
+        total_input = total_input.saturating_add(value);
+        println!(
+            "  {}  {} satoshis",
+            input.prev_transaction_output_hash, value
+        );
+    }
+    let mut total_output = 0u64;
+    let mut change = 0u64;
+    println!("Outputs:");
+    for (i, output) in transaction.outputs.iter().enumerate() {
+        total_output = total_output.saturating_add(output.value);
+        if i > 0 {
+            change = change.saturating_add(output.value);
         }
+        println!(
+            "  {}  {} satoshis{}",
+            core.counterparty_name(&output.pubkey),
+            output.value,
+            if i > 0 { " (change)" } else { "" }
+        );
     }
+    println!("Total input: {} satoshis", total_input);
+    println!("Total output: {} satoshis", total_output);
+    println!("Fee: {} satoshis", total_input.saturating_sub(total_output));
+    println!("Change: {} satoshis", change);
 }
 async fn run_cli(core: Arc<Core>) -> Result<()> {
     loop {
+        for failure in core.take_send_failures() {
+            println!("{failure}");
+        }
         print!("> ");
         io::stdout().flush()?;
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+        let parts: Vec<&str> = input.split_whitespace().collect();
         if parts.is_empty() {
             continue;
         }
         match parts[0] {
+            "address" => {
+                for address in core.addresses() {
+                    println!("{}", address);
+                }
+            }
             "balance" => {
-                println!("Current balance: {} satoshis", core.get_balance().await?);
-                println!(
-                    "Stakable balance: {} satoshis",
-                    core.get_unlocked_stake_balance().await?
-                );
+                let (balance, active_stake, unlocked_stake) = core.fetch_balance().await?;
+                println!("Current balance: {} satoshis", balance);
+                println!("Staked (locked) balance: {} satoshis", active_stake);
+                println!("Unstakable balance: {} satoshis", unlocked_stake);
             }
 
             "send" => {
+                // everything after --memo is the memo, quotes optional
+                let (args, memo) = match input.split_once("--memo") {
+                    Some((args, memo)) => (args, Some(memo.trim().trim_matches('"').as_bytes())),
+                    None => (input.as_str(), None),
+                };
+                let parts: Vec<&str> = args.split_whitespace().collect();
+                if parts.len() != 3 && parts.len() != 4 {
+                    println!(
+                        "Usage: send <recipient> <amount> [valid-until-height] [--memo \"text\"]"
+                    );
+                    continue;
+                }
+                if memo.is_some_and(|memo| memo.len() > poslib::consensus::MAX_MEMO_SIZE) {
+                    println!(
+                        "Memo is longer than {} bytes",
+                        poslib::consensus::MAX_MEMO_SIZE
+                    );
+                    continue;
+                }
+                let recipient = parts[1];
+                let amount: u64 = parts[2].parse()?;
+                let valid_until = parts.get(3).map(|h| h.parse::<u64>()).transpose()?;
+                let recipient = core.resolve_recipient(recipient)?;
+                if let Err(e) = core.fetch_utxos().await {
+                    println!("failed to fetch utxos: {e}");
+                };
+                let transaction = core
+                    .create_transaction(
+                        &recipient.key,
+                        amount,
+                        valid_until,
+                        memo.map(<[u8]>::to_vec),
+                    )
+                    .await?;
+                if let Err(e) = core.queue_transaction(transaction).await {
+                    println!("{e}");
+                    continue;
+                }
+                println!("Transaction to {} sent successfully", recipient.name);
+                core.fetch_utxos().await?;
+            }
+            "send-preview" => {
                 if parts.len() != 3 {
-                    println!("Usage: send <recipient> <amount>");
+                    println!("Usage: send-preview <recipient> <amount>");
                     continue;
                 }
                 let recipient = parts[1];
                 let amount: u64 = parts[2].parse()?;
-                let recipient_key = core
-                    .config
-                    .contacts
-                    .iter()
-                    .find(|r| r.name == recipient)
-                    .ok_or_else(|| anyhow::anyhow!("Recipient not found"))?
-                    .load()?
-                    .key;
+                let recipient = core.resolve_recipient(recipient)?;
+                if let Err(e) = core.fetch_utxos().await {
+                    println!("failed to fetch utxos: {e}");
+                };
+                let transaction = core
+                    .create_transaction(&recipient.key, amount, None, None)
+                    .await?;
+                print_transaction_summary(&core, &transaction);
+                println!("Preview only, nothing was sent");
+            }
+            "sendmany" => {
+                if parts.len() < 2 {
+                    println!("Usage: sendmany <recipient:amount> [<recipient:amount> ...]");
+                    continue;
+                }
+                let mut payments = Vec::new();
+                let mut names = Vec::new();
+                for part in &parts[1..] {
+                    let (name, amount) = part.split_once(':').ok_or_else(|| {
+                        anyhow::anyhow!("Expected <recipient:amount>, got {part}")
+                    })?;
+                    let amount: u64 = amount.parse()?;
+                    let recipient = core.resolve_recipient(name)?;
+                    payments.push((recipient.key, amount));
+                    names.push(recipient.name);
+                }
                 if let Err(e) = core.fetch_utxos().await {
                     println!("failed to fetch utxos: {e}");
                 };
-                let transaction = core.create_transaction(&recipient_key, amount).await?;
-                core.tx_sender.send(transaction).await?;
-                println!("Transaction sent successfully");
+                let transaction = core.create_multi_output_transaction(&payments).await?;
+                if let Err(e) = core.queue_transaction(transaction).await {
+                    println!("{e}");
+                    continue;
+                }
+                println!("Transaction to {} sent successfully", names.join(", "));
                 core.fetch_utxos().await?;
             }
             "stake" => {
                 if parts.len() == 1 {
                     println!(
                         "You need {} to be a validator node",
-                        core.get_min_stake_amount()
+                        core.get_min_stake_amount().await?
                     );
                     println!(
                         "Your Stake amount is : {} satoshis",
@@ -131,11 +283,34 @@ async fn run_cli(core: Arc<Core>) -> Result<()> {
                 if let Err(e) = core.fetch_utxos().await {
                     println!("failed to fetch utxos: {e}");
                 };
-                let transaction = core.create_stake_transaction(amount).await?;
-                core.tx_sender.send(transaction).await?;
+                let transaction = core.create_stake_transaction(amount, None).await?;
+                if let Err(e) = core.queue_transaction(transaction).await {
+                    println!("{e}");
+                    continue;
+                }
                 println!("Stake transaction sent successfully");
                 core.fetch_utxos().await?;
             }
+            "delegate" => {
+                if parts.len() != 3 {
+                    println!("Usage: delegate <validator> <amount>");
+                    continue;
+                }
+                let validator = core.resolve_recipient(parts[1])?;
+                let amount: u64 = parts[2].parse()?;
+                if let Err(e) = core.fetch_utxos().await {
+                    println!("failed to fetch utxos: {e}");
+                };
+                let transaction = core
+                    .create_stake_transaction(amount, Some(validator.key))
+                    .await?;
+                if let Err(e) = core.queue_transaction(transaction).await {
+                    println!("{e}");
+                    continue;
+                }
+                println!("Delegated {} satoshis to {}", amount, validator.name);
+                core.fetch_utxos().await?;
+            }
             "unstake" => {
                 if parts.len() == 1 {
                     println!(
@@ -153,20 +328,161 @@ async fn run_cli(core: Arc<Core>) -> Result<()> {
                     println!("failed to fetch utxos: {e}");
                 };
                 let transaction = core.create_unstake_transaction(amount).await?;
-                core.tx_sender.send(transaction).await?;
+                if let Err(e) = core.queue_transaction(transaction).await {
+                    println!("{e}");
+                    continue;
+                }
                 println!("Unstake transaction sent successfully");
                 core.fetch_utxos().await?;
             }
+            "supply" => {
+                let (issued, remaining) = core.fetch_supply_info().await?;
+                println!("Issued supply: {} satoshis", issued);
+                println!("Remaining to issue: {} satoshis", remaining);
+            }
+            "stake-status" => {
+                let positions = core.stake_positions().await?;
+                if positions.is_empty() {
+                    println!("No staked coins");
+                    continue;
+                }
+                println!(
+                    "{:>20}  {:>12}  {:>16}",
+                    "amount", "unlocks at", "blocks remaining"
+                );
+                for (value, locked_until, remaining) in positions {
+                    let remaining = if remaining == 0 {
+                        "unlocked".to_string()
+                    } else {
+                        remaining.to_string()
+                    };
+                    println!("{:>20}  {:>12}  {:>16}", value, locked_until, remaining);
+                }
+            }
+            "slashings" => {
+                let records = core.fetch_slashing_history().await?;
+                if records.is_empty() {
+                    println!("No validator has been slashed");
+                    continue;
+                }
+                println!(
+                    "{:>8}  {:<14}  {:>16}  validator",
+                    "height", "reason", "penalty"
+                );
+                for record in records {
+                    println!(
+                        "{:>8}  {:<14}  {:>16}  {}",
+                        record.block_height,
+                        format!("{:?}", record.reason),
+                        record.penalty_amount,
+                        core.counterparty_name(&record.validator)
+                    );
+                }
+            }
+            "estimate-fee" => {
+                let (min, median, max) = core.fetch_fee_estimate().await?;
+                println!(
+                    "Mempool fees: min {} / median {} / max {} satoshis",
+                    min, median, max
+                );
+            }
+            "lookup" => {
+                if parts.len() != 2 {
+                    println!("Usage: lookup <txhash>");
+                    continue;
+                }
+                let hash: Hash = parts[1].parse()?;
+                match core.fetch_transaction(hash).await? {
+                    Some((height, transaction)) => {
+                        println!("Confirmed in block {}", height);
+                        if let Some(confirmations) = core.fetch_confirmations(hash).await? {
+                            println!("{} confirmations", confirmations);
+                        }
+                        if let Some(memo) = &transaction.memo {
+                            println!("Memo: {}", String::from_utf8_lossy(memo));
+                        }
+                        for output in &transaction.outputs {
+                            println!(
+                                "  {}  {} satoshis",
+                                core.counterparty_name(&output.pubkey),
+                                output.value
+                            );
+                        }
+                    }
+                    None => println!(
+                        "Transaction not found in the chain (only archive nodes index old transactions)"
+                    ),
+                }
+            }
+            "history" => {
+                let history = core.history()?;
+                if history.is_empty() {
+                    println!("No transactions sent yet");
+                    continue;
+                }
+                for entry in history {
+                    println!(
+                        "{}  {:?}  {} satoshis  {}",
+                        entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                        entry.direction,
+                        entry.amount,
+                        core.counterparty_name(&entry.counterparty)
+                    );
+                }
+            }
+            "export-history" => {
+                if parts.len() != 2 {
+                    println!("Usage: export-history <file>");
+                    continue;
+                }
+                let count = core.export_history(&PathBuf::from(parts[1]))?;
+                println!("Exported {} history entries to {}", count, parts[1]);
+            }
+            "watch" => {
+                if parts.len() != 2 {
+                    println!("Usage: watch <pubkey-file>");
+                    continue;
+                }
+                match core.watch(&PathBuf::from(parts[1])) {
+                    Ok(key) => {
+                        println!("Watching {} (until exit)", key.to_address());
+                        core.fetch_utxos().await?;
+                    }
+                    Err(e) => println!("Cannot watch {}: {}", parts[1], e),
+                }
+            }
             "help" => {
                 println!("Available commands:");
+                println!("  address               - Show the addresses others can pay you at");
                 println!("  balance               - Show current balance and staked balance");
-                println!("  send <recipient> <amount> - Send amount to recipient");
+                println!(
+                    "  send <recipient> <amount> [height] [--memo \"text\"] - Send amount to a contact or address, optionally expiring after block height"
+                );
+                println!(
+                    "  send-preview <recipient> <amount> - Show the transaction send would build"
+                );
+                println!(
+                    "  sendmany <recipient:amount> ... - Pay several recipients in one transaction"
+                );
                 println!(
                     "  stake <amount>        - Send your coins to stake (or just 'stake' to view stakable balance)"
                 );
                 println!(
                     "  unstake <amount>      - Unstake your coins (or just 'unstake' to view unstakable balance)"
                 );
+                println!(
+                    "  delegate <validator> <amount> - Stake coins towards a validator contact"
+                );
+                println!("  stake-status          - Show each stake and when it unlocks");
+                println!("  supply                - Show issued and remaining coin supply");
+                println!("  slashings             - Show every validator slashing on the chain");
+                println!("  estimate-fee          - Show the fees currently paid in the mempool");
+                println!("  lookup <txhash>       - Find a confirmed transaction by hash");
+                println!("  history               - Show transactions sent from this wallet");
+                println!("  export-history <file> - Write the history to a CSV file");
+                println!(
+                    "  watch <pubkey-file>   - Include a key's balance without its private key"
+                );
                 println!("  help                  - Show this help message");
                 println!("  exit                  - Exit the wallet");
             }
@@ -184,13 +500,32 @@ async fn main() -> Result<()> {
         Some(Commands::GenerateConfig { output }) => {
             return generate_dummy_config(output);
         }
+        Some(Commands::EncryptKey { key }) => {
+            return encrypt_key(key);
+        }
         None => {}
     }
+    // Logs go to a file so they don't garble the REPL. Level from RUST_LOG.
+    let (log_writer, _log_guard) =
+        tracing_appender::non_blocking(tracing_appender::rolling::never(".", "wallet.log"));
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(log_writer)
+        .with_ansi(false)
+        .init();
     let config_path = cli.config;
-    let mut core = Core::load(config_path.clone())?;
+    let passphrase = if cli.ask_passphrase {
+        Some(rpassword::prompt_password("Wallet passphrase: ")?)
+    } else {
+        None
+    };
+    let mut core = Core::load(config_path.clone(), passphrase.as_deref())?;
     if let Some(node) = cli.node {
         core.config.default_node = node;
     }
+    core.connect_timeout = Duration::from_secs(cli.connect_timeout.max(1));
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender.clone_async();
     let core = Arc::new(core);